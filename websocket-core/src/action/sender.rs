@@ -1,5 +1,9 @@
 use std::io::Write;
-use crate::protocol::dataframe::DataFrame;
+use crate::compression::{Compressor, DEFAULT_COMPRESSION_THRESHOLD};
+use crate::dataframe::DataFrame;
+use crate::error::WebSocketError;
+use crate::protocol::dataframe::DataFrameRefTrait;
+use crate::protocol::header::{MaskingImpl, Opcode};
 use crate::protocol::message::Message;
 use crate::result::WebSocketResult;
 
@@ -7,12 +11,41 @@ pub trait Sender {
 
     fn is_masked(&self) -> bool;
 
+    /// The masking implementation used for outgoing payloads.
+    fn masking(&self) -> MaskingImpl {
+        MaskingImpl::default()
+    }
+
+    /// Whether frames are written to the writer as they are masked, see
+    /// [`DataFrameRefTrait::write_to_streaming`], instead of being built in
+    /// full first.
+    fn streaming(&self) -> bool {
+        false
+    }
+
+    /// The compressor applied to outgoing data messages, if compression was
+    /// negotiated.
+    fn compressor(&mut self) -> Option<&mut dyn Compressor> {
+        None
+    }
+
+    /// Whether a data message with this opcode and payload is compressed
+    /// when a compressor is set. Messages that aren't go out with RSV1
+    /// clear.
+    fn should_compress(&self, _opcode: Opcode, payload: &[u8]) -> bool {
+        payload.len() > DEFAULT_COMPRESSION_THRESHOLD
+    }
+
     fn send_dataframe<D, W>(&mut self, writer: &mut W, dataframe: &D) -> WebSocketResult<()>
         where
-            D: DataFrame,
+            D: DataFrameRefTrait + ?Sized,
             W: Write,
     {
-        dataframe.write_to(writer, self.is_masked())?;
+        if self.streaming() {
+            dataframe.write_to_streaming(writer, self.is_masked(), self.masking())?;
+        } else {
+            dataframe.write_to_with_masking(writer, self.is_masked(), self.masking())?;
+        }
         Ok(())
     }
 
@@ -22,7 +55,35 @@ pub trait Sender {
             M: Message,
             W: Write,
     {
-        message.serialize(writer, self.is_masked())?;
+        if message.as_dataframe().is_some_and(|frame| frame.opcode() == Opcode::Continuation as u8) {
+            return Err(WebSocketError::ProtocolError("A continuation can't be sent as a message"));
+        }
+        if let Some(frame) = message.as_dataframe() {
+            let opcode = match Opcode::new(frame.opcode()) {
+                Some(opcode @ (Opcode::Text | Opcode::Binary)) => Some(opcode),
+                _ => None,
+            };
+            let opcode = opcode.filter(|_| self.compressor().is_some());
+            if let Some(opcode) = opcode {
+                let mut payload = Vec::with_capacity(frame.size());
+                frame.write_payload(&mut payload)?;
+                if !self.should_compress(opcode, &payload) {
+                    return self.send_dataframe(writer, &DataFrame::new(true, opcode, payload));
+                }
+                let mut compressed = Vec::new();
+                if let Some(compressor) = self.compressor() {
+                    compressor.compress(&payload, &mut compressed)?;
+                }
+                let mut frame = DataFrame::new(true, opcode, compressed);
+                // RSV1 marks a compressed message (RFC7692 6)
+                frame.reserved[0] = true;
+                return self.send_dataframe(writer, &frame);
+            }
+        }
+        if let Some(frame) = message.as_dataframe().filter(|_| self.streaming()) {
+            return self.send_dataframe(writer, frame);
+        }
+        message.serialize_with_masking(writer, self.is_masked(), self.masking())?;
         Ok(())
     }
 }
\ No newline at end of file
@@ -0,0 +1,153 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use crate::clock::{Clock, SystemClock};
+
+/// A memory ceiling shared by many connections.
+///
+/// Receivers charge it for the payload they hold while assembling a message
+/// and credit it back once the message is delivered or discarded, so
+/// `used()` is the payload currently buffered across all of them.
+#[derive(Debug)]
+pub struct MemoryBudget {
+    ceiling: usize,
+    used: AtomicUsize,
+    peak: AtomicUsize,
+}
+
+/// How long a charge may wait for room in the budget before failing.
+#[derive(Debug, Clone, Copy)]
+pub struct BudgetWait {
+    /// Pause between two attempts.
+    pub backoff: Duration,
+    /// Give up once this much time has passed.
+    pub max_wait: Duration,
+}
+
+impl MemoryBudget {
+    pub fn new(ceiling: usize) -> MemoryBudget {
+        MemoryBudget {
+            ceiling,
+            used: AtomicUsize::new(0),
+            peak: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn ceiling(&self) -> usize {
+        self.ceiling
+    }
+
+    /// Bytes currently charged.
+    pub fn used(&self) -> usize {
+        self.used.load(Ordering::Acquire)
+    }
+
+    /// The highest value `used()` has reached.
+    pub fn peak(&self) -> usize {
+        self.peak.load(Ordering::Acquire)
+    }
+
+    /// Charges `bytes` if that keeps the budget under its ceiling.
+    pub fn try_charge(&self, bytes: usize) -> bool {
+        let mut used = self.used.load(Ordering::Acquire);
+        loop {
+            let next = match used.checked_add(bytes) {
+                Some(next) if next <= self.ceiling => next,
+                _ => return false,
+            };
+            match self.used.compare_exchange_weak(used, next, Ordering::AcqRel, Ordering::Acquire) {
+                Ok(_) => {
+                    self.peak.fetch_max(next, Ordering::AcqRel);
+                    return true;
+                }
+                Err(actual) => used = actual,
+            }
+        }
+    }
+
+    /// Charges `bytes`, retrying as described by `wait` if the budget is
+    /// full. Without `wait` this is `try_charge`.
+    pub fn charge(&self, bytes: usize, wait: Option<BudgetWait>) -> bool {
+        self.charge_with_clock(bytes, wait, &SystemClock)
+    }
+
+    /// Same as `charge`, waiting on `clock`.
+    pub fn charge_with_clock(&self, bytes: usize, wait: Option<BudgetWait>, clock: &dyn Clock) -> bool {
+        if self.try_charge(bytes) {
+            return true;
+        }
+        let wait = match wait {
+            Some(wait) => wait,
+            None => return false,
+        };
+        let deadline = clock.now() + wait.max_wait;
+        while clock.now() < deadline {
+            clock.sleep_until(clock.now() + wait.backoff);
+            if self.try_charge(bytes) {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Returns `bytes` previously charged.
+    pub fn credit(&self, bytes: usize) {
+        let previous = self.used.fetch_sub(bytes, Ordering::AcqRel);
+        debug_assert!(previous >= bytes, "credited more than was charged");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::thread;
+    use super::*;
+    use crate::clock::SimClock;
+
+    #[test]
+    fn concurrent_charges_never_pass_the_ceiling() {
+        let budget = Arc::new(MemoryBudget::new(1000));
+        let threads: Vec<_> = (1..=8)
+            .map(|bytes| {
+                let budget = budget.clone();
+                thread::spawn(move || {
+                    let mut charged = 0;
+                    for _ in 0..10_000 {
+                        if budget.try_charge(bytes * 50) {
+                            assert!(budget.used() <= budget.ceiling());
+                            charged += 1;
+                            budget.credit(bytes * 50);
+                        }
+                    }
+                    charged
+                })
+            })
+            .collect();
+        let charged: usize = threads.into_iter().map(|thread| thread.join().unwrap()).sum();
+        assert!(charged > 0);
+        assert_eq!(budget.used(), 0);
+        assert!(budget.peak() <= budget.ceiling());
+        assert!(budget.peak() >= 8 * 50);
+    }
+
+    #[test]
+    fn charge_waits_for_a_credit() {
+        let budget = MemoryBudget::new(100);
+        assert!(budget.try_charge(100));
+        assert!(!budget.try_charge(1));
+        let wait = BudgetWait {
+            backoff: Duration::from_millis(10),
+            max_wait: Duration::from_millis(100),
+        };
+        let clock = SimClock::new();
+        let started = clock.now();
+        assert!(!budget.charge_with_clock(1, Some(wait), &clock));
+        assert_eq!(clock.now() - started, Duration::from_millis(100));
+        assert_eq!(budget.used(), 100);
+
+        budget.credit(100);
+        assert!(budget.charge_with_clock(60, Some(wait), &clock));
+        assert!(!budget.charge_with_clock(60, None, &clock));
+        assert_eq!(budget.used(), 60);
+        assert_eq!(budget.peak(), 100);
+    }
+}
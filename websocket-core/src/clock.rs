@@ -0,0 +1,76 @@
+//! Time sources for the time-based features, so they can run against a
+//! simulated clock instead of the system one.
+use std::fmt::Debug;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub trait Clock: Debug + Send + Sync {
+    fn now(&self) -> Instant;
+
+    /// Blocks until `now()` has reached `deadline`.
+    fn sleep_until(&self, deadline: Instant);
+}
+
+/// The system clock.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep_until(&self, deadline: Instant) {
+        let now = Instant::now();
+        if deadline > now {
+            thread::sleep(deadline - now);
+        }
+    }
+}
+
+/// A clock that only moves when told to. Clones share the same time.
+///
+/// Sleeping on it advances it to the deadline right away, so blocking
+/// paths run at simulated speed.
+#[derive(Debug, Clone)]
+pub struct SimClock {
+    base: Instant,
+    elapsed_nanos: Arc<AtomicU64>,
+}
+
+impl Default for SimClock {
+    fn default() -> Self {
+        SimClock::new()
+    }
+}
+
+impl SimClock {
+    pub fn new() -> SimClock {
+        SimClock {
+            base: Instant::now(),
+            elapsed_nanos: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    pub fn advance(&self, by: Duration) {
+        self.elapsed_nanos.fetch_add(by.as_nanos() as u64, Ordering::AcqRel);
+    }
+
+    /// Moves the clock to `at`, unless it is already past it.
+    pub fn advance_to(&self, at: Instant) {
+        let nanos = at.saturating_duration_since(self.base).as_nanos() as u64;
+        self.elapsed_nanos.fetch_max(nanos, Ordering::AcqRel);
+    }
+}
+
+impl Clock for SimClock {
+    fn now(&self) -> Instant {
+        self.base + Duration::from_nanos(self.elapsed_nanos.load(Ordering::Acquire))
+    }
+
+    fn sleep_until(&self, deadline: Instant) {
+        self.advance_to(deadline);
+    }
+}
@@ -0,0 +1,106 @@
+//! Names and canonical reasons for application-defined close codes.
+use thiserror::Error;
+use crate::message::Message;
+use crate::protocol::message::Type;
+
+/// The close codes reserved for applications (RFC6455 7.4.2).
+pub const APPLICATION_CLOSE_CODES: std::ops::RangeInclusive<u16> = 4000..=4999;
+
+/// One registered close code.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CloseCatalogEntry {
+    pub code: u16,
+    /// A short identifier, e.g. for metric labels.
+    pub name: String,
+    /// The reason sent in Close frames with this code.
+    pub reason: String,
+}
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum CatalogError {
+    #[error("close code {0} is outside the application range 4000-4999")]
+    OutOfRange(u16),
+    #[error("close code {0} is already registered")]
+    DuplicateCode(u16),
+    #[error("close code name {0:?} is already registered")]
+    DuplicateName(String),
+}
+
+/// Application close codes with their names and canonical reasons.
+///
+/// Codes that aren't registered still work everywhere, they just resolve
+/// to no entry. Share a catalog between connections with an `Arc`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(try_from = "Vec<CloseCatalogEntry>", into = "Vec<CloseCatalogEntry>")
+)]
+pub struct CloseCatalog {
+    entries: Vec<CloseCatalogEntry>,
+}
+
+impl CloseCatalog {
+    pub fn new() -> CloseCatalog {
+        CloseCatalog::default()
+    }
+
+    /// Registers `code`, which must be in the application range and not
+    /// registered yet, as must `name`.
+    pub fn register(&mut self, code: u16, name: &str, reason: &str) -> Result<&CloseCatalogEntry, CatalogError> {
+        if !APPLICATION_CLOSE_CODES.contains(&code) {
+            return Err(CatalogError::OutOfRange(code));
+        }
+        if self.get(code).is_some() {
+            return Err(CatalogError::DuplicateCode(code));
+        }
+        if self.by_name(name).is_some() {
+            return Err(CatalogError::DuplicateName(name.to_string()));
+        }
+        self.entries.push(CloseCatalogEntry {
+            code,
+            name: name.to_string(),
+            reason: reason.to_string(),
+        });
+        Ok(&self.entries[self.entries.len() - 1])
+    }
+
+    pub fn get(&self, code: u16) -> Option<&CloseCatalogEntry> {
+        self.entries.iter().find(|entry| entry.code == code)
+    }
+
+    pub fn by_name(&self, name: &str) -> Option<&CloseCatalogEntry> {
+        self.entries.iter().find(|entry| entry.name == name)
+    }
+
+    /// The entry of the status code of a Close message, if registered.
+    pub fn resolve(&self, close: &Message) -> Option<&CloseCatalogEntry> {
+        match (close.opcode, close.cd_status_code) {
+            (Type::Close, Some(code)) => self.get(code),
+            _ => None,
+        }
+    }
+
+    pub fn entries(&self) -> &[CloseCatalogEntry] {
+        &self.entries
+    }
+}
+
+impl TryFrom<Vec<CloseCatalogEntry>> for CloseCatalog {
+    type Error = CatalogError;
+
+    fn try_from(entries: Vec<CloseCatalogEntry>) -> Result<CloseCatalog, CatalogError> {
+        let mut catalog = CloseCatalog::new();
+        for entry in entries {
+            catalog.register(entry.code, &entry.name, &entry.reason)?;
+        }
+        Ok(catalog)
+    }
+}
+
+impl From<CloseCatalog> for Vec<CloseCatalogEntry> {
+    fn from(catalog: CloseCatalog) -> Vec<CloseCatalogEntry> {
+        catalog.entries
+    }
+}
@@ -0,0 +1,109 @@
+//! Status codes of Close frames, and the code each error closes with.
+use std::fmt;
+use crate::error::WebSocketError;
+
+/// The status code of a Close frame (RFC6455 7.4).
+///
+/// Any `u16` converts to and from it, so codes without a constant here,
+/// e.g. those of a [`CloseCatalog`](crate::close_catalog::CloseCatalog),
+/// work as well.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct CloseCode(pub u16);
+
+impl CloseCode {
+    pub const NORMAL: CloseCode = CloseCode(1000);
+    pub const GOING_AWAY: CloseCode = CloseCode(1001);
+    pub const PROTOCOL_ERROR: CloseCode = CloseCode(1002);
+    pub const UNSUPPORTED_DATA: CloseCode = CloseCode(1003);
+    pub const INVALID_PAYLOAD: CloseCode = CloseCode(1007);
+    pub const POLICY_VIOLATION: CloseCode = CloseCode(1008);
+    pub const MESSAGE_TOO_BIG: CloseCode = CloseCode(1009);
+    pub const INTERNAL_ERROR: CloseCode = CloseCode(1011);
+    pub const TRY_AGAIN_LATER: CloseCode = CloseCode(1013);
+
+    /// The code to close the connection with because of `error`. The
+    /// mapping is stable:
+    ///
+    /// - 1000 (Normal Closure): `PeerFinishedSending`, `ClosedBeforePong`,
+    ///   the peer already closed
+    /// - 1001 (Going Away): `PongTimeout`
+    /// - 1002 (Protocol Error): `DataFrameError`, `ProtocolError`
+    /// - 1007 (Invalid Payload Data): `Utf8Error`
+    /// - 1009 (Message Too Big): `MessageTooLarge`
+    /// - 1011 (Internal Error): `Io`, `Transport`, `Poisoned`,
+    ///   `PeerDidNotClose`, `CloseHandshakeTimedOut`
+    /// - 1013 (Try Again Later): `GlobalMemoryPressure`
+    ///
+    /// For the errors mapped to 1011 a Close can't be sent any more, see
+    /// [`WebSocketError::should_attempt_close_handshake`]; the code is only
+    /// what to record or report.
+    pub fn from_error(error: &WebSocketError) -> CloseCode {
+        match error {
+            WebSocketError::PeerFinishedSending | WebSocketError::ClosedBeforePong => CloseCode::NORMAL,
+            WebSocketError::PongTimeout => CloseCode::GOING_AWAY,
+            WebSocketError::DataFrameError(_) | WebSocketError::ProtocolError(_) => CloseCode::PROTOCOL_ERROR,
+            WebSocketError::Utf8Error(_) => CloseCode::INVALID_PAYLOAD,
+            WebSocketError::MessageTooLarge { .. } => CloseCode::MESSAGE_TOO_BIG,
+            WebSocketError::Io(_)
+            | WebSocketError::Transport { .. }
+            | WebSocketError::Poisoned
+            | WebSocketError::PeerDidNotClose
+            | WebSocketError::CloseHandshakeTimedOut => CloseCode::INTERNAL_ERROR,
+            WebSocketError::GlobalMemoryPressure => CloseCode::TRY_AGAIN_LATER,
+        }
+    }
+}
+
+impl From<u16> for CloseCode {
+    fn from(code: u16) -> CloseCode {
+        CloseCode(code)
+    }
+}
+
+impl From<CloseCode> for u16 {
+    fn from(code: CloseCode) -> u16 {
+        code.0
+    }
+}
+
+impl fmt::Display for CloseCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use super::*;
+    use crate::error::TransportErrorKind;
+
+    #[test]
+    fn stable_error_mapping() {
+        let errors = [
+            (WebSocketError::PeerFinishedSending, 1000),
+            (WebSocketError::ClosedBeforePong, 1000),
+            (WebSocketError::PongTimeout, 1001),
+            (WebSocketError::DataFrameError("bad frame"), 1002),
+            (WebSocketError::ProtocolError("bad protocol"), 1002),
+            (WebSocketError::Utf8Error(String::from_utf8(vec![0xff]).unwrap_err().utf8_error()), 1007),
+            (WebSocketError::MessageTooLarge { limit: 16 }, 1009),
+            (WebSocketError::Io(io::ErrorKind::UnexpectedEof.into()), 1011),
+            (
+                WebSocketError::Transport {
+                    kind: TransportErrorKind::PeerGone,
+                    source: io::ErrorKind::BrokenPipe.into(),
+                },
+                1011,
+            ),
+            (WebSocketError::Poisoned, 1011),
+            (WebSocketError::PeerDidNotClose, 1011),
+            (WebSocketError::CloseHandshakeTimedOut, 1011),
+            (WebSocketError::GlobalMemoryPressure, 1013),
+        ];
+        for (error, code) in errors {
+            assert_eq!(CloseCode::from_error(&error), CloseCode(code), "{:?}", error);
+            assert_eq!(u16::from(error.close_code()), code, "{:?}", error);
+        }
+    }
+}
@@ -1 +1,4 @@
-pub mod order_byte;
\ No newline at end of file
+pub mod order_byte;
+pub mod visitor;
+#[cfg(feature = "pcap")]
+pub mod pcap;
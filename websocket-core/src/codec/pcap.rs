@@ -0,0 +1,818 @@
+//! WebSocket sessions read out of packet captures. A pcapng or pcap file is
+//! reduced to one TCP connection, both directions are reassembled, the HTTP
+//! handshake is split off and the rest is walked with [`visit_stream`] into
+//! a timeline of frames and messages.
+//!
+//! Reassembly is in order only: missing bytes are reported as gaps, and a
+//! direction isn't decoded past its first gap, as frames can't be found
+//! again in the middle of a stream.
+use std::io::{self, Read};
+use std::mem;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use thiserror::Error;
+use crate::error::WebSocketError;
+use super::visitor::{visit_stream, FrameSummary, FrameVisitor};
+
+const PCAPNG_SECTION_HEADER: u32 = 0x0A0D_0D0A;
+const PCAPNG_INTERFACE_DESCRIPTION: u32 = 1;
+const PCAPNG_ENHANCED_PACKET: u32 = 6;
+
+const LINKTYPE_NULL: u32 = 0;
+const LINKTYPE_ETHERNET: u32 = 1;
+const LINKTYPE_RAW: u32 = 101;
+const LINKTYPE_LINUX_SLL: u32 = 113;
+const LINKTYPE_IPV4: u32 = 228;
+const LINKTYPE_IPV6: u32 = 229;
+
+#[derive(Error, Debug)]
+pub enum PcapError {
+    #[error("IO error: {0}")]
+    Io(#[from] io::Error),
+    #[error("malformed capture: {0}")]
+    Malformed(&'static str),
+    #[error("unsupported link type {0}")]
+    UnsupportedLinkType(u32),
+}
+
+/// The connection to extract, telling the client from the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TcpFlow {
+    pub client: SocketAddr,
+    pub server: SocketAddr,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    ClientToServer,
+    ServerToClient,
+}
+
+/// Bytes of a direction that are missing from the capture.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CaptureGap {
+    /// Offset of the first missing byte in the direction's byte stream.
+    pub offset: u64,
+    pub len: u64,
+    /// When the first byte after the gap was captured.
+    pub timestamp: Duration,
+}
+
+/// A frame, with `summary.offset` counted from the start of the TCP
+/// stream, handshake included.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CapturedFrame {
+    /// When the last byte of the frame was captured.
+    pub timestamp: Duration,
+    pub direction: Direction,
+    pub summary: FrameSummary,
+}
+
+/// A message, control frames included. The payload is unmasked but
+/// otherwise as sent, compressed messages aren't inflated.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CapturedMessage {
+    /// When the last byte of the message was captured.
+    pub timestamp: Duration,
+    pub direction: Direction,
+    pub opcode: u8,
+    pub payload: Vec<u8>,
+    pub frames: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TimelineEvent {
+    Frame(CapturedFrame),
+    /// Follows the frame completing it.
+    Message(CapturedMessage),
+}
+
+impl TimelineEvent {
+    pub fn timestamp(&self) -> Duration {
+        match self {
+            TimelineEvent::Frame(frame) => frame.timestamp,
+            TimelineEvent::Message(message) => message.timestamp,
+        }
+    }
+
+    pub fn direction(&self) -> Direction {
+        match self {
+            TimelineEvent::Frame(frame) => frame.direction,
+            TimelineEvent::Message(message) => message.direction,
+        }
+    }
+}
+
+/// What was found in one direction of the connection.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DirectionReport {
+    /// The HTTP request or response head, if the stream starts with one.
+    pub handshake: Option<String>,
+    /// Bytes captured, not counting retransmissions.
+    pub captured_bytes: u64,
+    pub gaps: Vec<CaptureGap>,
+    /// Why frames stopped being decoded before the end of the stream.
+    pub stopped: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CapturedSession {
+    pub client: DirectionReport,
+    pub server: DirectionReport,
+    /// Frames and messages of both directions, by capture time.
+    pub timeline: Vec<TimelineEvent>,
+}
+
+/// Reads a pcapng or pcap capture and extracts the WebSocket session of
+/// `flow` from it. Packets of other connections are ignored, as are IP
+/// fragments.
+pub fn read_session<R>(reader: &mut R, flow: TcpFlow) -> Result<CapturedSession, PcapError>
+    where
+        R: Read,
+{
+    let mut data = Vec::new();
+    reader.read_to_end(&mut data)?;
+    let packets = if data.starts_with(&PCAPNG_SECTION_HEADER.to_le_bytes()) {
+        read_pcapng(&data)?
+    } else {
+        read_pcap(&data)?
+    };
+
+    let mut client = Segments::default();
+    let mut server = Segments::default();
+    for packet in packets {
+        let ip = match link_payload(packet.link_type, packet.data)? {
+            Some(ip) => ip,
+            None => continue,
+        };
+        let (src, dst, tcp) = match tcp_segment(ip) {
+            Some(segment) => segment,
+            None => continue,
+        };
+        let segment = match parse_tcp(src, dst, tcp) {
+            Some(segment) => segment,
+            None => continue,
+        };
+        if segment.src == flow.client && segment.dst == flow.server {
+            client.push(segment, packet.timestamp);
+        } else if segment.src == flow.server && segment.dst == flow.client {
+            server.push(segment, packet.timestamp);
+        }
+    }
+
+    let mut session = CapturedSession::default();
+    session.client = decode_direction(client.reassemble(), Direction::ClientToServer, &mut session.timeline);
+    session.server = decode_direction(server.reassemble(), Direction::ServerToClient, &mut session.timeline);
+    // stable, so frames of a direction keep their order on equal timestamps
+    session.timeline.sort_by_key(TimelineEvent::timestamp);
+    Ok(session)
+}
+
+struct Packet<'d> {
+    timestamp: Duration,
+    link_type: u32,
+    data: &'d [u8],
+}
+
+#[derive(Clone, Copy)]
+struct Endian {
+    big: bool,
+}
+
+impl Endian {
+    fn u16(self, bytes: &[u8]) -> u16 {
+        let bytes = [bytes[0], bytes[1]];
+        if self.big { u16::from_be_bytes(bytes) } else { u16::from_le_bytes(bytes) }
+    }
+
+    fn u32(self, bytes: &[u8]) -> u32 {
+        let bytes = [bytes[0], bytes[1], bytes[2], bytes[3]];
+        if self.big { u32::from_be_bytes(bytes) } else { u32::from_le_bytes(bytes) }
+    }
+}
+
+fn read_pcap(data: &[u8]) -> Result<Vec<Packet<'_>>, PcapError> {
+    let header = data.get(..24).ok_or(PcapError::Malformed("truncated pcap header"))?;
+    let (endian, nanos) = match header[..4] {
+        [0xD4, 0xC3, 0xB2, 0xA1] => (Endian { big: false }, false),
+        [0xA1, 0xB2, 0xC3, 0xD4] => (Endian { big: true }, false),
+        [0x4D, 0x3C, 0xB2, 0xA1] => (Endian { big: false }, true),
+        [0xA1, 0xB2, 0x3C, 0x4D] => (Endian { big: true }, true),
+        _ => return Err(PcapError::Malformed("neither a pcap nor a pcapng file")),
+    };
+    // the upper bits may carry FCS information
+    let link_type = endian.u32(&header[20..24]) & 0x0FFF_FFFF;
+
+    let mut packets = Vec::new();
+    let mut pos = 24;
+    while pos < data.len() {
+        let record = data.get(pos..pos + 16).ok_or(PcapError::Malformed("truncated pcap record"))?;
+        let secs = Duration::from_secs(endian.u32(&record[0..4]) as u64);
+        let fraction = endian.u32(&record[4..8]) as u64;
+        let len = endian.u32(&record[8..12]) as usize;
+        let body = data
+            .get(pos + 16..pos + 16 + len)
+            .ok_or(PcapError::Malformed("truncated pcap record"))?;
+        packets.push(Packet {
+            timestamp: secs + if nanos { Duration::from_nanos(fraction) } else { Duration::from_micros(fraction) },
+            link_type,
+            data: body,
+        });
+        pos += 16 + len;
+    }
+    Ok(packets)
+}
+
+struct Interface {
+    link_type: u32,
+    units_per_second: u64,
+}
+
+fn read_pcapng(data: &[u8]) -> Result<Vec<Packet<'_>>, PcapError> {
+    let mut endian = Endian { big: false };
+    let mut interfaces = Vec::new();
+    let mut packets = Vec::new();
+    let mut pos = 0;
+    while pos < data.len() {
+        let head = data.get(pos..pos + 12).ok_or(PcapError::Malformed("truncated pcapng block"))?;
+        // the section header's type reads the same either way round, its
+        // byte-order magic tells how the rest of the section is written
+        if head[..4] == PCAPNG_SECTION_HEADER.to_le_bytes() {
+            endian = match head[8..12] {
+                [0x1A, 0x2B, 0x3C, 0x4D] => Endian { big: true },
+                [0x4D, 0x3C, 0x2B, 0x1A] => Endian { big: false },
+                _ => return Err(PcapError::Malformed("bad pcapng byte-order magic")),
+            };
+            interfaces.clear();
+        }
+        let block_type = endian.u32(&head[0..4]);
+        let len = endian.u32(&head[4..8]) as usize;
+        if len < 12 || !len.is_multiple_of(4) {
+            return Err(PcapError::Malformed("bad pcapng block length"));
+        }
+        let body = data.get(pos + 8..pos + len - 4).ok_or(PcapError::Malformed("truncated pcapng block"))?;
+
+        match block_type {
+            PCAPNG_INTERFACE_DESCRIPTION => {
+                if body.len() < 8 {
+                    return Err(PcapError::Malformed("truncated pcapng interface"));
+                }
+                interfaces.push(Interface {
+                    link_type: endian.u16(&body[0..2]) as u32,
+                    units_per_second: timestamp_resolution(endian, &body[8..])?,
+                });
+            }
+            PCAPNG_ENHANCED_PACKET => {
+                if body.len() < 20 {
+                    return Err(PcapError::Malformed("truncated pcapng packet"));
+                }
+                let interface = interfaces
+                    .get(endian.u32(&body[0..4]) as usize)
+                    .ok_or(PcapError::Malformed("pcapng packet of an undescribed interface"))?;
+                let units = (endian.u32(&body[4..8]) as u64) << 32 | endian.u32(&body[8..12]) as u64;
+                let len = endian.u32(&body[12..16]) as usize;
+                let packet = body.get(20..20 + len).ok_or(PcapError::Malformed("truncated pcapng packet"))?;
+                let per_second = interface.units_per_second;
+                let nanos = (units % per_second) as u128 * 1_000_000_000 / per_second as u128;
+                packets.push(Packet {
+                    timestamp: Duration::from_secs(units / per_second) + Duration::from_nanos(nanos as u64),
+                    link_type: interface.link_type,
+                    data: packet,
+                });
+            }
+            _ => {}
+        }
+        pos += len;
+    }
+    Ok(packets)
+}
+
+/// The `if_tsresol` of an interface's options, microseconds if unset.
+fn timestamp_resolution(endian: Endian, mut options: &[u8]) -> Result<u64, PcapError> {
+    while options.len() >= 4 {
+        let code = endian.u16(&options[0..2]);
+        let len = endian.u16(&options[2..4]) as usize;
+        let value = options.get(4..4 + len).ok_or(PcapError::Malformed("truncated pcapng option"))?;
+        match code {
+            0 => break,
+            9 if len == 1 => {
+                let exponent = (value[0] & 0x7F) as u32;
+                let base: u64 = if value[0] & 0x80 != 0 { 2 } else { 10 };
+                return base
+                    .checked_pow(exponent)
+                    .ok_or(PcapError::Malformed("pcapng timestamp resolution out of range"));
+            }
+            _ => {}
+        }
+        options = options.get(4 + len.div_ceil(4) * 4..).unwrap_or(&[]);
+    }
+    Ok(1_000_000)
+}
+
+/// The IP packet in a link-layer frame, `None` if it carries something else.
+fn link_payload(link_type: u32, data: &[u8]) -> Result<Option<&[u8]>, PcapError> {
+    Ok(match link_type {
+        LINKTYPE_NULL => data.get(4..),
+        LINKTYPE_ETHERNET => {
+            let mut pos = 12;
+            loop {
+                match data.get(pos..pos + 2) {
+                    // 802.1Q and 802.1ad tags
+                    Some([0x81, 0x00]) | Some([0x88, 0xA8]) => pos += 4,
+                    Some([0x08, 0x00]) | Some([0x86, 0xDD]) => break data.get(pos + 2..),
+                    _ => break None,
+                }
+            }
+        }
+        LINKTYPE_RAW | LINKTYPE_IPV4 | LINKTYPE_IPV6 => Some(data),
+        LINKTYPE_LINUX_SLL => match data.get(14..16) {
+            Some([0x08, 0x00]) | Some([0x86, 0xDD]) => data.get(16..),
+            _ => None,
+        },
+        other => return Err(PcapError::UnsupportedLinkType(other)),
+    })
+}
+
+/// The addresses and TCP segment of an IP packet, cut to the length the IP
+/// header gives as link layers may pad.
+fn tcp_segment(ip: &[u8]) -> Option<(IpAddr, IpAddr, &[u8])> {
+    match ip.first()? >> 4 {
+        4 if ip.len() >= 20 => {
+            let header_len = (ip[0] & 0x0F) as usize * 4;
+            let total_len = u16::from_be_bytes([ip[2], ip[3]]) as usize;
+            let fragment = u16::from_be_bytes([ip[6], ip[7]]);
+            if ip[9] != 6 || fragment & 0x3FFF != 0 {
+                return None;
+            }
+            let src = Ipv4Addr::new(ip[12], ip[13], ip[14], ip[15]);
+            let dst = Ipv4Addr::new(ip[16], ip[17], ip[18], ip[19]);
+            Some((src.into(), dst.into(), ip.get(header_len..total_len.min(ip.len()))?))
+        }
+        6 if ip.len() >= 40 => {
+            let end = (40 + u16::from_be_bytes([ip[4], ip[5]]) as usize).min(ip.len());
+            let mut next = ip[6];
+            let mut pos = 40;
+            // hop-by-hop, routing and destination options headers
+            while matches!(next, 0 | 43 | 60) {
+                let header = ip.get(pos..pos + 2)?;
+                next = header[0];
+                pos += (header[1] as usize + 1) * 8;
+            }
+            if next != 6 {
+                return None;
+            }
+            let src = Ipv6Addr::from(<[u8; 16]>::try_from(&ip[8..24]).ok()?);
+            let dst = Ipv6Addr::from(<[u8; 16]>::try_from(&ip[24..40]).ok()?);
+            Some((src.into(), dst.into(), ip.get(pos..end)?))
+        }
+        _ => None,
+    }
+}
+
+struct TcpSegment<'d> {
+    src: SocketAddr,
+    dst: SocketAddr,
+    seq: u32,
+    syn: bool,
+    payload: &'d [u8],
+}
+
+fn parse_tcp<'d>(src: IpAddr, dst: IpAddr, tcp: &'d [u8]) -> Option<TcpSegment<'d>> {
+    if tcp.len() < 20 {
+        return None;
+    }
+    let header_len = (tcp[12] >> 4) as usize * 4;
+    Some(TcpSegment {
+        src: SocketAddr::new(src, u16::from_be_bytes([tcp[0], tcp[1]])),
+        dst: SocketAddr::new(dst, u16::from_be_bytes([tcp[2], tcp[3]])),
+        seq: u32::from_be_bytes([tcp[4], tcp[5], tcp[6], tcp[7]]),
+        syn: tcp[13] & 0x02 != 0,
+        payload: tcp.get(header_len..)?,
+    })
+}
+
+/// The segments of one direction, in capture order.
+#[derive(Default)]
+struct Segments<'d> {
+    initial_seq: Option<u32>,
+    segments: Vec<(u32, Duration, &'d [u8])>,
+}
+
+/// A direction's byte stream up to its first gap.
+struct Reassembled {
+    data: Vec<u8>,
+    /// Where each captured piece of `data` starts and when it was captured.
+    times: Vec<(u64, Duration)>,
+    gaps: Vec<CaptureGap>,
+    captured_bytes: u64,
+}
+
+impl<'d> Segments<'d> {
+    fn push(&mut self, segment: TcpSegment<'d>, timestamp: Duration) {
+        let mut seq = segment.seq;
+        if segment.syn {
+            seq = seq.wrapping_add(1);
+            self.initial_seq = Some(seq);
+        }
+        if !segment.payload.is_empty() {
+            self.segments.push((seq, timestamp, segment.payload));
+        }
+    }
+
+    fn reassemble(self) -> Reassembled {
+        // without the SYN the stream starts with the first segment seen
+        let initial_seq = self.initial_seq.or(self.segments.first().map(|(seq, _, _)| *seq));
+        let mut segments: Vec<_> = self
+            .segments
+            .into_iter()
+            .map(|(seq, timestamp, payload)| (seq.wrapping_sub(initial_seq.unwrap_or(seq)), timestamp, payload))
+            // anything "before" the start wraps around to the far end
+            .filter(|(offset, _, _)| *offset < u32::MAX / 2)
+            .map(|(offset, timestamp, payload)| (offset as u64, timestamp, payload))
+            .collect();
+        segments.sort_by_key(|(offset, _, _)| *offset);
+
+        let mut reassembled = Reassembled {
+            data: Vec::new(),
+            times: Vec::new(),
+            gaps: Vec::new(),
+            captured_bytes: 0,
+        };
+        let mut next = 0;
+        let mut latest = Duration::ZERO;
+        for (offset, timestamp, payload) in segments {
+            let end = offset + payload.len() as u64;
+            if end <= next {
+                continue;
+            }
+            if offset > next {
+                reassembled.gaps.push(CaptureGap {
+                    offset: next,
+                    len: offset - next,
+                    timestamp,
+                });
+            }
+            let start = next.max(offset);
+            let new = &payload[(start - offset) as usize..];
+            reassembled.captured_bytes += new.len() as u64;
+            if reassembled.gaps.is_empty() {
+                // a byte doesn't count as captured before the ones ahead of it
+                latest = latest.max(timestamp);
+                reassembled.times.push((start, latest));
+                reassembled.data.extend_from_slice(new);
+            }
+            next = end;
+        }
+        reassembled
+    }
+}
+
+impl Reassembled {
+    fn time_of(&self, offset: u64) -> Duration {
+        let index = self.times.partition_point(|(start, _)| *start <= offset);
+        self.times[index.saturating_sub(1)].1
+    }
+}
+
+/// Collects the frames and messages of one direction.
+struct TimelineVisitor<'r> {
+    direction: Direction,
+    handshake_len: u64,
+    stream: &'r Reassembled,
+    frame_payload: Vec<u8>,
+    /// Opcode, payload and frame count of the data message in progress.
+    message: Option<(u8, Vec<u8>, usize)>,
+    events: Vec<TimelineEvent>,
+}
+
+impl FrameVisitor for TimelineVisitor<'_> {
+    fn on_payload_chunk(&mut self, chunk: &[u8], _masked: bool) {
+        self.frame_payload.extend_from_slice(chunk);
+    }
+
+    fn on_frame_end(&mut self, mut summary: FrameSummary) {
+        summary.offset += self.handshake_len;
+        let last_byte = summary.offset + summary.header_len as u64 + summary.payload_len - 1;
+        let timestamp = self.stream.time_of(last_byte);
+        let payload = mem::take(&mut self.frame_payload);
+        let (opcode, finished) = (summary.opcode, summary.finished);
+        self.events.push(TimelineEvent::Frame(CapturedFrame {
+            timestamp,
+            direction: self.direction,
+            summary,
+        }));
+
+        let message = if opcode & 0x8 != 0 {
+            Some((opcode, payload, 1))
+        } else {
+            // a new data frame abandons the message in progress, as a
+            // receiver would fail it
+            let (opcode, mut data, frames) = match self.message.take() {
+                Some(message) if opcode == 0 => message,
+                _ => (opcode, Vec::new(), 0),
+            };
+            data.extend_from_slice(&payload);
+            if finished {
+                Some((opcode, data, frames + 1))
+            } else {
+                self.message = Some((opcode, data, frames + 1));
+                None
+            }
+        };
+        if let Some((opcode, payload, frames)) = message {
+            self.events.push(TimelineEvent::Message(CapturedMessage {
+                timestamp,
+                direction: self.direction,
+                opcode,
+                payload,
+                frames,
+            }));
+        }
+    }
+}
+
+/// Splits an HTTP head off the start of `data`, returning it and its
+/// length. `Err` holds what there is of a head the stream ends inside.
+fn split_handshake(data: &[u8]) -> Result<Option<(String, usize)>, String> {
+    if !data.starts_with(b"GET ") && !data.starts_with(b"HTTP/") {
+        return Ok(None);
+    }
+    match data.windows(4).position(|window| window == b"\r\n\r\n") {
+        Some(end) => Ok(Some((String::from_utf8_lossy(&data[..end + 4]).into_owned(), end + 4))),
+        None => Err(String::from_utf8_lossy(data).into_owned()),
+    }
+}
+
+fn decode_direction(stream: Reassembled, direction: Direction, timeline: &mut Vec<TimelineEvent>) -> DirectionReport {
+    let mut report = DirectionReport {
+        handshake: None,
+        captured_bytes: stream.captured_bytes,
+        gaps: stream.gaps.clone(),
+        stopped: None,
+    };
+    let handshake_len = match split_handshake(&stream.data) {
+        Ok(Some((head, len))) => {
+            report.handshake = Some(head);
+            len
+        }
+        Ok(None) => 0,
+        Err(partial) => {
+            report.handshake = Some(partial);
+            report.stopped = Some("the capture ends inside the HTTP handshake".to_string());
+            return report;
+        }
+    };
+
+    let mut visitor = TimelineVisitor {
+        direction,
+        handshake_len: handshake_len as u64,
+        stream: &stream,
+        frame_payload: Vec::new(),
+        message: None,
+        events: Vec::new(),
+    };
+    let should_be_masked = direction == Direction::ClientToServer;
+    let result = visit_stream(&mut &stream.data[handshake_len..], &mut visitor, Some(should_be_masked));
+    timeline.append(&mut visitor.events);
+
+    let gap = stream.gaps.first();
+    report.stopped = match (result, gap) {
+        (Err(WebSocketError::Io(ref e)), Some(gap)) if e.kind() == io::ErrorKind::UnexpectedEof => {
+            Some(format!("frame cut off by the gap at offset {}", gap.offset))
+        }
+        (Err(WebSocketError::Io(ref e)), None) if e.kind() == io::ErrorKind::UnexpectedEof => {
+            Some("the capture ends inside a frame".to_string())
+        }
+        (Err(e), _) => Some(e.to_string()),
+        (Ok(_), Some(gap)) => Some(format!("not decoded past the gap at offset {}", gap.offset)),
+        (Ok(_), None) => None,
+    };
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dataframe::DataFrame;
+    use crate::protocol::dataframe::DataFrameRefTrait;
+    use crate::protocol::header::Opcode;
+
+    const START_MICROS: u64 = 1_700_000_000_000_000;
+
+    fn flow() -> TcpFlow {
+        TcpFlow {
+            client: "10.0.0.1:50000".parse().unwrap(),
+            server: "10.0.0.2:80".parse().unwrap(),
+        }
+    }
+
+    fn frame(finished: bool, opcode: Opcode, data: &[u8], mask: bool) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        DataFrame::new(finished, opcode, data.to_vec()).write_to(&mut bytes, mask).unwrap();
+        bytes
+    }
+
+    /// Ethernet frames of one connection, one millisecond apart.
+    struct Capture {
+        packets: Vec<(u64, Vec<u8>)>,
+        seq: [u32; 2],
+    }
+
+    impl Capture {
+        fn new(client_seq: u32, server_seq: u32) -> Capture {
+            Capture {
+                packets: Vec::new(),
+                seq: [client_seq, server_seq],
+            }
+        }
+
+        fn micros(&self) -> u64 {
+            START_MICROS + self.packets.len() as u64 * 1000
+        }
+
+        fn packet(&mut self, direction: Direction, syn: bool, payload: &[u8]) {
+            let (side, from, to) = match direction {
+                Direction::ClientToServer => (0, flow().client, flow().server),
+                Direction::ServerToClient => (1, flow().server, flow().client),
+            };
+            let (IpAddr::V4(src), IpAddr::V4(dst)) = (from.ip(), to.ip()) else { unreachable!() };
+            let ip_len = 40 + payload.len();
+            let mut packet = vec![0; 12];
+            packet.extend([0x08, 0x00]);
+            packet.extend([0x45, 0, (ip_len >> 8) as u8, ip_len as u8, 0, 0, 0x40, 0, 64, 6, 0, 0]);
+            packet.extend(src.octets());
+            packet.extend(dst.octets());
+            packet.extend(from.port().to_be_bytes());
+            packet.extend(to.port().to_be_bytes());
+            packet.extend(self.seq[side].to_be_bytes());
+            packet.extend([0; 4]);
+            packet.extend([0x50, if syn { 0x02 } else { 0x18 }, 0xFF, 0xFF, 0, 0, 0, 0]);
+            packet.extend(payload);
+            self.seq[side] = self.seq[side].wrapping_add(payload.len() as u32 + syn as u32);
+            self.packets.push((self.micros(), packet));
+        }
+
+        fn syn(&mut self, direction: Direction) {
+            self.packet(direction, true, &[]);
+        }
+
+        fn send(&mut self, direction: Direction, payload: &[u8]) {
+            self.packet(direction, false, payload);
+        }
+
+        /// Bytes sent but not captured.
+        fn lose(&mut self, direction: Direction, len: u32) {
+            let side = (direction == Direction::ServerToClient) as usize;
+            self.seq[side] = self.seq[side].wrapping_add(len);
+        }
+
+        fn repeat_last(&mut self) {
+            let packet = self.packets.last().unwrap().1.clone();
+            self.packets.push((self.micros(), packet));
+        }
+
+        fn pcapng(&self) -> Vec<u8> {
+            fn block(out: &mut Vec<u8>, block_type: u32, body: &[u8]) {
+                let padded = body.len().div_ceil(4) * 4;
+                let len = (12 + padded) as u32;
+                out.extend(block_type.to_le_bytes());
+                out.extend(len.to_le_bytes());
+                out.extend(body);
+                out.resize(out.len() + padded - body.len(), 0);
+                out.extend(len.to_le_bytes());
+            }
+            let mut out = Vec::new();
+            block(&mut out, PCAPNG_SECTION_HEADER, &[0x4D, 0x3C, 0x2B, 0x1A, 1, 0, 0, 0, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF]);
+            // Ethernet, nanosecond timestamps
+            block(&mut out, PCAPNG_INTERFACE_DESCRIPTION, &[1, 0, 0, 0, 0, 0, 0, 0, 9, 0, 1, 0, 9, 0, 0, 0, 0, 0, 0, 0]);
+            for (micros, packet) in &self.packets {
+                let nanos = micros * 1000;
+                let mut body = Vec::new();
+                body.extend(0u32.to_le_bytes());
+                body.extend(((nanos >> 32) as u32).to_le_bytes());
+                body.extend((nanos as u32).to_le_bytes());
+                body.extend((packet.len() as u32).to_le_bytes());
+                body.extend((packet.len() as u32).to_le_bytes());
+                body.extend(packet);
+                block(&mut out, PCAPNG_ENHANCED_PACKET, &body);
+            }
+            out
+        }
+
+        fn pcap(&self) -> Vec<u8> {
+            let mut out = vec![0xD4, 0xC3, 0xB2, 0xA1, 2, 0, 4, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xFF, 0xFF, 0, 0, 1, 0, 0, 0];
+            for (micros, packet) in &self.packets {
+                out.extend(((micros / 1_000_000) as u32).to_le_bytes());
+                out.extend(((micros % 1_000_000) as u32).to_le_bytes());
+                out.extend((packet.len() as u32).to_le_bytes());
+                out.extend((packet.len() as u32).to_le_bytes());
+                out.extend(packet);
+            }
+            out
+        }
+    }
+
+    fn messages(session: &CapturedSession) -> Vec<(Direction, u8, Vec<u8>)> {
+        session
+            .timeline
+            .iter()
+            .filter_map(|event| match event {
+                TimelineEvent::Message(message) => Some((message.direction, message.opcode, message.payload.clone())),
+                TimelineEvent::Frame(_) => None,
+            })
+            .collect()
+    }
+
+    const REQUEST: &[u8] = b"GET /chat HTTP/1.1\r\nHost: example.com\r\nUpgrade: websocket\r\n\r\n";
+    const RESPONSE: &[u8] = b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\n\r\n";
+
+    fn clean_session() -> Capture {
+        use Direction::*;
+        // the client's sequence numbers wrap around
+        let mut capture = Capture::new(0xFFFF_FFF0, 1000);
+        capture.syn(ClientToServer);
+        capture.syn(ServerToClient);
+        capture.send(ClientToServer, REQUEST);
+        let mut response = RESPONSE.to_vec();
+        response.extend(frame(true, Opcode::Text, b"hi", false));
+        capture.send(ServerToClient, &response);
+        let mut fragments = frame(false, Opcode::Text, b"hel", true);
+        fragments.extend(frame(true, Opcode::Continuation, b"lo", true));
+        capture.send(ClientToServer, &fragments[..5]);
+        capture.send(ClientToServer, &fragments[5..]);
+        capture.repeat_last();
+        capture.send(ServerToClient, &frame(true, Opcode::Ping, b"p", false));
+        capture.send(ClientToServer, &frame(true, Opcode::Pong, b"p", true));
+        capture.send(ServerToClient, &frame(true, Opcode::Close, &[3, 232], false));
+        capture
+    }
+
+    #[test]
+    fn clean_session_timeline() {
+        use Direction::*;
+        let session = read_session(&mut &clean_session().pcapng()[..], flow()).unwrap();
+
+        assert_eq!(session.client.handshake.as_deref().map(str::as_bytes), Some(REQUEST));
+        assert_eq!(session.server.handshake.as_deref().map(str::as_bytes), Some(RESPONSE));
+        assert!(session.client.gaps.is_empty() && session.server.gaps.is_empty());
+        assert_eq!((&session.client.stopped, &session.server.stopped), (&None, &None));
+        assert_eq!(messages(&session), vec![
+            (ServerToClient, 1, b"hi".to_vec()),
+            (ClientToServer, 1, b"hello".to_vec()),
+            (ServerToClient, 9, b"p".to_vec()),
+            (ClientToServer, 10, b"p".to_vec()),
+            (ServerToClient, 8, vec![3, 232]),
+        ]);
+
+        let hello = session.timeline.iter().find_map(|event| match event {
+            TimelineEvent::Message(message) if message.payload == b"hello" => Some(message),
+            _ => None,
+        }).unwrap();
+        // completed by the sixth packet, not its retransmission
+        assert_eq!(hello.timestamp, Duration::from_micros(START_MICROS + 5 * 1000));
+        assert_eq!(hello.frames, 2);
+        let frames = session.timeline.iter().filter(|event| matches!(event, TimelineEvent::Frame(_))).count();
+        assert_eq!(frames, 6);
+    }
+
+    #[test]
+    fn pcap_reads_like_pcapng() {
+        let capture = clean_session();
+        let from_pcap = read_session(&mut &capture.pcap()[..], flow()).unwrap();
+        let from_pcapng = read_session(&mut &capture.pcapng()[..], flow()).unwrap();
+        assert_eq!(from_pcap, from_pcapng);
+    }
+
+    #[test]
+    fn gap_inside_a_frame() {
+        use Direction::*;
+        // no SYNs, the capture starts with the handshake
+        let mut capture = Capture::new(5000, 9000);
+        capture.send(ClientToServer, REQUEST);
+        capture.send(ServerToClient, RESPONSE);
+        let before = frame(true, Opcode::Text, b"before", false);
+        capture.send(ServerToClient, &before);
+        let cut = frame(true, Opcode::Binary, &[7; 10], false);
+        capture.send(ServerToClient, &cut[..4]);
+        capture.lose(ServerToClient, 4);
+        capture.send(ServerToClient, &cut[8..]);
+        capture.send(ServerToClient, &frame(true, Opcode::Text, b"after", false));
+        capture.send(ClientToServer, &frame(true, Opcode::Text, b"client", true));
+
+        let session = read_session(&mut &capture.pcapng()[..], flow()).unwrap();
+        assert_eq!(messages(&session), vec![
+            (ServerToClient, 1, b"before".to_vec()),
+            (ClientToServer, 1, b"client".to_vec()),
+        ]);
+        let offset = (RESPONSE.len() + before.len() + 4) as u64;
+        assert_eq!(session.server.gaps, vec![CaptureGap {
+            offset,
+            len: 4,
+            timestamp: Duration::from_micros(START_MICROS + 4 * 1000),
+        }]);
+        assert_eq!(session.server.stopped, Some(format!("frame cut off by the gap at offset {}", offset)));
+        assert_eq!(session.server.captured_bytes, (RESPONSE.len() + before.len() + cut.len() - 4 + 7) as u64);
+        assert!(session.client.gaps.is_empty());
+        assert_eq!(session.client.stopped, None);
+    }
+}
@@ -0,0 +1,159 @@
+//! Frame-by-frame walk over a raw WebSocket byte stream, for analyzers that
+//! need the structure the message API hides.
+use std::io::{self, Read};
+use crate::error::WebSocketError;
+use crate::protocol::header::{DataFrameFlags, DataFrameHeader, FrameHeader, MaskingImpl};
+use crate::result::WebSocketResult;
+
+const VISIT_CHUNK_SIZE: usize = 8 * 1024;
+
+/// How the payload length of a frame was encoded on the wire.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LengthEncoding {
+    /// 7-bit length in the second header byte.
+    Short,
+    /// 126 followed by a 16-bit length.
+    Medium,
+    /// 127 followed by a 64-bit length.
+    Long,
+}
+
+/// Everything known about a frame once it has been fully visited.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FrameSummary {
+    /// Offset of the first header byte within the stream.
+    pub offset: u64,
+    pub finished: bool,
+    pub reserved: [bool; 3],
+    pub opcode: u8,
+    pub mask: Option<[u8; 4]>,
+    pub length_encoding: LengthEncoding,
+    pub header_len: usize,
+    pub payload_len: u64,
+}
+
+/// Totals over a visited stream.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct VisitStats {
+    pub frames: u64,
+    /// All bytes consumed, headers included.
+    pub bytes: u64,
+    pub payload_bytes: u64,
+}
+
+/// Callbacks invoked by [`visit_stream`]. All of them do nothing by default.
+pub trait FrameVisitor {
+    /// Called once the header of a frame has been parsed.
+    fn on_header(&mut self, _header: &DataFrameHeader, _header_bytes: &[u8], _stream_offset: u64) {}
+
+    /// Called for each chunk of payload, already unmasked. `masked` tells
+    /// whether the chunk was masked on the wire.
+    fn on_payload_chunk(&mut self, _chunk: &[u8], _masked: bool) {}
+
+    /// Called after the last payload chunk of a frame.
+    fn on_frame_end(&mut self, _summary: FrameSummary) {}
+}
+
+/// Keeps a copy of every byte read through it.
+struct Recorder<'r, R> {
+    inner: &'r mut R,
+    recorded: Vec<u8>,
+}
+
+impl<'r, R: Read> Read for Recorder<'r, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.recorded.extend_from_slice(&buf[..read]);
+        Ok(read)
+    }
+}
+
+/// Walks `reader` frame by frame until it ends, reporting every frame to
+/// `visitor`. Frames are not assembled into messages and no message-level
+/// limits apply; only the frame-level checks of the header parser run.
+///
+/// With `direction_should_be_masked` set, frames whose masking doesn't
+/// match are rejected.
+pub fn visit_stream<R, V>(
+    reader: &mut R,
+    visitor: &mut V,
+    direction_should_be_masked: Option<bool>,
+) -> WebSocketResult<VisitStats>
+    where
+        R: Read,
+        V: FrameVisitor,
+{
+    let mut stats = VisitStats::default();
+    let mut chunk = vec![0u8; VISIT_CHUNK_SIZE];
+
+    loop {
+        let mut recorder = Recorder {
+            inner: &mut *reader,
+            recorded: Vec::with_capacity(14),
+        };
+        let header = match DataFrameHeader::read(&mut recorder) {
+            Ok(header) => header,
+            // the stream ended cleanly between two frames
+            Err(WebSocketError::Io(ref e))
+                if e.kind() == io::ErrorKind::UnexpectedEof && recorder.recorded.is_empty() => {
+                return Ok(stats);
+            }
+            Err(e) => return Err(e),
+        };
+        let header_bytes = recorder.recorded;
+
+        if let Some(should_be_masked) = direction_should_be_masked {
+            if header.mask.is_some() != should_be_masked {
+                return Err(WebSocketError::DataFrameError(if should_be_masked {
+                    "Expected masked data frame"
+                } else {
+                    "Expected unmasked data frame"
+                }));
+            }
+        }
+
+        let offset = stats.bytes;
+        visitor.on_header(&header, &header_bytes, offset);
+
+        let mut remaining = header.len;
+        let mut pos = 0usize;
+        while remaining > 0 {
+            let want = remaining.min(chunk.len() as u64) as usize;
+            let read = match reader.read(&mut chunk[..want]) {
+                Ok(read) => read,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            };
+            if read == 0 {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "incomplete payload").into());
+            }
+            let data = &mut chunk[..read];
+            if let Some(mask) = header.mask {
+                MaskingImpl::default().apply(mask, pos, data);
+            }
+            visitor.on_payload_chunk(data, header.mask.is_some());
+            pos = (pos + read) % 4;
+            remaining -= read as u64;
+        }
+
+        let length_encoding = match header_bytes[1] & 0x7F {
+            126 => LengthEncoding::Medium,
+            127 => LengthEncoding::Long,
+            _ => LengthEncoding::Short,
+        };
+        stats.frames += 1;
+        stats.bytes += header_bytes.len() as u64 + header.len;
+        stats.payload_bytes += header.len;
+
+        visitor.on_frame_end(FrameSummary {
+            offset,
+            finished: header.flags.contains(DataFrameFlags::FIN),
+            reserved: header.flags.reserved(),
+            opcode: header.opcode,
+            mask: header.mask,
+            length_encoding,
+            header_len: header_bytes.len(),
+            payload_len: header.len,
+        });
+    }
+}
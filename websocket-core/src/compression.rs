@@ -0,0 +1,203 @@
+//! Per-message compression codecs.
+//!
+//! A codec is registered under the extension name negotiated in
+//! `Sec-WebSocket-Extensions`. While it is active, data messages are
+//! compressed as a whole and sent with RSV1 set; received messages with
+//! RSV1 set are decompressed before they are assembled.
+use std::collections::HashMap;
+use std::sync::Arc;
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress, Status};
+use crate::error::WebSocketError;
+use crate::result::WebSocketResult;
+
+/// The name of the permessage-deflate extension (RFC7692).
+pub const PERMESSAGE_DEFLATE: &str = "permessage-deflate";
+
+/// Data messages with payloads up to this long are sent uncompressed by
+/// default; deflate only makes them bigger.
+pub const DEFAULT_COMPRESSION_THRESHOLD: usize = 64;
+
+/// The four bytes a sync flush ends with, stripped from every compressed
+/// message by RFC7692 7.2.1.
+const DEFLATE_TRAILER: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+
+/// Compresses the payloads of outgoing messages.
+///
+/// `Sync`, so a `Sender` holding one can be shared between threads.
+pub trait Compressor: Send + Sync {
+    /// Appends the compressed form of `input` to `output`.
+    fn compress(&mut self, input: &[u8], output: &mut Vec<u8>) -> WebSocketResult<()>;
+}
+
+/// Decompresses the payloads of incoming messages.
+///
+/// A message can be fed in parts, typically one frame at a time, so it
+/// never has to be held compressed and decompressed at once.
+pub trait Decompressor: Send {
+    /// Appends the decompressed form of `input`, the next part of a
+    /// message, to `output`, failing once `output` would grow past
+    /// `max_len` bytes.
+    fn decompress_chunk(&mut self, input: &[u8], output: &mut Vec<u8>, max_len: usize) -> WebSocketResult<()>;
+
+    /// Ends the message fed so far, appending what is left of it to
+    /// `output` under the same limit.
+    fn finish_message(&mut self, output: &mut Vec<u8>, max_len: usize) -> WebSocketResult<()>;
+
+    /// Forgets everything kept from earlier messages, including a message
+    /// left unfinished, as if newly created.
+    fn reset(&mut self);
+
+    /// Appends the decompressed form of the whole message `input` to
+    /// `output`, failing once `output` would grow past `max_len` bytes.
+    fn decompress(&mut self, input: &[u8], output: &mut Vec<u8>, max_len: usize) -> WebSocketResult<()> {
+        self.decompress_chunk(input, output, max_len)?;
+        self.finish_message(output, max_len)
+    }
+}
+
+/// Creates the compressor and decompressor of one connection.
+pub trait CompressionCodec: Send + Sync {
+    fn new_compressor(&self) -> Box<dyn Compressor>;
+    fn new_decompressor(&self) -> Box<dyn Decompressor>;
+}
+
+/// Codecs keyed by extension name. permessage-deflate is always available.
+#[derive(Clone)]
+pub struct CodecRegistry {
+    codecs: HashMap<String, Arc<dyn CompressionCodec>>,
+}
+
+impl Default for CodecRegistry {
+    fn default() -> Self {
+        let mut registry = CodecRegistry {
+            codecs: HashMap::new(),
+        };
+        registry.register(PERMESSAGE_DEFLATE, Arc::new(DeflateCodec::default()));
+        registry
+    }
+}
+
+impl CodecRegistry {
+    /// Registers `codec` under `name`, replacing any codec registered before.
+    pub fn register(&mut self, name: &str, codec: Arc<dyn CompressionCodec>) {
+        self.codecs.insert(name.to_ascii_lowercase(), codec);
+    }
+
+    pub fn get(&self, name: &str) -> Option<Arc<dyn CompressionCodec>> {
+        self.codecs.get(&name.to_ascii_lowercase()).cloned()
+    }
+}
+
+/// permessage-deflate as described by RFC7692.
+#[derive(Debug, Clone, Copy)]
+pub struct DeflateCodec {
+    /// Compression level, 0 to 9.
+    pub level: u32,
+    /// Reset the compression context after every message.
+    pub compress_no_context_takeover: bool,
+    /// Reset the decompression context after every message.
+    pub decompress_no_context_takeover: bool,
+}
+
+impl Default for DeflateCodec {
+    fn default() -> Self {
+        DeflateCodec {
+            level: 6,
+            compress_no_context_takeover: false,
+            decompress_no_context_takeover: false,
+        }
+    }
+}
+
+impl CompressionCodec for DeflateCodec {
+    fn new_compressor(&self) -> Box<dyn Compressor> {
+        Box::new(DeflateCompressor {
+            inner: Compress::new(Compression::new(self.level), false),
+            no_context_takeover: self.compress_no_context_takeover,
+        })
+    }
+
+    fn new_decompressor(&self) -> Box<dyn Decompressor> {
+        Box::new(DeflateDecompressor {
+            inner: Decompress::new(false),
+            no_context_takeover: self.decompress_no_context_takeover,
+        })
+    }
+}
+
+pub struct DeflateCompressor {
+    inner: Compress,
+    no_context_takeover: bool,
+}
+
+impl Compressor for DeflateCompressor {
+    fn compress(&mut self, input: &[u8], output: &mut Vec<u8>) -> WebSocketResult<()> {
+        let start = self.inner.total_in();
+        loop {
+            output.reserve(input.len() / 2 + 64);
+            let consumed = (self.inner.total_in() - start) as usize;
+            self.inner
+                .compress_vec(&input[consumed..], output, FlushCompress::Sync)
+                .map_err(|_| WebSocketError::ProtocolError("Failed to compress message"))?;
+            let consumed = (self.inner.total_in() - start) as usize;
+            // the flush is complete once all input is in and output space is left over
+            if consumed == input.len() && output.len() < output.capacity() {
+                break;
+            }
+        }
+        if output.ends_with(&DEFLATE_TRAILER) {
+            output.truncate(output.len() - DEFLATE_TRAILER.len());
+        }
+        if self.no_context_takeover {
+            self.inner.reset();
+        }
+        Ok(())
+    }
+}
+
+pub struct DeflateDecompressor {
+    inner: Decompress,
+    no_context_takeover: bool,
+}
+
+impl DeflateDecompressor {
+    fn inflate(&mut self, input: &[u8], output: &mut Vec<u8>, max_len: usize) -> WebSocketResult<()> {
+        let start = self.inner.total_in();
+        loop {
+            let consumed = (self.inner.total_in() - start) as usize;
+            if consumed == input.len() && output.len() < output.capacity() {
+                return Ok(());
+            }
+            // always leave one byte of room so going past the limit is noticed
+            let room = (input.len() * 2 + 64).min(max_len.saturating_sub(output.len()).max(1));
+            output.reserve_exact(room);
+            let status = self.inner
+                .decompress_vec(&input[consumed..], output, FlushDecompress::Sync)
+                .map_err(|_| WebSocketError::ProtocolError("Invalid compressed payload"))?;
+            if output.len() > max_len {
+                return Err(WebSocketError::ProtocolError("Decompressed message exceeds size limit"));
+            }
+            if status == Status::StreamEnd {
+                return Ok(());
+            }
+        }
+    }
+}
+
+impl Decompressor for DeflateDecompressor {
+    fn decompress_chunk(&mut self, input: &[u8], output: &mut Vec<u8>, max_len: usize) -> WebSocketResult<()> {
+        self.inflate(input, output, max_len)
+    }
+
+    fn finish_message(&mut self, output: &mut Vec<u8>, max_len: usize) -> WebSocketResult<()> {
+        self.inflate(&DEFLATE_TRAILER, output, max_len)?;
+        if self.no_context_takeover {
+            self.inner.reset(false);
+        }
+        Ok(())
+    }
+
+    fn reset(&mut self) {
+        self.inner.reset(false);
+    }
+}
@@ -1,10 +1,13 @@
 //! Module containing the default implementation of data frames.
 use crate::error::{WebSocketError};
 use std::io::{self, Read, Write};
-use crate::protocol::dataframe::DataFrame as DataFrameAble;
-use crate::protocol::header::{DataFrameFlags, DataFrameHeader, FrameHeader, mask_data, Opcode};
+use crate::protocol::dataframe::{DataFrame as DataFrameAble, DataFrameRefTrait};
+use crate::protocol::header::{DataFrameFlags, DataFrameHeader, FrameHeader, MaskingImpl, Opcode};
 use crate::result::WebSocketResult;
 
+/// How much payload room is reserved before any of it has arrived.
+pub const MAX_PAYLOAD_PREALLOCATION: usize = 1024 * 1024;
+
 #[derive(Debug, Clone, PartialEq)]
 pub struct DataFrame {
     /// Whether or no this constitutes the end of a message
@@ -15,6 +18,9 @@ pub struct DataFrame {
     pub opcode: Opcode,
     /// The payload associated with this data frame
     pub data: Vec<u8>,
+    /// The masking key this frame carried on the wire, kept for logging;
+    /// `data` is already unmasked. Always `None` for frames built locally.
+    pub mask: Option<[u8; 4]>,
 }
 
 impl DataFrame {
@@ -25,6 +31,7 @@ impl DataFrame {
             reserved: [false; 3],
             opcode,
             data,
+            mask: None,
         }
     }
 
@@ -36,16 +43,24 @@ impl DataFrame {
         header: DataFrameHeader,
         body: Vec<u8>,
         should_be_masked: bool,
+    ) -> WebSocketResult<Self> {
+        DataFrame::read_dataframe_body_with_masking(header, body, should_be_masked, MaskingImpl::default())
+    }
+
+    /// Same as `read_dataframe_body`, unmasking the payload with the given implementation.
+    pub fn read_dataframe_body_with_masking(
+        header: DataFrameHeader,
+        mut body: Vec<u8>,
+        should_be_masked: bool,
+        masking: MaskingImpl,
     ) -> WebSocketResult<Self> {
         let finished = header.flags.contains(DataFrameFlags::FIN);
 
-        let reserved = [
-            header.flags.contains(DataFrameFlags::RSV1),
-            header.flags.contains(DataFrameFlags::RSV2),
-            header.flags.contains(DataFrameFlags::RSV3),
-        ];
+        let reserved = header.flags.reserved();
 
-        let opcode = Opcode::new(header.opcode).expect("Invalid header opcode!");
+        // a header read off the wire has 4 opcode bits, one built by hand
+        // may have more
+        let opcode = Opcode::new(header.opcode).ok_or(WebSocketError::DataFrameError("Invalid header opcode"))?;
 
         let data = match header.mask {
             Some(mask) => {
@@ -54,7 +69,8 @@ impl DataFrame {
                         "Expected unmasked data frame",
                     ));
                 }
-                mask_data(mask, &body)
+                masking.apply(mask, 0, &mut body);
+                body
             }
             None => {
                 if should_be_masked {
@@ -69,9 +85,42 @@ impl DataFrame {
             reserved,
             opcode,
             data,
+            mask: header.mask,
         })
     }
 
+    /// Appends exactly `len` payload bytes from `reader` to `data`.
+    ///
+    /// Room for up to [`MAX_PAYLOAD_PREALLOCATION`] bytes is reserved up
+    /// front, so frames that size read with a single allocation; beyond
+    /// that the buffer doubles only as the bytes actually arrive, so a
+    /// bogus length on a short stream can't allocate more than about twice
+    /// what was received.
+    pub fn read_payload_into<R>(reader: &mut R, len: u64, data: &mut Vec<u8>) -> WebSocketResult<()>
+        where
+            R: Read,
+    {
+        let len = usize::try_from(len)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "payload length does not fit in memory"))?;
+        let start = data.len();
+        let mut filled = 0;
+        let mut chunk = len.min(MAX_PAYLOAD_PREALLOCATION);
+        while filled < len {
+            data.resize(start + filled + chunk, 0);
+            if let Err(e) = reader.read_exact(&mut data[start + filled..]) {
+                data.truncate(start);
+                return Err(if e.kind() == io::ErrorKind::UnexpectedEof {
+                    io::Error::new(io::ErrorKind::UnexpectedEof, "incomplete payload").into()
+                } else {
+                    e.into()
+                });
+            }
+            filled += chunk;
+            chunk = filled.min(len - filled);
+        }
+        Ok(())
+    }
+
     /// Reads a DataFrame from a Reader.
     pub fn read_dataframe<R>(reader: &mut R, should_be_masked: bool) -> WebSocketResult<Self>
         where
@@ -79,36 +128,45 @@ impl DataFrame {
     {
         let header =DataFrameHeader::read(reader)?;
 
-        let mut data: Vec<u8> = Vec::with_capacity(header.len as usize);
-        let read = reader.take(header.len).read_to_end(&mut data)?;
-        if (read as u64) < header.len {
-            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "incomplete payload").into());
-        }
+        let mut data = Vec::new();
+        DataFrame::read_payload_into(reader, header.len, &mut data)?;
 
         DataFrame::read_dataframe_body(header, data, should_be_masked)
     }
 
-    /// Reads a DataFrame from a Reader, or error out if header declares exceeding limit you specify
+    /// Reads a DataFrame from a Reader, or error out if header declares exceeding limit you specify.
+    /// Control frames are exempt: the header parser already caps them at 125 bytes.
     pub fn read_dataframe_with_limit<R>(reader: &mut R, should_be_masked: bool, limit: usize) -> WebSocketResult<Self>
         where
             R: Read,
+    {
+        DataFrame::read_dataframe_with_limit_and_masking(reader, should_be_masked, limit, MaskingImpl::default())
+    }
+
+    /// Same as `read_dataframe_with_limit`, unmasking the payload with the given implementation.
+    pub fn read_dataframe_with_limit_and_masking<R>(
+        reader: &mut R,
+        should_be_masked: bool,
+        limit: usize,
+        masking: MaskingImpl,
+    ) -> WebSocketResult<Self>
+        where
+            R: Read,
     {
         let header = DataFrameHeader::read(reader)?;
 
-        if header.len > limit as u64 {
+        let is_control = header.opcode & 0x8 != 0;
+        if !is_control && header.len > limit as u64 {
             return Err(io::Error::new(io::ErrorKind::InvalidData, "exceeded DataFrame length limit").into());
         }
-        let mut data: Vec<u8> = Vec::with_capacity(header.len as usize);
-        let read = reader.take(header.len).read_to_end(&mut data)?;
-        if (read as u64) < header.len {
-            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "incomplete payload").into());
-        }
+        let mut data = Vec::new();
+        DataFrame::read_payload_into(reader, header.len, &mut data)?;
 
-        DataFrame::read_dataframe_body(header, data, should_be_masked)
+        DataFrame::read_dataframe_body_with_masking(header, data, should_be_masked, masking)
     }
 }
 
-impl DataFrameAble for DataFrame {
+impl DataFrameRefTrait for DataFrame {
     #[inline(always)]
     fn is_last(&self) -> bool {
         self.finished
@@ -130,15 +188,47 @@ impl DataFrameAble for DataFrame {
     }
 
     #[inline(always)]
-    fn write_payload(&self, socket: &mut impl Write) -> WebSocketResult<()> {
+    fn is_masked(&self) -> Option<bool> {
+        Some(self.mask.is_some())
+    }
+
+    #[inline(always)]
+    fn write_payload(&self, socket: &mut dyn Write) -> WebSocketResult<()> {
         socket.write_all(self.data.as_slice())?;
         Ok(())
     }
+}
 
+impl DataFrameAble for DataFrame {
     #[inline(always)]
     fn take_payload(self) -> Vec<u8> {
         self.data
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use crate::protocol::dataframe::DataFrameRefTrait;
+    use crate::protocol::header::Opcode;
+    use super::DataFrame;
+
+    fn frame_bytes(opcode: Opcode, len: usize) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        DataFrame::new(true, opcode, vec![7; len]).write_to(&mut bytes, false).unwrap();
+        bytes
+    }
 
+    #[test]
+    fn control_frames_are_exempt_from_the_limit() {
+        let bytes = frame_bytes(Opcode::Ping, 100);
+        let frame = DataFrame::read_dataframe_with_limit(&mut &bytes[..], false, 16).unwrap();
+        assert_eq!(frame.opcode, Opcode::Ping);
+        assert_eq!(frame.data.len(), 100);
+    }
+
+    #[test]
+    fn data_frames_over_the_limit_are_rejected() {
+        let bytes = frame_bytes(Opcode::Binary, 100);
+        assert!(DataFrame::read_dataframe_with_limit(&mut &bytes[..], false, 16).is_err());
+    }
+}
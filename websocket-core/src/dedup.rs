@@ -0,0 +1,129 @@
+//! Dropping messages that were already delivered, keyed on ids the
+//! application extracts from them.
+use std::collections::{HashSet, VecDeque};
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use crate::clock::{Clock, SystemClock};
+use crate::message::Message;
+
+pub type MessageId = [u8; 16];
+
+type IdExtractor = Box<dyn Fn(&Message) -> Option<MessageId> + Send>;
+
+/// Which ids a [`DedupFilter`] remembers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DedupWindow {
+    /// The last `n` ids.
+    LastN(usize),
+    /// Ids seen within `ttl`, at most `capacity` of them. Once full, the
+    /// oldest id is forgotten early.
+    Time { ttl: Duration, capacity: usize },
+}
+
+impl DedupWindow {
+    fn capacity(self) -> usize {
+        match self {
+            DedupWindow::LastN(n) => n,
+            DedupWindow::Time { capacity, .. } => capacity,
+        }
+    }
+}
+
+/// Drops messages whose id was already seen within a window. Messages the
+/// extractor gives no id for always pass.
+///
+/// Memory is allocated once, at construction.
+pub struct DedupFilter {
+    extractor: IdExtractor,
+    window: DedupWindow,
+    order: VecDeque<(MessageId, Instant)>,
+    seen: HashSet<MessageId>,
+    dropped: u64,
+    clock: Arc<dyn Clock>,
+}
+
+impl fmt::Debug for DedupFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DedupFilter")
+            .field("window", &self.window)
+            .field("remembered", &self.order.len())
+            .field("dropped", &self.dropped)
+            .finish()
+    }
+}
+
+impl DedupFilter {
+    pub fn new<F>(extractor: F, window: DedupWindow) -> DedupFilter
+        where
+            F: Fn(&Message) -> Option<MessageId> + Send + 'static,
+    {
+        DedupFilter::with_clock(extractor, window, Arc::new(SystemClock))
+    }
+
+    /// Same as `new`, measuring time windows on `clock`.
+    pub fn with_clock<F>(extractor: F, window: DedupWindow, clock: Arc<dyn Clock>) -> DedupFilter
+        where
+            F: Fn(&Message) -> Option<MessageId> + Send + 'static,
+    {
+        let capacity = window.capacity();
+        DedupFilter {
+            extractor: Box::new(extractor),
+            window,
+            order: VecDeque::with_capacity(capacity),
+            seen: HashSet::with_capacity(capacity),
+            dropped: 0,
+            clock,
+        }
+    }
+
+    /// Whether `message` should be delivered. Its id, if any, is remembered.
+    pub fn check(&mut self, message: &Message) -> bool {
+        let id = match (self.extractor)(message) {
+            Some(id) => id,
+            None => return true,
+        };
+        let now = self.clock.now();
+        if let DedupWindow::Time { ttl, .. } = self.window {
+            while let Some(&(oldest, at)) = self.order.front() {
+                if now.saturating_duration_since(at) < ttl {
+                    break;
+                }
+                self.order.pop_front();
+                self.seen.remove(&oldest);
+            }
+        }
+        if self.seen.contains(&id) {
+            self.dropped += 1;
+            return false;
+        }
+        if self.window.capacity() == 0 {
+            return true;
+        }
+        if self.order.len() == self.window.capacity() {
+            if let Some((oldest, _)) = self.order.pop_front() {
+                self.seen.remove(&oldest);
+            }
+        }
+        self.order.push_back((id, now));
+        self.seen.insert(id);
+        true
+    }
+
+    /// Forgets all remembered ids and the count of dropped messages.
+    pub fn clear(&mut self) {
+        self.order.clear();
+        self.seen.clear();
+        self.dropped = 0;
+    }
+
+    /// How many messages were dropped as duplicates.
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    /// How many ids are currently remembered.
+    pub fn remembered(&self) -> usize {
+        self.order.len()
+    }
+}
@@ -1,6 +1,7 @@
 use std::io;
 use std::str::Utf8Error;
 use thiserror::Error;
+use crate::close_code::CloseCode;
 
 #[derive(Error, Debug)]
 pub enum WebSocketError {
@@ -11,5 +12,100 @@ pub enum WebSocketError {
     #[error("IO error: {0}")]
     Io(#[from] io::Error),
     #[error("utf8 error: {0}")]
-    Utf8Error(#[from] Utf8Error)
+    Utf8Error(#[from] Utf8Error),
+    #[error("peer finished sending after its Close frame")]
+    PeerFinishedSending,
+    #[error("peer did not answer our Close within the drain limit")]
+    PeerDidNotClose,
+    #[error("global memory budget exhausted")]
+    GlobalMemoryPressure,
+    #[error("no matching pong arrived in time")]
+    PongTimeout,
+    #[error("peer sent a Close before answering our ping")]
+    ClosedBeforePong,
+    #[error("close handshake did not complete in time")]
+    CloseHandshakeTimedOut,
+    #[error("message exceeds the size limit of {limit} bytes")]
+    MessageTooLarge { limit: usize },
+    #[error("the stream is out of sync after an earlier send or receive stopped part way")]
+    Poisoned,
+    #[error("transport error ({kind:?}): {source}")]
+    Transport {
+        kind: TransportErrorKind,
+        source: io::Error,
+    },
+}
+
+/// Why writing to the transport failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransportErrorKind {
+    /// The peer reset or aborted the connection.
+    PeerReset,
+    /// The peer closed the connection or it is no longer connected.
+    PeerGone,
+    /// We shut the connection down ourselves earlier.
+    LocalShutdown,
+    /// The write timed out.
+    Timeout,
+    Other,
+}
+
+impl TransportErrorKind {
+    /// Classifies an IO error from a write.
+    pub fn classify(error: &io::Error) -> TransportErrorKind {
+        match error.kind() {
+            io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionAborted => TransportErrorKind::PeerReset,
+            io::ErrorKind::BrokenPipe | io::ErrorKind::NotConnected | io::ErrorKind::UnexpectedEof => TransportErrorKind::PeerGone,
+            // sockets with a write timeout report it as WouldBlock on unix
+            io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => TransportErrorKind::Timeout,
+            _ => TransportErrorKind::Other,
+        }
+    }
+}
+
+impl WebSocketError {
+    /// Whether the connection can't be used any more after this error.
+    pub fn is_fatal_for_connection(&self) -> bool {
+        match self {
+            // we may still send, the peer only stopped
+            WebSocketError::PeerFinishedSending | WebSocketError::ClosedBeforePong => false,
+            // slow, not necessarily gone
+            WebSocketError::PongTimeout => false,
+            WebSocketError::DataFrameError(_)
+            | WebSocketError::ProtocolError(_)
+            | WebSocketError::MessageTooLarge { .. }
+            | WebSocketError::Io(_)
+            | WebSocketError::Utf8Error(_)
+            | WebSocketError::PeerDidNotClose
+            | WebSocketError::CloseHandshakeTimedOut
+            | WebSocketError::GlobalMemoryPressure
+            | WebSocketError::Poisoned
+            | WebSocketError::Transport { .. } => true,
+        }
+    }
+
+    /// The status code to close the connection with because of this error,
+    /// see [`CloseCode::from_error`] for the stable mapping.
+    pub fn close_code(&self) -> CloseCode {
+        CloseCode::from_error(self)
+    }
+
+    /// Whether sending a Close is still worth trying, i.e. the transport
+    /// may still carry it.
+    pub fn should_attempt_close_handshake(&self) -> bool {
+        match self {
+            WebSocketError::DataFrameError(_)
+            | WebSocketError::ProtocolError(_)
+            | WebSocketError::MessageTooLarge { .. }
+            | WebSocketError::Utf8Error(_)
+            | WebSocketError::PeerFinishedSending
+            | WebSocketError::GlobalMemoryPressure
+            | WebSocketError::PongTimeout
+            | WebSocketError::ClosedBeforePong => true,
+            WebSocketError::Transport { kind, .. } => *kind == TransportErrorKind::Timeout,
+            // a Close would land in the middle of the broken frame
+            WebSocketError::Poisoned => false,
+            WebSocketError::Io(_) | WebSocketError::PeerDidNotClose | WebSocketError::CloseHandshakeTimedOut => false,
+        }
+    }
 }
\ No newline at end of file
@@ -7,4 +7,11 @@ pub mod dataframe;
 pub mod sec_header;
 pub mod message;
 pub mod utils;
-pub mod stream;
\ No newline at end of file
+pub mod stream;
+pub mod policy;
+pub mod budget;
+pub mod compression;
+pub mod clock;
+pub mod dedup;
+pub mod ping;
+pub mod close_catalog;pub mod close_code;
@@ -1,17 +1,30 @@
+use std::fmt;
 use std::io;
 use std::io::Write;
 use std::str::from_utf8;
+use crate::close_catalog::CloseCatalogEntry;
 use crate::codec::order_byte::{NetworkEndian, ReadBytesExt, WriteBytesExt};
 use crate::error::WebSocketError;
 use crate::protocol;
-use crate::protocol::dataframe::DataFrame;
-use crate::protocol::header::Opcode;
+use crate::protocol::dataframe::{DataFrame, DataFrameRefTrait};
+use crate::protocol::header::{MaskingImpl, Opcode};
 use crate::protocol::message::Type;
+use crate::protocol::role::Role;
 use crate::result::WebSocketResult;
 use crate::utils::bytes_to_string;
 
 const FALSE_RESERVED_BITS: &[bool; 3] = &[false; 3];
 
+/// Adds up the length of what is written to it.
+struct LenCounter(usize);
+
+impl fmt::Write for LenCounter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.0 += s.len();
+        Ok(())
+    }
+}
+
 #[derive(Eq, PartialEq, Clone, Debug)]
 pub struct Message {
     /// Type of WebSocket message
@@ -41,13 +54,36 @@ impl Message {
         )
     }
 
+    /// Builds a text message from `format_args!`, formatting it into a
+    /// payload allocated once at its exact length: the arguments are
+    /// formatted twice, first only to measure them. Panics like `format!`
+    /// if a formatting implementation fails.
+    pub fn text_fmt(args: fmt::Arguments) -> Self
+    {
+        if let Some(text) = args.as_str() {
+            return Message::text(text.to_string());
+        }
+        let mut len = LenCounter(0);
+        let _ = fmt::write(&mut len, args);
+        let mut text = String::with_capacity(len.0);
+        fmt::write(&mut text, args).expect("a formatting trait implementation returned an error");
+        Message::text(text)
+    }
+
+    /// Builds a text message from bytes that may not be valid UTF-8,
+    /// replacing invalid sequences with U+FFFD.
+    pub fn text_lossy(data: &[u8]) -> Self
+    {
+        Message::text(String::from_utf8_lossy(data).into_owned())
+    }
+
     pub fn binary(data: Vec<u8>) -> Self
     {
         Message::new(Type::Binary, None, data)
     }
 
     pub fn close() -> Self {
-        Message::new(Type::Close, None, vec![0])
+        Message::new(Type::Close, None, Vec::new())
     }
 
     pub fn close_because(code: u16, reason: String) -> Self
@@ -60,6 +96,22 @@ impl Message {
 		)
     }
 
+    /// A message with a reserved opcode, one of 3-7 for data or 11-15 for
+    /// control. `None` for any other opcode. Received messages never have
+    /// one: the receive path rejects reserved opcodes, as no extension
+    /// giving them a meaning can be negotiated.
+    pub fn reserved(opcode: u8, data: Vec<u8>) -> Option<Self> {
+        match opcode {
+            3..=7 | 11..=15 => Some(Message::new(Type::Other(opcode), None, data)),
+            _ => None,
+        }
+    }
+
+    /// A Close with a registered code and its canonical reason.
+    pub fn close_with(entry: &CloseCatalogEntry) -> Self {
+        Message::close_because(entry.code, entry.reason.clone())
+    }
+
     pub fn ping(data: Vec<u8>) -> Self
     {
         Message::new(Type::Ping, None, data)
@@ -70,80 +122,104 @@ impl Message {
         Message::new(Type::Pong, None, data)
     }
 
-    pub fn to_pong(&mut self) -> Result<(), ()> {
-        if self.opcode == Type::Ping {
-            self.opcode = Type::Pong;
-            Ok(())
-        } else {
-            Err(())
+    /// This message as a single frame with FIN as given, for fragmenting
+    /// a message by hand: a `Text` or `Binary` message not `finished`,
+    /// then `Continuation` ones, the last `finished`. Control frames can't
+    /// be fragmented.
+    pub fn to_dataframe(&self, finished: bool) -> WebSocketResult<crate::dataframe::DataFrame> {
+        if !finished && self.opcode.as_u8() & 0x8 != 0 {
+            return Err(WebSocketError::ProtocolError("Illegal fragmented control frame"));
         }
+        let opcode = Opcode::new(self.opcode.as_u8()).ok_or(WebSocketError::ProtocolError("Invalid opcode"))?;
+        Ok(crate::dataframe::DataFrame::new(finished, opcode, self.clone().take_payload()))
     }
-}
 
-impl DataFrame for Message {
-    #[inline(always)]
-    fn is_last(&self) -> bool {
-        true
-    }
-
-    #[inline(always)]
-    fn opcode(&self) -> u8 {
-        self.opcode as u8
-    }
-
-    #[inline(always)]
-    fn reserved(&self) -> &[bool; 3] {
-        FALSE_RESERVED_BITS
-    }
-
-    fn size(&self) -> usize {
-        self.payload.len() + if self.cd_status_code.is_some() { 2 } else { 0 }
-    }
-
-    fn write_payload(&self, socket: &mut impl Write) -> WebSocketResult<()> {
-        if let Some(reason) = self.cd_status_code {
-            socket.write_u16::<NetworkEndian>(reason)?;
+    /// Compares two messages by opcode and payload, except that close
+    /// messages only compare their status codes and ignore the reason.
+    pub fn same_content(&self, other: &Message) -> bool {
+        if self.opcode != other.opcode {
+            return false;
+        }
+        match self.opcode {
+            Type::Close => self.cd_status_code == other.cd_status_code,
+            _ => self.payload == other.payload,
         }
-        socket.write_all(&*self.payload)?;
-        Ok(())
     }
 
-    fn take_payload(self) -> Vec<u8> {
-        if let Some(reason) = self.cd_status_code {
-            let mut buf = Vec::with_capacity(2 + self.payload.len());
-            buf.write_u16::<NetworkEndian>(reason)
-                .expect("failed to write close code in take_payload");
-            buf.append(&mut self.payload.to_vec());
-            buf
-        } else {
-            self.payload.to_vec()
+    /// Parses the message at the start of `bytes`, as received by an
+    /// endpoint playing `role`, and returns it with the number of bytes it
+    /// took up. Frames get the same checks as on the receive path, except
+    /// that a control frame between the fragments of a message is rejected
+    /// since only one message can be returned.
+    pub fn from_bytes(bytes: &[u8], role: Role) -> WebSocketResult<(Message, usize)> {
+        let mut reader = bytes;
+        let mut frames = Vec::new();
+        loop {
+            // no frame can be longer than the buffer it comes from
+            let frame = crate::dataframe::DataFrame::read_dataframe_with_limit(
+                &mut reader,
+                role.expects_masked_incoming(),
+                bytes.len(),
+            )?;
+            if frames.is_empty() && frame.opcode == Opcode::Continuation {
+                return Err(WebSocketError::ProtocolError(
+                    "Unexpected continuation data frame opcode",
+                ));
+            }
+            if !frames.is_empty() && frame.opcode as u8 & 0x8 != 0 {
+                return Err(WebSocketError::ProtocolError(
+                    "Control frame between the fragments of a message",
+                ));
+            }
+            let finished = frame.finished;
+            frames.push(frame);
+            if finished {
+                break;
+            }
         }
+        let consumed = bytes.len() - reader.len();
+        Ok((Message::from_dataframes_validating(frames, true)?, consumed))
     }
-}
 
-impl protocol::message::Message for Message {
-    /// Attempt to form a message from a series of data frames
-    fn serialize(&self, writer: &mut impl Write, masked: bool) -> WebSocketResult<()> {
-        self.write_to(writer, masked)
-    }
+    /// Splits this message into frames carrying at most `max_frame_size`
+    /// bytes of payload each, the first one with `opcode` and the rest as
+    /// continuations, e.g. to forward it within an upstream's frame limit.
+    /// A control `opcode` can't be fragmented and always gives one frame.
+    pub fn refragment(&self, max_frame_size: usize, opcode: Opcode) -> Vec<crate::dataframe::DataFrame> {
+        let mut payload = Vec::with_capacity(self.size());
+        if let Some(code) = self.cd_status_code {
+            payload.extend_from_slice(&code.to_be_bytes());
+        }
+        payload.extend_from_slice(&self.payload);
 
-    /// Returns how many bytes this message will take up
-    fn message_size(&self, masked: bool) -> usize {
-        self.frame_size(masked)
+        if opcode as u8 & 0x8 != 0 || payload.len() <= max_frame_size {
+            return vec![crate::dataframe::DataFrame::new(true, opcode, payload)];
+        }
+        let chunks = payload.chunks(max_frame_size.max(1));
+        let count = chunks.len();
+        chunks
+            .enumerate()
+            .map(|(i, chunk)| {
+                let opcode = if i == 0 { opcode } else { Opcode::Continuation };
+                crate::dataframe::DataFrame::new(i + 1 == count, opcode, chunk.to_vec())
+            })
+            .collect()
     }
 
-    /// Attempt to form a message from a series of data frames
-    fn from_dataframes<D>(frames: Vec<D>) -> WebSocketResult<Self>
+    /// Same as `from_dataframes`, checking that text payloads are UTF-8
+    /// only if `validate_utf8` is set. Unchecked text messages may hold any
+    /// bytes; close reasons are always checked.
+    pub fn from_dataframes_validating<D>(frames: Vec<D>, validate_utf8: bool) -> WebSocketResult<Self>
         where
             D: DataFrame,
     {
         let opcode = frames
             .first()
             .ok_or(WebSocketError::ProtocolError("No dataframes provided"))
-            .map(DataFrame::opcode)?;
+            .map(DataFrameRefTrait::opcode)?;
         let opcode = Opcode::new(opcode);
 
-        let payload_size = frames.iter().map(DataFrame::size).sum();
+        let payload_size = frames.iter().map(DataFrameRefTrait::size).sum();
 
         let mut data = Vec::with_capacity(payload_size);
 
@@ -161,7 +237,7 @@ impl protocol::message::Message for Message {
             data.append(&mut dataframe.take_payload());
         }
 
-        if opcode == Some(Opcode::Text) {
+        if validate_utf8 && opcode == Some(Opcode::Text) {
             if let Err(e) = from_utf8(data.as_slice()) {
                 return Err(e.into());
             }
@@ -175,6 +251,12 @@ impl protocol::message::Message for Message {
             },
             Some(Opcode::Binary) => Message::binary(data),
             Some(Opcode::Close) => {
+                if data.len() == 1 {
+                    // a status code takes two bytes (RFC6455 5.5.1)
+                    return Err(WebSocketError::ProtocolError(
+                        "Close frame payload of one byte",
+                    ));
+                }
                 if !data.is_empty() {
                     let status_code = (&data[..]).read_u16::<NetworkEndian>()?;
                     let reason = bytes_to_string(&data[2..])?;
@@ -189,6 +271,98 @@ impl protocol::message::Message for Message {
         };
         Ok(msg)
     }
+
+    /// The Close to answer this Close with: it carries the same status code,
+    /// or none if this one had none, and no reason. `None` if this isn't a
+    /// Close.
+    pub fn close_reply(&self) -> Option<Message> {
+        match (self.opcode, self.cd_status_code) {
+            (Type::Close, Some(code)) => Some(Message::close_because(code, String::new())),
+            (Type::Close, None) => Some(Message::close()),
+            _ => None,
+        }
+    }
+
+    #[allow(clippy::result_unit_err)]
+    pub fn to_pong(&mut self) -> Result<(), ()> {
+        if self.opcode == Type::Ping {
+            self.opcode = Type::Pong;
+            Ok(())
+        } else {
+            Err(())
+        }
+    }
+}
+
+impl DataFrameRefTrait for Message {
+    #[inline(always)]
+    fn is_last(&self) -> bool {
+        true
+    }
+
+    #[inline(always)]
+    fn opcode(&self) -> u8 {
+        self.opcode.as_u8()
+    }
+
+    #[inline(always)]
+    fn reserved(&self) -> &[bool; 3] {
+        FALSE_RESERVED_BITS
+    }
+
+    fn size(&self) -> usize {
+        self.payload.len() + if self.cd_status_code.is_some() { 2 } else { 0 }
+    }
+
+    fn write_payload(&self, socket: &mut dyn Write) -> WebSocketResult<()> {
+        if let Some(reason) = self.cd_status_code {
+            socket.write_u16::<NetworkEndian>(reason)?;
+        }
+        socket.write_all(&self.payload)?;
+        Ok(())
+    }
+}
+
+impl DataFrame for Message {
+
+    fn take_payload(self) -> Vec<u8> {
+        if let Some(reason) = self.cd_status_code {
+            let mut buf = Vec::with_capacity(2 + self.payload.len());
+            buf.extend_from_slice(&reason.to_be_bytes());
+            buf.extend_from_slice(&self.payload);
+            buf
+        } else {
+            self.payload
+        }
+    }
+}
+
+impl protocol::message::Message for Message {
+    /// Attempt to form a message from a series of data frames
+    fn serialize(&self, writer: &mut impl Write, masked: bool) -> WebSocketResult<()> {
+        self.write_to(writer, masked)
+    }
+
+    fn serialize_with_masking(&self, writer: &mut impl Write, masked: bool, masking: MaskingImpl) -> WebSocketResult<()> {
+        self.write_to_with_masking(writer, masked, masking)
+    }
+
+    /// Returns how many bytes this message will take up
+    fn message_size(&self, masked: bool) -> usize {
+        self.frame_size(masked)
+    }
+
+    fn as_dataframe(&self) -> Option<&dyn DataFrameRefTrait> {
+        Some(self)
+    }
+
+    /// Attempt to form a message from a series of data frames
+    fn from_dataframes<D>(frames: Vec<D>) -> WebSocketResult<Self>
+        where
+            D: DataFrame,
+    {
+        Message::from_dataframes_validating(frames, true)
+    }
 }
 
 /// Represents data contained in a Close message
@@ -218,3 +392,24 @@ impl CloseData {
         Ok(buf)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn close_reply_echoes_the_code_only() {
+        let coded = Message::close_because(4001, "going to lunch".to_string());
+        let reply = coded.close_reply().unwrap();
+        assert_eq!(reply, Message::close_because(4001, String::new()));
+        assert_eq!(reply.take_payload(), vec![0x0f, 0xa1]);
+
+        let bare = Message::close().close_reply().unwrap();
+        assert_eq!(bare, Message::close());
+        assert_eq!(bare.cd_status_code, None);
+        assert!(bare.take_payload().is_empty());
+
+        assert_eq!(Message::ping(b"p".to_vec()).close_reply(), None);
+        assert_eq!(Message::text("close".to_string()).close_reply(), None);
+    }
+}
@@ -0,0 +1,88 @@
+//! Ping payloads that let the pong prove it came back unmodified.
+use std::fmt::Debug;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+use crate::codec::order_byte::{NetworkEndian, WriteBytesExt};
+use crate::utils::crc32;
+
+/// The largest payload a control frame can carry.
+pub const MAX_PING_PAYLOAD: usize = 125;
+
+/// How a pong compares to a ping we sent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PongVerdict {
+    /// The pong answers this ping, unmodified.
+    Match,
+    /// The pong answers this ping but its payload was changed on the way.
+    Mismatch { detail: String },
+    /// The pong doesn't answer this ping.
+    Unknown,
+}
+
+/// Builds ping payloads and checks the pongs that come back.
+pub trait PingPayloadCodec: Debug + Send + Sync {
+    /// The payload of the `seq`th ping, sent at `now`. At most
+    /// [`MAX_PING_PAYLOAD`] bytes; longer payloads are cut.
+    fn encode(&self, seq: u64, now: Instant) -> Vec<u8>;
+
+    fn verify(&self, sent: &[u8], received: &[u8]) -> PongVerdict;
+}
+
+/// Payloads of the sequence number, the send time and a CRC-32 over both
+/// and a secret, so a middlebox can't forge a consistent payload without
+/// knowing the secret.
+///
+/// The secret can be rotated at any time; pings already sent are checked
+/// against what was sent, not recomputed.
+#[derive(Debug)]
+pub struct CrcTimestampCodec {
+    secret: AtomicU64,
+    started: Instant,
+}
+
+impl CrcTimestampCodec {
+    pub fn new(secret: u64) -> CrcTimestampCodec {
+        CrcTimestampCodec {
+            secret: AtomicU64::new(secret),
+            started: Instant::now(),
+        }
+    }
+
+    pub fn rotate_secret(&self, secret: u64) {
+        self.secret.store(secret, Ordering::Relaxed);
+    }
+}
+
+impl PingPayloadCodec for CrcTimestampCodec {
+    fn encode(&self, seq: u64, now: Instant) -> Vec<u8> {
+        let micros = now.saturating_duration_since(self.started).as_micros() as u64;
+        let mut payload = Vec::with_capacity(20);
+        // writing to a Vec can't fail
+        let _ = payload.write_u64::<NetworkEndian>(seq);
+        let _ = payload.write_u64::<NetworkEndian>(micros);
+        let mut signed = payload.clone();
+        let _ = signed.write_u64::<NetworkEndian>(self.secret.load(Ordering::Relaxed));
+        let _ = payload.write_u32::<NetworkEndian>(crc32(&signed));
+        payload
+    }
+
+    fn verify(&self, sent: &[u8], received: &[u8]) -> PongVerdict {
+        if sent == received {
+            return PongVerdict::Match;
+        }
+        if !received.is_empty() && sent.starts_with(received) {
+            return PongVerdict::Mismatch { detail: format!("truncated to {} of {} bytes", received.len(), sent.len()) };
+        }
+        // the sequence number says which ping it answers
+        if received.len() >= 8 && sent.len() >= 8 && received[..8] == sent[..8] {
+            let detail = if received.len() != sent.len() {
+                format!("{} bytes instead of {}", received.len(), sent.len())
+            } else {
+                let changed = sent.iter().zip(received).filter(|(a, b)| a != b).count();
+                format!("{changed} bytes changed")
+            };
+            return PongVerdict::Mismatch { detail };
+        }
+        PongVerdict::Unknown
+    }
+}
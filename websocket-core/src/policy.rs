@@ -0,0 +1,476 @@
+//! Connection-level reactions to incoming traffic.
+//!
+//! [`ConnectionPolicy`] decides what an endpoint should do about each incoming
+//! message or error (answer pings, echo closes, enforce keepalive, map
+//! violations to close codes) without doing any IO itself. Front-ends execute
+//! the returned [`PolicyAction`]s through their own streams, so they all
+//! behave the same way.
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use crate::close_code::CloseCode;
+use crate::error::WebSocketError;
+use crate::message::Message;
+use crate::ping::{PingPayloadCodec, PongVerdict, MAX_PING_PAYLOAD};
+use crate::protocol::message::Type;
+
+/// How many sent keepalive pings are remembered for checking pongs. Pongs
+/// for older ones are unknown.
+const MAX_OUTSTANDING_PINGS: usize = 4;
+
+/// Something that happened on a connection.
+#[derive(Debug)]
+pub enum PolicyEvent {
+    /// A message (data or control) was received.
+    Message(Message),
+    /// Receiving failed.
+    Error(WebSocketError),
+    /// We sent a Close of our own.
+    CloseSent,
+    /// Nothing was received; lets the policy check its deadlines.
+    Tick,
+}
+
+/// What the front-end should do in response to an event.
+#[derive(Debug, PartialEq, Eq)]
+pub enum PolicyAction {
+    /// Hand the message to the application.
+    Deliver(Message),
+    /// Answer a ping with this payload.
+    SendPong(Vec<u8>),
+    /// Send a ping with this payload.
+    SendPing(Vec<u8>),
+    /// Send a Close frame, with or without a status code.
+    SendClose(Option<u16>, String),
+    /// Shut the connection down once this much time has passed.
+    ShutdownAfter(Duration),
+    /// Nothing to do.
+    Ignore,
+}
+
+#[derive(Debug, Clone)]
+pub struct ConnectionPolicy {
+    /// Answer pings with a pong instead of delivering them.
+    pub auto_pong: bool,
+    /// Echo a received Close when we haven't sent one yet.
+    pub echo_close: bool,
+    /// Send a ping after this long without incoming traffic.
+    pub keepalive_interval: Option<Duration>,
+    /// Close the connection after this long without incoming traffic.
+    pub keepalive_timeout: Option<Duration>,
+    /// Close the connection with 1001 after this long without data
+    /// messages or pings from the peer. Pongs don't count, so a peer that
+    /// only answers keepalive pings is still idle.
+    pub idle_timeout: Option<Duration>,
+    /// Fail the connection on protocol violations. When false, the
+    /// offending message is dropped and the connection kept.
+    pub strict: bool,
+    /// How long to wait for the peer to finish the close handshake.
+    pub close_timeout: Duration,
+    /// Builds keepalive ping payloads and checks the pongs; keepalive pings
+    /// are empty without one.
+    pub ping_codec: Option<Arc<dyn PingPayloadCodec>>,
+    /// Close the connection with 1008 after this many pongs in a row came
+    /// back modified.
+    pub max_pong_mismatches: Option<u32>,
+    last_activity: Option<Instant>,
+    /// When the peer last sent something other than a pong or a Close.
+    last_peer_activity: Option<Instant>,
+    last_ping: Option<Instant>,
+    close_sent: bool,
+    /// When a Close was first sent or received.
+    close_started: Option<Instant>,
+    pings_sent: u64,
+    /// Payloads of the last keepalive pings, oldest first.
+    outstanding_pings: VecDeque<Vec<u8>>,
+    pong_mismatches: u64,
+    consecutive_pong_mismatches: u32,
+}
+
+impl Default for ConnectionPolicy {
+    fn default() -> Self {
+        ConnectionPolicy {
+            auto_pong: true,
+            echo_close: true,
+            keepalive_interval: None,
+            keepalive_timeout: None,
+            idle_timeout: None,
+            strict: true,
+            close_timeout: Duration::from_secs(10),
+            ping_codec: None,
+            max_pong_mismatches: None,
+            last_activity: None,
+            last_peer_activity: None,
+            last_ping: None,
+            close_sent: false,
+            close_started: None,
+            pings_sent: 0,
+            outstanding_pings: VecDeque::new(),
+            pong_mismatches: 0,
+            consecutive_pong_mismatches: 0,
+        }
+    }
+}
+
+impl ConnectionPolicy {
+    /// Decides how to react to `event`, which happened at `now`.
+    pub fn react(&mut self, event: PolicyEvent, now: Instant) -> Vec<PolicyAction> {
+        let actions = match event {
+            PolicyEvent::Message(message) => {
+                self.last_activity = Some(now);
+                match message.opcode {
+                    Type::Close => {
+                        self.close_started.get_or_insert(now);
+                    }
+                    Type::Pong => {}
+                    _ => self.last_peer_activity = Some(now),
+                }
+                self.on_message(message)
+            }
+            PolicyEvent::Error(error) => self.on_error(error),
+            PolicyEvent::CloseSent => {
+                self.close_sent = true;
+                vec![PolicyAction::ShutdownAfter(self.close_timeout)]
+            }
+            PolicyEvent::Tick => self.on_tick(now),
+        };
+        if self.close_sent {
+            self.close_started.get_or_insert(now);
+        }
+        actions
+    }
+
+    /// Whether a Close has been sent on this connection.
+    pub fn close_sent(&self) -> bool {
+        self.close_sent
+    }
+
+    /// How many pongs answered a keepalive ping with a modified payload.
+    pub fn pong_mismatches(&self) -> u64 {
+        self.pong_mismatches
+    }
+
+    /// When the close handshake must be complete: `close_timeout` after a
+    /// Close was first sent or received. Front-ends fail the connection
+    /// with `WebSocketError::CloseHandshakeTimedOut` past it.
+    pub fn close_deadline(&self) -> Option<Instant> {
+        self.close_started.map(|at| at + self.close_timeout)
+    }
+
+    fn on_message(&mut self, message: Message) -> Vec<PolicyAction> {
+        match message.opcode {
+            Type::Ping if self.auto_pong => vec![PolicyAction::SendPong(message.payload)],
+            Type::Pong => self.on_pong(message),
+            Type::Close => {
+                if self.close_sent {
+                    // the peer answered our Close, the handshake is complete
+                    return vec![PolicyAction::Deliver(message), PolicyAction::ShutdownAfter(Duration::ZERO)];
+                }
+                if !self.echo_close {
+                    return vec![PolicyAction::Deliver(message)];
+                }
+                self.close_sent = true;
+                let code = message.cd_status_code;
+                vec![
+                    PolicyAction::Deliver(message),
+                    PolicyAction::SendClose(code, String::new()),
+                    PolicyAction::ShutdownAfter(Duration::ZERO),
+                ]
+            }
+            _ => vec![PolicyAction::Deliver(message)],
+        }
+    }
+
+    fn on_pong(&mut self, message: Message) -> Vec<PolicyAction> {
+        let codec = match self.ping_codec {
+            Some(ref codec) => codec.clone(),
+            None => return vec![PolicyAction::Deliver(message)],
+        };
+        let mut mismatch = false;
+        for (i, sent) in self.outstanding_pings.iter().enumerate() {
+            match codec.verify(sent, &message.payload) {
+                PongVerdict::Match => {
+                    // it also answers for the older ones
+                    self.outstanding_pings.drain(..=i);
+                    self.consecutive_pong_mismatches = 0;
+                    return vec![PolicyAction::Deliver(message)];
+                }
+                PongVerdict::Mismatch { .. } => mismatch = true,
+                PongVerdict::Unknown => {}
+            }
+        }
+        if !mismatch {
+            // unsolicited, or for a ping we forgot
+            return vec![PolicyAction::Deliver(message)];
+        }
+        self.pong_mismatches += 1;
+        self.consecutive_pong_mismatches += 1;
+        let limit_reached = self
+            .max_pong_mismatches
+            .is_some_and(|max| self.consecutive_pong_mismatches >= max);
+        if !limit_reached || self.close_sent {
+            return vec![PolicyAction::Deliver(message)];
+        }
+        self.close_sent = true;
+        vec![
+            PolicyAction::Deliver(message),
+            // Policy Violation
+            PolicyAction::SendClose(Some(1008), "pong payload modified".to_string()),
+            PolicyAction::ShutdownAfter(self.close_timeout),
+        ]
+    }
+
+    fn ping_payload(&mut self, now: Instant) -> Vec<u8> {
+        let codec = match self.ping_codec {
+            Some(ref codec) => codec,
+            None => return Vec::new(),
+        };
+        self.pings_sent += 1;
+        let mut payload = codec.encode(self.pings_sent, now);
+        payload.truncate(MAX_PING_PAYLOAD);
+        if self.outstanding_pings.len() == MAX_OUTSTANDING_PINGS {
+            self.outstanding_pings.pop_front();
+        }
+        self.outstanding_pings.push_back(payload.clone());
+        payload
+    }
+
+    fn on_error(&mut self, error: WebSocketError) -> Vec<PolicyAction> {
+        let violation = match error {
+            // the transport is gone, there is nobody to send a Close to
+            WebSocketError::Io(_)
+            | WebSocketError::Transport { .. }
+            | WebSocketError::PeerDidNotClose
+            | WebSocketError::Poisoned
+            | WebSocketError::CloseHandshakeTimedOut => return vec![PolicyAction::ShutdownAfter(Duration::ZERO)],
+            // a half-close after the peer's Close; our side may still finish
+            WebSocketError::PeerFinishedSending => return vec![PolicyAction::Ignore],
+            // the peer's Close was already reported
+            WebSocketError::ClosedBeforePong => return vec![PolicyAction::Ignore],
+            WebSocketError::Utf8Error(_)
+            | WebSocketError::DataFrameError(_)
+            | WebSocketError::ProtocolError(_)
+            | WebSocketError::MessageTooLarge { .. } => true,
+            WebSocketError::GlobalMemoryPressure | WebSocketError::PongTimeout => false,
+        };
+        let code = CloseCode::from_error(&error).into();
+        if violation && !self.strict {
+            return vec![PolicyAction::Ignore];
+        }
+        if self.close_sent {
+            return vec![PolicyAction::ShutdownAfter(Duration::ZERO)];
+        }
+        self.close_sent = true;
+        vec![
+            PolicyAction::SendClose(Some(code), error.to_string()),
+            PolicyAction::ShutdownAfter(self.close_timeout),
+        ]
+    }
+
+    fn on_tick(&mut self, now: Instant) -> Vec<PolicyAction> {
+        let last_activity = *self.last_activity.get_or_insert(now);
+        let idle = now.saturating_duration_since(last_activity);
+
+        if let Some(timeout) = self.keepalive_timeout {
+            if idle >= timeout {
+                return self.going_away("keepalive timeout");
+            }
+        }
+
+        if let Some(timeout) = self.idle_timeout {
+            let last_peer_activity = *self.last_peer_activity.get_or_insert(now);
+            if now.saturating_duration_since(last_peer_activity) >= timeout {
+                return self.going_away("idle timeout");
+            }
+        }
+
+        if let Some(interval) = self.keepalive_interval {
+            let since_ping = self.last_ping.map_or(idle, |at| now.saturating_duration_since(at));
+            if !self.close_sent && idle >= interval && since_ping >= interval {
+                self.last_ping = Some(now);
+                return vec![PolicyAction::SendPing(self.ping_payload(now))];
+            }
+        }
+
+        vec![PolicyAction::Ignore]
+    }
+
+    /// Starts the close handshake with 1001 (Going Away), or shuts down if
+    /// it was started already.
+    fn going_away(&mut self, reason: &str) -> Vec<PolicyAction> {
+        if self.close_sent {
+            return vec![PolicyAction::ShutdownAfter(Duration::ZERO)];
+        }
+        self.close_sent = true;
+        vec![
+            PolicyAction::SendClose(Some(1001), reason.to_string()),
+            PolicyAction::ShutdownAfter(self.close_timeout),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use super::*;
+    use crate::error::TransportErrorKind;
+
+    /// Which timer of the policy, if any, has run out when the event comes.
+    #[derive(Debug, Clone, Copy)]
+    enum Timer {
+        Off,
+        KeepaliveInterval,
+        KeepaliveTimeout,
+        IdleTimeout,
+    }
+
+    #[derive(Debug, Clone, Copy)]
+    struct Config {
+        auto_pong: bool,
+        echo_close: bool,
+        strict: bool,
+        close_sent: bool,
+        timer: Timer,
+    }
+
+    fn configs() -> Vec<Config> {
+        let mut configs = Vec::new();
+        for bits in 0..16 {
+            for timer in [Timer::Off, Timer::KeepaliveInterval, Timer::KeepaliveTimeout, Timer::IdleTimeout] {
+                configs.push(Config {
+                    auto_pong: bits & 1 != 0,
+                    echo_close: bits & 2 != 0,
+                    strict: bits & 4 != 0,
+                    close_sent: bits & 8 != 0,
+                    timer,
+                });
+            }
+        }
+        configs
+    }
+
+    /// Every kind of event, including every error.
+    fn events() -> Vec<fn() -> PolicyEvent> {
+        vec![
+            || PolicyEvent::Message(Message::text("hi".to_string())),
+            || PolicyEvent::Message(Message::binary(vec![1, 2])),
+            || PolicyEvent::Message(Message::ping(b"p".to_vec())),
+            || PolicyEvent::Message(Message::pong(b"p".to_vec())),
+            || PolicyEvent::Message(Message::close()),
+            || PolicyEvent::Message(Message::close_because(1000, "bye".to_string())),
+            || PolicyEvent::CloseSent,
+            || PolicyEvent::Tick,
+            || PolicyEvent::Error(WebSocketError::DataFrameError("bad frame")),
+            || PolicyEvent::Error(WebSocketError::ProtocolError("bad protocol")),
+            || PolicyEvent::Error(WebSocketError::Io(io::ErrorKind::UnexpectedEof.into())),
+            || PolicyEvent::Error(WebSocketError::Utf8Error(String::from_utf8(vec![0xff]).unwrap_err().utf8_error())),
+            || PolicyEvent::Error(WebSocketError::PeerFinishedSending),
+            || PolicyEvent::Error(WebSocketError::PeerDidNotClose),
+            || PolicyEvent::Error(WebSocketError::GlobalMemoryPressure),
+            || PolicyEvent::Error(WebSocketError::PongTimeout),
+            || PolicyEvent::Error(WebSocketError::ClosedBeforePong),
+            || PolicyEvent::Error(WebSocketError::CloseHandshakeTimedOut),
+            || PolicyEvent::Error(WebSocketError::MessageTooLarge { limit: 16 }),
+            || PolicyEvent::Error(WebSocketError::Poisoned),
+            || PolicyEvent::Error(WebSocketError::Transport {
+                kind: TransportErrorKind::PeerReset,
+                source: io::ErrorKind::ConnectionReset.into(),
+            }),
+        ]
+    }
+
+    const CLOSE_TIMEOUT: Duration = Duration::from_secs(10);
+
+    /// A policy in `config`, with its timer run out at the returned time.
+    fn policy(config: Config, start: Instant) -> (ConnectionPolicy, Instant) {
+        let second = Some(Duration::from_secs(1));
+        let mut policy = ConnectionPolicy {
+            auto_pong: config.auto_pong,
+            echo_close: config.echo_close,
+            strict: config.strict,
+            close_timeout: CLOSE_TIMEOUT,
+            ..ConnectionPolicy::default()
+        };
+        match config.timer {
+            Timer::Off => {}
+            Timer::KeepaliveInterval => policy.keepalive_interval = second,
+            Timer::KeepaliveTimeout => policy.keepalive_timeout = second,
+            Timer::IdleTimeout => policy.idle_timeout = second,
+        }
+        assert_eq!(policy.react(PolicyEvent::Tick, start), vec![PolicyAction::Ignore]);
+        if config.close_sent {
+            policy.react(PolicyEvent::CloseSent, start);
+        }
+        (policy, start + Duration::from_secs(5))
+    }
+
+    /// What the policy should do, and whether a Close was sent afterwards.
+    fn expected(config: Config, event: PolicyEvent) -> (Vec<PolicyAction>, bool) {
+        let close = |code: Option<u16>, reason: String, wait: Duration| {
+            if config.close_sent {
+                (vec![PolicyAction::ShutdownAfter(Duration::ZERO)], true)
+            } else {
+                (vec![PolicyAction::SendClose(code, reason), PolicyAction::ShutdownAfter(wait)], true)
+            }
+        };
+        let unchanged = |actions| (actions, config.close_sent);
+        match event {
+            PolicyEvent::Message(message) => match message.opcode {
+                Type::Ping if config.auto_pong => unchanged(vec![PolicyAction::SendPong(message.payload)]),
+                Type::Close if config.close_sent => {
+                    unchanged(vec![PolicyAction::Deliver(message), PolicyAction::ShutdownAfter(Duration::ZERO)])
+                }
+                Type::Close if config.echo_close => {
+                    let code = message.cd_status_code;
+                    let (mut actions, close_sent) = close(code, String::new(), Duration::ZERO);
+                    actions.insert(0, PolicyAction::Deliver(message));
+                    (actions, close_sent)
+                }
+                _ => unchanged(vec![PolicyAction::Deliver(message)]),
+            },
+            PolicyEvent::CloseSent => (vec![PolicyAction::ShutdownAfter(CLOSE_TIMEOUT)], true),
+            PolicyEvent::Tick => match config.timer {
+                Timer::Off => unchanged(vec![PolicyAction::Ignore]),
+                Timer::KeepaliveInterval if config.close_sent => unchanged(vec![PolicyAction::Ignore]),
+                Timer::KeepaliveInterval => unchanged(vec![PolicyAction::SendPing(Vec::new())]),
+                Timer::KeepaliveTimeout => close(Some(1001), "keepalive timeout".to_string(), CLOSE_TIMEOUT),
+                Timer::IdleTimeout => close(Some(1001), "idle timeout".to_string(), CLOSE_TIMEOUT),
+            },
+            PolicyEvent::Error(error) => match error {
+                WebSocketError::Io(_)
+                | WebSocketError::Transport { .. }
+                | WebSocketError::PeerDidNotClose
+                | WebSocketError::Poisoned
+                | WebSocketError::CloseHandshakeTimedOut => unchanged(vec![PolicyAction::ShutdownAfter(Duration::ZERO)]),
+                WebSocketError::PeerFinishedSending | WebSocketError::ClosedBeforePong => {
+                    unchanged(vec![PolicyAction::Ignore])
+                }
+                WebSocketError::Utf8Error(_)
+                | WebSocketError::DataFrameError(_)
+                | WebSocketError::ProtocolError(_)
+                | WebSocketError::MessageTooLarge { .. }
+                    if !config.strict =>
+                {
+                    unchanged(vec![PolicyAction::Ignore])
+                }
+                error => close(Some(error.close_code().into()), error.to_string(), CLOSE_TIMEOUT),
+            },
+        }
+    }
+
+    #[test]
+    fn react_to_every_event_in_every_config() {
+        let start = Instant::now();
+        for config in configs() {
+            for event in events() {
+                let (mut policy, now) = policy(config, start);
+                let described = format!("{:?} in {:?}", event(), config);
+                let actions = policy.react(event(), now);
+                let (expected, close_sent) = expected(config, event());
+                assert_eq!(actions, expected, "{}", described);
+                assert_eq!(policy.close_sent(), close_sent, "{}", described);
+                assert!(policy.close_deadline().is_some() || !close_sent, "{}", described);
+            }
+        }
+    }
+}
@@ -1,5 +1,6 @@
 use std::io::Write;
-use crate::protocol::header::{DataFrameFlags, DataFrameHeader, DataMasker, FrameHeader, gen_mask};
+use crate::protocol::header::{DataFrameFlags, DataFrameHeader, DataMasker, FrameHeader, gen_mask, MaskingImpl};
+use crate::error::WebSocketError;
 use crate::result::WebSocketResult;
 
 // 0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1
@@ -21,7 +22,7 @@ use crate::result::WebSocketResult;
 // +---------------------------------------------------------------+
 // Mask: 1 bit
 // ​	mask标志位，定义“有效负载数据”是否添加掩码。如果设置为1，那么掩码的键值存在于Masking-Key中，根据5.3节描述，这个一般用于解码“有效负载数据”。所有的从客户端发送到服务端的帧都需要设置这个bit位为1。
-/// Masking-Key: 0 or 4 bytes
+// Masking-Key: 0 or 4 bytes
 // ​	所有从客户端发往服务端的数据帧都已经与一个包含在这一帧中的32 bit的掩码进行过了运算。如果mask标志位（1 bit）为1，那么这个字段存在，如果标志位为0，那么这个字段不存在。在5.3节中会介绍更多关于客户端到服务端增加掩码的信息。
 // Payload data: (x+y) bytes
 // ​	“有效负载数据”是指“扩展数据”和“应用数据”。
@@ -31,7 +32,12 @@ use crate::result::WebSocketResult;
 // ​	任意的“应用数据”，占用“扩展数据”后面的剩余所有字段。“应用数据”的长度等于有效负载长度减去“扩展应用”长度。
 // 基础数据帧协议通过ABNF进行了正式的定义。需要重点知道的是，这些数据都是二进制的，而不是ASCII字符。例如，长度为1 bit的字段的值为%x0 / %x1代表的是一个值为0/1的单独的bit，而不是一整个字节（8 bit）来代表ASCII编码的字符“0”和“1”。一个长度为4 bit的范围是%x0-F的字段值代表的是4个bit，而不是字节（8 bit）对应的ASCII码的值。不要指定字符编码：“规则解析为一组最终的值，有时候是字符。在ABNF中，字符仅仅是一个非负的数字。在特定的上下文中，会根据特定的值的映射（编码）编码集（例如ASCII）”。在这里，指定的编码类型是将每个字段编码为特定的bits数组的二进制编码的最终数据。
 
-pub trait DataFrame {
+/// The object-safe part of [`DataFrame`].
+///
+/// Everything needed to put a frame on the wire lives here, so frames of
+/// different types can be queued as `Box<dyn DataFrameRefTrait>` and sent
+/// through the same write path.
+pub trait DataFrameRefTrait {
     /// FIN: 1 bit 表示这是消息的最后一个片段。第一个片段也有可能是最后一个片段。
     fn is_last(&self) -> bool;
 
@@ -49,6 +55,12 @@ pub trait DataFrame {
     /// RSV1，RSV2，RSV3: 每个1 bit 必须设置为0，除非扩展了非0值含义的扩展。如果收到了一个非0值但是没有扩展任何非0值的含义，接收终端必须断开WebSocket连接。
     fn reserved(&self) -> &[bool; 3];
 
+    /// Whether the frame was masked when it was received, or `None` if
+    /// that isn't known.
+    fn is_masked(&self) -> Option<bool> {
+        None
+    }
+
     /// Payload length: 7 bits, 7+16 bits, or 7+64 bits
     /// 以字节为单位的“有效负载数据”长度，如果值为0-125，那么就表示负载数据的长度。
     /// 如果是126，那么接下来的2个bytes解释为16bit的无符号整形作为负载数据的长度。
@@ -77,50 +89,154 @@ pub trait DataFrame {
     }
 
     /// Write the payload to a writer
-    fn write_payload(&self, socket: &mut impl Write) -> WebSocketResult<()>;
+    fn write_payload(&self, socket: &mut dyn Write) -> WebSocketResult<()>;
 
-    /// 获得传输数据
-    fn take_payload(self) -> Vec<u8>;
-
-    /// Writes a DataFrame to a Writer.
-    fn write_to(&self, writer: &mut impl Write, mask: bool) -> WebSocketResult<()> {
-        let mut flags = DataFrameFlags::empty();
+    /// The header this frame is written with, given the masking key.
+    fn header(&self, mask: Option<[u8; 4]>) -> DataFrameHeader {
+        let mut flags = DataFrameFlags::from_reserved(*self.reserved());
         if self.is_last() {
             flags.insert(DataFrameFlags::FIN);
         }
 
-        let reserved = self.reserved();
-        if reserved[0] {
-            flags.insert(DataFrameFlags::RSV1);
-        }
-        if reserved[1] {
-            flags.insert(DataFrameFlags::RSV2);
-        }
-        if reserved[2] {
-            flags.insert(DataFrameFlags::RSV3);
+        DataFrameHeader {
+            flags,
+            opcode: self.opcode(),
+            mask,
+            len: self.size() as u64,
         }
+    }
 
+    /// The 2 to 14 header bytes this frame is written with, given the masking key.
+    fn header_bytes(&self, mask: Option<[u8; 4]>) -> WebSocketResult<Vec<u8>> {
+        let mut bytes = Vec::with_capacity(14);
+        self.header(mask).write(&mut bytes)?;
+        Ok(bytes)
+    }
 
-        let masking_key = if mask { Some(gen_mask()) } else { None };
+    /// Writes a DataFrame to a Writer.
+    fn write_to(&self, writer: &mut dyn Write, mask: bool) -> WebSocketResult<()> {
+        self.write_to_with_masking(writer, mask, MaskingImpl::default())
+    }
 
-        let header = DataFrameHeader {
-            flags,
-            opcode: self.opcode() as u8,
-            mask: masking_key,
-            len: self.size() as u64,
-        };
+    /// Writes a DataFrame to a Writer, masking the payload with the given implementation.
+    ///
+    /// The whole frame is built before anything is written, and its payload
+    /// checked against `size()`, so only the final write can fail once
+    /// `writer` was touched.
+    fn write_to_with_masking(&self, writer: &mut dyn Write, mask: bool, masking: MaskingImpl) -> WebSocketResult<()> {
+        let masking_key = if mask { Some(gen_mask()) } else { None };
+        let header = self.header(masking_key);
 
-        let mut data = Vec::<u8>::new();
+        let mut data = Vec::<u8>::with_capacity(self.frame_size(mask));
         header.write(&mut data)?;
+        let header_len = data.len();
 
         match masking_key {
             Some(mask) => {
-                let mut masker = DataMasker::new(mask,&mut data);
+                let mut masker = DataMasker::with_masking(mask, masking, &mut data);
                 self.write_payload(&mut masker)?
             }
             None => self.write_payload(&mut data)?,
         };
+        if data.len() - header_len != self.size() {
+            return Err(WebSocketError::DataFrameError("Payload written differs from the frame size"));
+        }
         writer.write_all(data.as_slice())?;
         Ok(())
     }
-}
\ No newline at end of file
+
+    /// Like [`DataFrameRefTrait::write_to_with_masking`], but writes the
+    /// header straight to `writer` and masks the payload into it in small
+    /// chunks, instead of building the whole frame in a buffer first. The
+    /// frame takes several writes, so `writer` should buffer if it matters
+    /// that a frame is written at once.
+    ///
+    /// The payload is checked against `size()` as it goes out, so when they
+    /// differ `writer` was already handed part of a broken frame by the time
+    /// this fails.
+    fn write_to_streaming(&self, mut writer: &mut dyn Write, mask: bool, masking: MaskingImpl) -> WebSocketResult<()> {
+        let masking_key = if mask { Some(gen_mask()) } else { None };
+        self.header(masking_key).write(&mut writer)?;
+        let mut payload = PayloadWriter {
+            inner: writer,
+            left: self.size(),
+            overrun: false,
+        };
+        let result = match masking_key {
+            Some(mask) => {
+                let mut masker = DataMasker::with_masking(mask, masking, &mut payload);
+                self.write_payload(&mut masker)
+            }
+            None => self.write_payload(&mut payload),
+        };
+        if payload.overrun || (result.is_ok() && payload.left != 0) {
+            return Err(WebSocketError::DataFrameError("Payload written differs from the frame size"));
+        }
+        result
+    }
+}
+
+/// Passes at most `left` payload bytes on to `inner`, refusing any beyond
+/// the length the frame header announced.
+struct PayloadWriter<'a> {
+    inner: &'a mut dyn Write,
+    left: usize,
+    /// Set once more bytes were offered than the header announced.
+    overrun: bool,
+}
+
+impl Write for PayloadWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if buf.len() > self.left {
+            self.overrun = true;
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "payload longer than the frame size",
+            ));
+        }
+        let written = self.inner.write(buf)?;
+        self.left -= written;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+pub trait DataFrame: DataFrameRefTrait {
+    /// 获得传输数据
+    fn take_payload(self) -> Vec<u8>;
+}
+
+impl<T> DataFrameRefTrait for Box<T> where T: DataFrameRefTrait + ?Sized {
+    #[inline(always)]
+    fn is_last(&self) -> bool {
+        (**self).is_last()
+    }
+
+    #[inline(always)]
+    fn opcode(&self) -> u8 {
+        (**self).opcode()
+    }
+
+    #[inline(always)]
+    fn reserved(&self) -> &[bool; 3] {
+        (**self).reserved()
+    }
+
+    #[inline(always)]
+    fn is_masked(&self) -> Option<bool> {
+        (**self).is_masked()
+    }
+
+    #[inline(always)]
+    fn size(&self) -> usize {
+        (**self).size()
+    }
+
+    #[inline(always)]
+    fn write_payload(&self, socket: &mut dyn Write) -> WebSocketResult<()> {
+        (**self).write_payload(socket)
+    }
+}
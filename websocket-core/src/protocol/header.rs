@@ -20,6 +20,26 @@ bitflags! {
 	}
 }
 
+impl DataFrameFlags {
+    /// The RSV1, RSV2 and RSV3 bits, in that order.
+    pub fn reserved(&self) -> [bool; 3] {
+        [
+            self.contains(DataFrameFlags::RSV1),
+            self.contains(DataFrameFlags::RSV2),
+            self.contains(DataFrameFlags::RSV3),
+        ]
+    }
+
+    /// Flags with the given RSV1, RSV2 and RSV3 bits and FIN unset.
+    pub fn from_reserved(reserved: [bool; 3]) -> DataFrameFlags {
+        let mut flags = DataFrameFlags::empty();
+        flags.set(DataFrameFlags::RSV1, reserved[0]);
+        flags.set(DataFrameFlags::RSV2, reserved[1]);
+        flags.set(DataFrameFlags::RSV3, reserved[2]);
+        flags
+    }
+}
+
 pub trait FrameHeader: Sized {
     fn read(reader: &mut impl Read) -> WebSocketResult<Self>;
     fn write(self, writer: &mut impl Write) -> WebSocketResult<()>;
@@ -36,6 +56,29 @@ pub struct DataFrameHeader {
     pub len: u64,
 }
 
+impl DataFrameHeader {
+    /// Parses a header from the start of `buf` without blocking for more
+    /// input. Returns the header and the number of bytes it took up, or
+    /// `None` if `buf` doesn't hold a complete header yet.
+    pub fn parse(buf: &[u8]) -> WebSocketResult<Option<(DataFrameHeader, usize)>> {
+        if buf.len() < 2 {
+            return Ok(None);
+        }
+        let extended_len = match buf[1] & 0x7F {
+            126 => 2,
+            127 => 8,
+            _ => 0,
+        };
+        let mask_len = if buf[1] & 0x80 == 0x80 { 4 } else { 0 };
+        let header_len = 2 + extended_len + mask_len;
+        if buf.len() < header_len {
+            return Ok(None);
+        }
+        let header = DataFrameHeader::read(&mut &buf[..header_len])?;
+        Ok(Some((header, header_len)))
+    }
+}
+
 impl FrameHeader for DataFrameHeader {
     fn read(reader: &mut impl Read) -> WebSocketResult<Self> {
         let byte0 = reader.read_u8()?;
@@ -69,9 +112,11 @@ impl FrameHeader for DataFrameHeader {
                     "Control frame length too long",
                 ));
             }
+            // a control frame can't be fragmented, so without FIN it would
+            // start a fragmented message
             if !flags.contains(DataFrameFlags::FIN) {
                 return Err(WebSocketError::ProtocolError(
-                    "Illegal fragmented control frame",
+                    "Control frame cannot start a fragmented message",
                 ));
             }
         }
@@ -131,30 +176,43 @@ impl FrameHeader for DataFrameHeader {
     }
 }
 
+/// How many payload bytes a [`DataMasker`] masks per write.
+const MASK_CHUNK: usize = 4096;
+
 pub struct DataMasker<'w, T> where T: 'w + Write {
     key: [u8; 4],
     pos: usize,
+    masking: MaskingImpl,
     endpoint: &'w mut T,
 }
 
 impl<'w, T> DataMasker<'w, T> where T: 'w + Write {
     pub fn new(key: [u8; 4], endpoint: &'w mut T) -> Self {
+        DataMasker::with_masking(key, MaskingImpl::default(), endpoint)
+    }
+
+    /// Creates a masker that uses the given masking implementation.
+    pub fn with_masking(key: [u8; 4], masking: MaskingImpl, endpoint: &'w mut T) -> Self {
         Self {
             key,
             pos: 0,
+            masking,
             endpoint,
         }
     }
 }
 
 impl<'w, T> Write for DataMasker<'w, T> where T: 'w + Write {
+    /// Masks and writes at most `MASK_CHUNK` bytes of `buf` at a time, so
+    /// large payloads need no buffer of their size.
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
-        let mut data = Vec::with_capacity(buf.len());
-        for &byte in buf.iter() {
-            data.push(byte ^ self.key[self.pos]);
-            self.pos = (self.pos + 1) % self.key.len();
-        }
-        self.endpoint.write(&data)
+        let mut chunk = [0u8; MASK_CHUNK];
+        let len = buf.len().min(MASK_CHUNK);
+        chunk[..len].copy_from_slice(&buf[..len]);
+        self.masking.apply(self.key, self.pos, &mut chunk[..len]);
+        let written = self.endpoint.write(&chunk[..len])?;
+        self.pos = (self.pos + written) % self.key.len();
+        Ok(written)
     }
 
     fn flush(&mut self) -> std::io::Result<()> {
@@ -162,6 +220,51 @@ impl<'w, T> Write for DataMasker<'w, T> where T: 'w + Write {
     }
 }
 
+/// The implementation used to apply a masking key to a payload.
+///
+/// Both produce the same bytes; they can be switched at runtime to compare
+/// their cost on a given connection.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MaskingImpl {
+    /// XOR one byte at a time.
+    Scalar,
+    /// XOR eight bytes at a time, falling back to bytes for the tail.
+    #[default]
+    Word,
+}
+
+impl MaskingImpl {
+    /// Masks (or unmasks) `data` in place, as if it started at byte `pos`
+    /// of the payload.
+    pub fn apply(self, key: [u8; 4], pos: usize, data: &mut [u8]) {
+        match self {
+            MaskingImpl::Scalar => {
+                for (i, byte) in data.iter_mut().enumerate() {
+                    *byte ^= key[(pos + i) % 4];
+                }
+            }
+            MaskingImpl::Word => {
+                let mut rotated = [0u8; 8];
+                for (i, byte) in rotated.iter_mut().enumerate() {
+                    *byte = key[(pos + i) % 4];
+                }
+                let word = u64::from_ne_bytes(rotated);
+
+                let mut chunks = data.chunks_exact_mut(8);
+                for chunk in &mut chunks {
+                    let mut bytes = [0u8; 8];
+                    bytes.copy_from_slice(chunk);
+                    chunk.copy_from_slice(&(u64::from_ne_bytes(bytes) ^ word).to_ne_bytes());
+                }
+                // chunks of 8 keep the key phase, so the tail starts at `pos` again
+                for (i, byte) in chunks.into_remainder().iter_mut().enumerate() {
+                    *byte ^= key[(pos + i) % 4];
+                }
+            }
+        }
+    }
+}
+
 pub fn gen_mask() -> [u8; 4] {
     rand::random()
 }
@@ -238,4 +341,20 @@ impl Opcode {
             _ => return None,
         })
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DataFrameFlags;
+
+    #[test]
+    fn reserved_bits_round_trip() {
+        for bits in 0u8..8 {
+            let reserved = [bits & 4 != 0, bits & 2 != 0, bits & 1 != 0];
+            let flags = DataFrameFlags::from_reserved(reserved);
+            assert_eq!(flags.reserved(), reserved);
+            assert_eq!(DataFrameFlags::from_reserved(flags.reserved()), flags);
+            assert!(!flags.contains(DataFrameFlags::FIN));
+        }
+    }
+}
@@ -1,29 +1,94 @@
 use std::io::Write;
-use crate::protocol::dataframe::DataFrame;
+use crate::protocol::dataframe::{DataFrame, DataFrameRefTrait};
+use crate::protocol::header::MaskingImpl;
+use crate::error::WebSocketError;
 use crate::result::WebSocketResult;
 
 /// Valid types of messages (in the default implementation)
 #[derive(Debug, Eq, PartialEq, Clone, Copy)]
 pub enum Type {
+    /// A continuation of a fragmented message, only valid for the frames
+    /// after the first, see `Message::to_dataframe`. Never a message on
+    /// its own: received messages don't have it and senders refuse to send
+    /// it as a message.
+    Continuation,
     /// Message with UTF8 test
-    Text = 1,
+    Text,
     /// Message containing binary data
-    Binary = 2,
+    Binary,
     /// Ping message with data
-    Ping = 9,
+    Ping,
     /// Pong message with data
-    Pong = 10,
+    Pong,
     /// Close connection message with optional reason
-    Close = 8,
+    Close,
+    /// A message with a reserved opcode (3-7 or 11-15), given a meaning by
+    /// an extension, e.g. for a proxy passing it through untouched.
+    Other(u8),
+}
+
+impl Type {
+    /// The opcode a message of this type is sent with.
+    pub fn as_u8(self) -> u8 {
+        match self {
+            Type::Continuation => 0,
+            Type::Text => 1,
+            Type::Binary => 2,
+            Type::Close => 8,
+            Type::Ping => 9,
+            Type::Pong => 10,
+            Type::Other(opcode) => opcode,
+        }
+    }
 }
 
 pub trait Message: Sized {
     /// Writes this message to the writer
     fn serialize(&self, _: &mut impl Write, masked: bool) -> WebSocketResult<()>;
 
+    /// Writes this message to the writer, masking it with the given implementation.
+    ///
+    /// Implementations that don't care about the masking implementation can
+    /// rely on the default, which ignores it.
+    fn serialize_with_masking(&self, writer: &mut impl Write, masked: bool, _masking: MaskingImpl) -> WebSocketResult<()> {
+        self.serialize(writer, masked)
+    }
+
     /// Returns how many bytes this message will take up
     fn message_size(&self, masked: bool) -> usize;
 
+    /// Serializes this message into a freshly allocated buffer of exactly
+    /// `message_size(masked)` bytes.
+    fn to_vec(&self, masked: bool) -> WebSocketResult<Vec<u8>> {
+        let mut buf = Vec::with_capacity(self.message_size(masked));
+        self.serialize(&mut buf, masked)?;
+        Ok(buf)
+    }
+
+    /// Views this message as a single frame, which lets senders compress
+    /// its payload. Messages that can't be viewed this way are always sent
+    /// uncompressed.
+    fn as_dataframe(&self) -> Option<&dyn DataFrameRefTrait> {
+        None
+    }
+
     /// Attempt to form a message from a series of data frames
     fn from_dataframes<D: DataFrame>(frames: Vec<D>) -> WebSocketResult<Self>;
+
+    /// Same as `from_dataframes`, first checking that every frame was
+    /// received masked if `expect_masked` is set, or unmasked otherwise.
+    /// Frames that don't know whether they were masked fail the check.
+    ///
+    /// For frames parsed outside a `Receiver`, e.g. by a server assembling
+    /// client frames itself.
+    fn from_dataframes_expecting_mask<D: DataFrame>(frames: Vec<D>, expect_masked: bool) -> WebSocketResult<Self> {
+        if frames.iter().any(|frame| frame.is_masked() != Some(expect_masked)) {
+            return Err(WebSocketError::ProtocolError(if expect_masked {
+                "Expected masked data frame"
+            } else {
+                "Expected unmasked data frame"
+            }));
+        }
+        Self::from_dataframes(frames)
+    }
 }
\ No newline at end of file
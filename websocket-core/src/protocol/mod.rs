@@ -1,3 +1,4 @@
 pub mod dataframe;
 pub mod header;
-pub mod message;
\ No newline at end of file
+pub mod message;
+pub mod role;
\ No newline at end of file
@@ -0,0 +1,49 @@
+/// Which end of a connection we are. Decides the masking rules of RFC6455 5.3:
+/// every frame sent by a client is masked, no frame sent by a server is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Client,
+    Server,
+}
+
+impl Role {
+    /// Whether frames we send must be masked.
+    pub fn masks_outgoing(self) -> bool {
+        self == Role::Client
+    }
+
+    /// Whether frames we receive must be masked.
+    pub fn expects_masked_incoming(self) -> bool {
+        self == Role::Server
+    }
+}
+
+mod sealed {
+    pub trait Sealed {}
+}
+
+/// A [`Role`] known at compile time, for halves of a connection whose types
+/// record which end they belong to. Implemented by [`ServerRole`] and
+/// [`ClientRole`] only.
+pub trait RoleMarker: sealed::Sealed {
+    const ROLE: Role;
+}
+
+/// Marks the server end of a connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ServerRole;
+
+/// Marks the client end of a connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientRole;
+
+impl sealed::Sealed for ServerRole {}
+impl sealed::Sealed for ClientRole {}
+
+impl RoleMarker for ServerRole {
+    const ROLE: Role = Role::Server;
+}
+
+impl RoleMarker for ClientRole {
+    const ROLE: Role = Role::Client;
+}
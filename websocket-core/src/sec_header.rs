@@ -12,6 +12,7 @@ pub mod names {
     pub const ACCEPT: &str = "Sec-WebSocket-Accept";
     pub const EXTENSIONS: &str = "Sec-WebSocket-Extensions";
     pub const KEY: &str = "Sec-WebSocket-Key";
+    pub const VERSION: &str = "Sec-WebSocket-Version";
 }
 #[derive(PartialEq, Clone, Copy, Default)]
 pub struct WebSocketKey([u8; 16]);
@@ -20,7 +21,7 @@ impl FromStr for WebSocketKey {
     type Err = WebSocketError;
 
     fn from_str(key: &str) -> WebSocketResult<WebSocketKey> {
-        match general_purpose::URL_SAFE_NO_PAD.decode(key) {
+        match general_purpose::STANDARD.decode(key) {
             Ok(vec) => {
                 if vec.len() != 16 {
                     return Err(WebSocketError::ProtocolError(
@@ -47,7 +48,7 @@ impl WebSocketKey {
     /// Return the Base64 encoding of this WebSocketKey
     pub fn serialize(&self) -> String {
         let WebSocketKey(key) = *self;
-        general_purpose::URL_SAFE_NO_PAD.encode(&key)
+        general_purpose::STANDARD.encode(key)
     }
 }
 
@@ -67,7 +68,7 @@ impl FromStr for WebSocketAccept {
     type Err = WebSocketError;
 
     fn from_str(accept: &str) -> WebSocketResult<WebSocketAccept> {
-        match general_purpose::URL_SAFE_NO_PAD.decode(accept) {
+        match general_purpose::STANDARD.decode(accept) {
             Ok(vec) => {
                 if vec.len() != 20 {
                     return Err(WebSocketError::ProtocolError(
@@ -98,6 +99,29 @@ impl WebSocketAccept {
     /// Return the Base64 encoding of this WebSocketAccept
     pub fn serialize(&self) -> String {
         let WebSocketAccept(accept) = *self;
-        general_purpose::URL_SAFE_NO_PAD.encode(&accept)
+        general_purpose::STANDARD.encode(accept)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{WebSocketAccept, WebSocketKey};
+
+    /// The example key of RFC6455 section 1.3.
+    const RFC_EXAMPLE_KEY: &str = "dGhlIHNhbXBsZSBub25jZQ==";
+    /// The accept RFC6455 section 1.3 gives for `RFC_EXAMPLE_KEY`.
+    const RFC_EXAMPLE_ACCEPT: &str = "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=";
+
+    #[test]
+    fn rfc_example_key_gives_rfc_example_accept() {
+        let key: WebSocketKey = RFC_EXAMPLE_KEY.parse().unwrap();
+        assert_eq!(key.serialize(), RFC_EXAMPLE_KEY);
+
+        let accept = WebSocketAccept::new(&key);
+        assert_eq!(accept.serialize(), RFC_EXAMPLE_ACCEPT);
+
+        let parsed: WebSocketAccept = RFC_EXAMPLE_ACCEPT.parse().unwrap();
+        assert_eq!(parsed, accept);
+        assert_eq!(parsed.serialize(), RFC_EXAMPLE_ACCEPT);
     }
 }
@@ -67,6 +67,80 @@ impl AsTcpStream for TcpStream {
 	}
 }
 
+/// A stream that reads `prefix` before reading from the stream itself, for
+/// handing over bytes a handshake parser read past the end of the head.
+/// Writes go straight to the stream.
+pub struct PrefixedStream<S> {
+	prefix: Vec<u8>,
+	pos: usize,
+	inner: S,
+}
+
+impl<S> PrefixedStream<S> {
+	pub fn new(prefix: Vec<u8>, inner: S) -> PrefixedStream<S> {
+		PrefixedStream { prefix, pos: 0, inner }
+	}
+
+	/// The prefix bytes not read yet.
+	pub fn remaining_prefix(&self) -> &[u8] {
+		&self.prefix[self.pos..]
+	}
+
+	pub fn get_ref(&self) -> &S {
+		&self.inner
+	}
+
+	pub fn get_mut(&mut self) -> &mut S {
+		&mut self.inner
+	}
+
+	/// Returns the stream. Prefix bytes not read yet are lost.
+	pub fn into_inner(self) -> S {
+		self.inner
+	}
+}
+
+impl<S> Read for PrefixedStream<S>
+where
+	S: Read,
+{
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		if self.pos < self.prefix.len() {
+			let read = (&self.prefix[self.pos..]).read(buf)?;
+			self.pos += read;
+			if self.pos == self.prefix.len() {
+				self.prefix = Vec::new();
+				self.pos = 0;
+			}
+			return Ok(read);
+		}
+		self.inner.read(buf)
+	}
+}
+
+impl<S> Write for PrefixedStream<S>
+where
+	S: Write,
+{
+	#[inline(always)]
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		self.inner.write(buf)
+	}
+	#[inline(always)]
+	fn flush(&mut self) -> io::Result<()> {
+		self.inner.flush()
+	}
+}
+
+impl<S> AsTcpStream for PrefixedStream<S>
+where
+	S: AsTcpStream,
+{
+	fn as_tcp(&self) -> &TcpStream {
+		self.inner.as_tcp()
+	}
+}
+
 // /// A collection of traits and implementations for async streams.
 // #[cfg(feature = "async")]
 // pub mod r#async {
@@ -4,3 +4,16 @@ pub fn bytes_to_string(data: &[u8]) -> Result<String, Utf8Error> {
     let utf8 = from_utf8(data)?;
     Ok(utf8.to_string())
 }
+
+/// CRC-32 (IEEE 802.3) of `data`.
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
@@ -0,0 +1,178 @@
+//! Adapters between WebSocket messages and byte-oriented `std::io` traits.
+use std::io::{self, Read, Write};
+use websocket_core::error::WebSocketError;
+use websocket_core::message::Message;
+use websocket_core::protocol::message::Type;
+use crate::receiver::Reader;
+use crate::sender::Writer;
+
+fn into_io_error(error: WebSocketError) -> io::Error {
+    match error {
+        WebSocketError::Io(e) | WebSocketError::Transport { source: e, .. } => e,
+        e => io::Error::new(io::ErrorKind::InvalidData, e),
+    }
+}
+
+/// Reads the payloads of consecutive binary messages as one byte stream.
+///
+/// Pings and pongs are skipped, a text message is an `InvalidData` error and
+/// the peer's Close ends the stream.
+pub struct MessageRead<R>
+    where
+        R: Read,
+{
+    reader: Reader<R>,
+    buffer: Vec<u8>,
+    pos: usize,
+    close: Option<Message>,
+}
+
+impl<R> MessageRead<R>
+    where
+        R: Read,
+{
+    pub fn new(reader: Reader<R>) -> MessageRead<R> {
+        MessageRead {
+            reader,
+            buffer: Vec::new(),
+            pos: 0,
+            close: None,
+        }
+    }
+
+    /// The Close that ended the stream, once it has been read.
+    pub fn close(&self) -> Option<&Message> {
+        self.close.as_ref()
+    }
+
+    pub fn get_mut(&mut self) -> &mut Reader<R> {
+        &mut self.reader
+    }
+
+    /// Returns the underlying reader. Bytes of the current message that
+    /// haven't been read yet are lost.
+    pub fn into_inner(self) -> Reader<R> {
+        self.reader
+    }
+
+    /// Loads the next binary message into the buffer. Returns false at the
+    /// end of the stream.
+    fn fill(&mut self) -> io::Result<bool> {
+        while self.close.is_none() {
+            let message = self.reader.recv_message().map_err(into_io_error)?;
+            match message.opcode {
+                Type::Binary => {
+                    // empty messages would read as the end of the stream
+                    if !message.payload.is_empty() {
+                        self.buffer = message.payload;
+                        self.pos = 0;
+                        return Ok(true);
+                    }
+                }
+                Type::Text => {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "text message in a binary stream"));
+                }
+                Type::Continuation | Type::Other(_) => {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "reserved opcode in a binary stream"));
+                }
+                Type::Ping | Type::Pong => {}
+                Type::Close => self.close = Some(message),
+            }
+        }
+        Ok(false)
+    }
+}
+
+impl<R> Read for MessageRead<R>
+    where
+        R: Read,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.pos >= self.buffer.len() && !self.fill()? {
+            return Ok(0);
+        }
+        let read = (&self.buffer[self.pos..]).read(buf)?;
+        self.pos += read;
+        Ok(read)
+    }
+}
+
+/// When a [`MessageWrite`] ends a message.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MessageBoundary {
+    /// Every `write` call sends its bytes as one message.
+    #[default]
+    PerWrite,
+    /// Bytes are buffered and sent as one message on `flush`.
+    PerFlush,
+}
+
+/// Sends bytes written to it as binary messages.
+pub struct MessageWrite<W>
+    where
+        W: Write,
+{
+    writer: Writer<W>,
+    boundary: MessageBoundary,
+    buffer: Vec<u8>,
+}
+
+impl<W> MessageWrite<W>
+    where
+        W: Write,
+{
+    pub fn new(writer: Writer<W>, boundary: MessageBoundary) -> MessageWrite<W> {
+        MessageWrite {
+            writer,
+            boundary,
+            buffer: Vec::new(),
+        }
+    }
+
+    pub fn boundary(&self) -> MessageBoundary {
+        self.boundary
+    }
+
+    pub fn get_mut(&mut self) -> &mut Writer<W> {
+        &mut self.writer
+    }
+
+    /// Sends any buffered bytes and returns the underlying writer.
+    pub fn into_inner(mut self) -> io::Result<Writer<W>> {
+        self.send_buffered()?;
+        Ok(self.writer)
+    }
+
+    fn send_buffered(&mut self) -> io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        let message = Message::binary(std::mem::take(&mut self.buffer));
+        self.writer.send_message(&message).map_err(into_io_error)
+    }
+}
+
+impl<W> Write for MessageWrite<W>
+    where
+        W: Write,
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.boundary {
+            MessageBoundary::PerWrite => {
+                if !buf.is_empty() {
+                    self.writer.send_message(&Message::binary(buf.to_vec())).map_err(into_io_error)?;
+                }
+            }
+            MessageBoundary::PerFlush => self.buffer.extend_from_slice(buf),
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.send_buffered()?;
+        self.writer.flush().map_err(into_io_error)
+    }
+}
@@ -0,0 +1,353 @@
+use std::io::{self, Read, Write};
+use websocket_core::action::receiver::Receiver as ReceiverAble;
+use websocket_core::close_catalog::CloseCatalog;
+use websocket_core::error::WebSocketError;
+use websocket_core::message::Message;
+use websocket_core::protocol::header::{DataFrameHeader, Opcode};
+use websocket_core::protocol::message::Message as MessageAble;
+use websocket_core::stream::AsTcpStream;
+use crate::receiver::Reader;
+use crate::sender::Writer;
+use crate::WebSocketResult;
+
+/// How much the close helper reads and discards while waiting for the
+/// peer's Close before giving up on it.
+#[derive(Debug, Clone, Copy)]
+pub struct DrainLimit {
+    pub max_frames: usize,
+    pub max_bytes: usize,
+}
+
+impl Default for DrainLimit {
+    fn default() -> Self {
+        DrainLimit {
+            max_frames: 64,
+            max_bytes: 1024 * 1024,
+        }
+    }
+}
+
+/// Completes a close handshake started by the peer: answers `peer_close`
+/// with [`Message::close_reply`], so a bare Close gets a bare Close back,
+/// and shuts down our write side.
+pub fn echo_close<W>(writer: &mut Writer<W>, peer_close: &Message) -> WebSocketResult<()>
+    where
+        W: AsTcpStream + Write,
+{
+    let reply = peer_close
+        .close_reply()
+        .ok_or(WebSocketError::ProtocolError("Only a Close message can be echoed"))?;
+    writer.send_close_and_shutdown(&reply)
+}
+
+/// Which side started the close handshake.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseInitiator {
+    Local,
+    Remote,
+    /// Both sides sent a Close before seeing the other one.
+    Both,
+}
+
+/// How a finished close handshake went.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CloseSummary {
+    /// The status code of our Close.
+    pub sent_code: Option<u16>,
+    /// The status code of the peer's Close.
+    pub received_code: Option<u16>,
+    /// The peer's Close.
+    pub received: Message,
+    pub initiated_by: CloseInitiator,
+    /// The two Close frames carry different status codes. Allowed by
+    /// RFC6455, but when one of them is an echo usually a bug on the side
+    /// that echoed.
+    pub mismatch: bool,
+    /// Control frames the peer sent after its Close, which were dropped,
+    /// see `Receiver::set_post_close_tolerance`.
+    pub post_close_frames: u8,
+}
+
+/// What the close helper does when the peer echoes a different code.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MismatchPolicy {
+    /// Treat it as a clean close; `CloseSummary::mismatch` is still set.
+    #[default]
+    Ignore,
+    /// Return it as [`CloseOutcome::Mismatch`].
+    Report,
+}
+
+/// The result of a close handshake that completed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CloseOutcome {
+    Clean(CloseSummary),
+    /// The peer echoed a different status code, reported as asked by
+    /// [`MismatchPolicy::Report`]. The connection is closed all the same.
+    Mismatch(CloseSummary),
+}
+
+impl CloseSummary {
+    /// The registered name of the peer's status code.
+    pub fn catalog_name<'a>(&self, catalog: &'a CloseCatalog) -> Option<&'a str> {
+        catalog.resolve(&self.received).map(|entry| entry.name.as_str())
+    }
+}
+
+impl CloseOutcome {
+    pub fn summary(&self) -> &CloseSummary {
+        match self {
+            CloseOutcome::Clean(summary) | CloseOutcome::Mismatch(summary) => summary,
+        }
+    }
+
+    pub fn into_summary(self) -> CloseSummary {
+        match self {
+            CloseOutcome::Clean(summary) | CloseOutcome::Mismatch(summary) => summary,
+        }
+    }
+}
+
+/// Runs the close handshake: sends `close`, then discards incoming frames
+/// until the peer's Close arrives and returns it.
+///
+/// If the peer keeps sending past `limit` instead, the TCP connection is
+/// shut down and `WebSocketError::PeerDidNotClose` returned.
+pub fn close_gracefully<R, W>(
+    reader: &mut Reader<R>,
+    writer: &mut Writer<W>,
+    close: &Message,
+    limit: DrainLimit,
+) -> WebSocketResult<Message>
+    where
+        R: Read,
+        W: AsTcpStream + Write,
+{
+    close_handshake(reader, writer, close, limit, MismatchPolicy::Ignore)
+        .map(|outcome| outcome.into_summary().received)
+}
+
+/// Same as [`close_gracefully`], describing how the handshake went.
+///
+/// If the peer's Close was already received before ours was sent, the peer
+/// initiated and ours is the reply. If it had arrived but was still unread
+/// in the reader's buffer, both sides sent a Close before seeing the other
+/// one. Either way the handshake is complete once ours is out. A peer that
+/// closes the connection without sending a Close gives
+/// `WebSocketError::PeerDidNotClose`.
+pub fn close_handshake<R, W>(
+    reader: &mut Reader<R>,
+    writer: &mut Writer<W>,
+    close: &Message,
+    limit: DrainLimit,
+    on_mismatch: MismatchPolicy,
+) -> WebSocketResult<CloseOutcome>
+    where
+        R: Read,
+        W: AsTcpStream + Write,
+{
+    let already_received = reader.receiver.received_close().cloned();
+    let crossed = already_received.is_none() && close_buffered(reader.stream.buffer());
+    writer.send_message(close)?;
+    writer.flush()?;
+
+    let (received, initiated_by) = match already_received {
+        Some(frame) => (Message::from_dataframes(vec![frame])?, CloseInitiator::Remote),
+        None if crossed => (drain_until_close(reader, writer, limit)?, CloseInitiator::Both),
+        None => (drain_until_close(reader, writer, limit)?, CloseInitiator::Local),
+    };
+    let sent_code = close.cd_status_code;
+    let received_code = received.cd_status_code;
+    let mismatch = sent_code != received_code;
+    let summary = CloseSummary {
+        sent_code,
+        received_code,
+        received,
+        initiated_by,
+        mismatch,
+        post_close_frames: reader.receiver.post_close_frames().min(u8::MAX as usize) as u8,
+    };
+    if mismatch && on_mismatch == MismatchPolicy::Report {
+        Ok(CloseOutcome::Mismatch(summary))
+    } else {
+        Ok(CloseOutcome::Clean(summary))
+    }
+}
+
+/// Whether `buffered`, bytes read from the peer but not yet received, hold
+/// the header of a Close frame.
+fn close_buffered(mut buffered: &[u8]) -> bool {
+    while let Ok(Some((header, header_len))) = DataFrameHeader::parse(buffered) {
+        if header.opcode == Opcode::Close as u8 {
+            return true;
+        }
+        match usize::try_from(header.len).ok().and_then(|len| buffered.get(header_len + len..)) {
+            Some(rest) => buffered = rest,
+            None => return false,
+        }
+    }
+    false
+}
+
+fn drain_until_close<R, W>(
+    reader: &mut Reader<R>,
+    writer: &mut Writer<W>,
+    limit: DrainLimit,
+) -> WebSocketResult<Message>
+    where
+        R: Read,
+        W: AsTcpStream + Write,
+{
+    let mut frames = 0;
+    let mut bytes = 0;
+    loop {
+        let frame = match reader.receiver.recv_dataframe(&mut reader.stream) {
+            Ok(frame) => frame,
+            Err(WebSocketError::Io(ref e)) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                return Err(WebSocketError::PeerDidNotClose);
+            }
+            Err(e) => return Err(e),
+        };
+        if frame.opcode == Opcode::Close {
+            return Message::from_dataframes(vec![frame]);
+        }
+
+        frames += 1;
+        bytes += frame.data.len();
+        if frames > limit.max_frames || bytes > limit.max_bytes {
+            writer.shutdown_all()?;
+            return Err(WebSocketError::PeerDidNotClose);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, Write};
+    use std::net::{Shutdown, TcpListener, TcpStream};
+    use websocket_core::message::Message;
+    use websocket_core::protocol::message::Message as MessageAble;
+    use websocket_core::protocol::role::Role;
+    use crate::receiver::Reader;
+    use crate::sender::{Sender, Writer};
+    use super::*;
+
+    /// Our reader and writer on one end of a loopback connection, and the
+    /// peer's end, which writes masked frames like a client.
+    fn connected() -> (Reader<TcpStream>, Writer<TcpStream>, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let peer = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (ours, _) = listener.accept().unwrap();
+        let reader = Reader::from_reader(ours.try_clone().unwrap(), Role::Server);
+        let writer = Writer {
+            stream: ours,
+            sender: Sender::new(false),
+        };
+        (reader, writer, peer)
+    }
+
+    fn send(peer: &mut TcpStream, message: &Message) {
+        message.serialize(peer, true).unwrap();
+        peer.flush().unwrap();
+    }
+
+    fn bye(code: u16) -> Message {
+        Message::close_because(code, "bye".to_string())
+    }
+
+    #[test]
+    fn matching_echo() {
+        let (mut reader, mut writer, mut peer) = connected();
+        send(&mut peer, &bye(1000));
+        let outcome = close_handshake(&mut reader, &mut writer, &bye(1000), DrainLimit::default(), MismatchPolicy::Report).unwrap();
+        let CloseOutcome::Clean(summary) = outcome else { panic!("{:?}", outcome) };
+        assert_eq!(summary.initiated_by, CloseInitiator::Local);
+        assert_eq!(summary.received_code, Some(1000));
+        assert!(!summary.mismatch);
+    }
+
+    #[test]
+    fn mismatched_echo() {
+        let (mut reader, mut writer, mut peer) = connected();
+        send(&mut peer, &Message::ping(b"still here".to_vec()));
+        send(&mut peer, &bye(1001));
+        let outcome = close_handshake(&mut reader, &mut writer, &bye(1000), DrainLimit::default(), MismatchPolicy::Report).unwrap();
+        let CloseOutcome::Mismatch(summary) = outcome else { panic!("{:?}", outcome) };
+        assert_eq!(summary.initiated_by, CloseInitiator::Local);
+        assert_eq!((summary.sent_code, summary.received_code), (Some(1000), Some(1001)));
+    }
+
+    #[test]
+    fn peer_closed_first() {
+        let (mut reader, mut writer, mut peer) = connected();
+        send(&mut peer, &bye(1001));
+        let peer_close = reader.recv_message().unwrap();
+        let reply = peer_close.close_reply().unwrap();
+        let outcome = close_handshake(&mut reader, &mut writer, &reply, DrainLimit::default(), MismatchPolicy::Report).unwrap();
+        let CloseOutcome::Clean(summary) = outcome else { panic!("{:?}", outcome) };
+        assert_eq!(summary.initiated_by, CloseInitiator::Remote);
+        assert_eq!(summary.received, peer_close);
+        assert!(!summary.mismatch);
+    }
+
+    #[test]
+    fn simultaneous_close() {
+        let (mut reader, mut writer, mut peer) = connected();
+        let mut bytes = Vec::new();
+        Message::ping(Vec::new()).serialize(&mut bytes, true).unwrap();
+        bye(1001).serialize(&mut bytes, true).unwrap();
+        peer.write_all(&bytes).unwrap();
+        // the peer's Close arrived, unread, before ours goes out
+        assert_eq!(reader.stream.fill_buf().unwrap(), &bytes[..]);
+        assert!(close_buffered(reader.stream.buffer()));
+        let outcome = close_handshake(&mut reader, &mut writer, &bye(1000), DrainLimit::default(), MismatchPolicy::Ignore).unwrap();
+        let CloseOutcome::Clean(summary) = outcome else { panic!("{:?}", outcome) };
+        assert_eq!(summary.initiated_by, CloseInitiator::Both);
+        assert_eq!(summary.received_code, Some(1001));
+        assert!(summary.mismatch);
+    }
+
+    #[test]
+    fn peer_closes_tcp_without_echo() {
+        let (mut reader, mut writer, mut peer) = connected();
+        send(&mut peer, &Message::text("last words".to_string()));
+        peer.shutdown(Shutdown::Write).unwrap();
+        let result = close_handshake(&mut reader, &mut writer, &bye(1000), DrainLimit::default(), MismatchPolicy::Ignore);
+        assert!(matches!(result, Err(WebSocketError::PeerDidNotClose)));
+    }
+
+    /// What our side wrote, read back by the peer, up to our shutdown.
+    fn read_replies(peer: &mut TcpStream) -> Vec<Message> {
+        let mut receiver = crate::receiver::Receiver::new(false);
+        let mut messages = Vec::new();
+        loop {
+            match ReceiverAble::recv_message(&mut receiver, peer) {
+                Ok(message) => messages.push(message),
+                Err(WebSocketError::PeerFinishedSending) => return messages,
+                Err(e) => panic!("{:?}", e),
+            }
+        }
+    }
+
+    #[test]
+    fn echo_close_replies_with_the_code_only() {
+        for (peer_close, reply) in [
+            (bye(4001), Message::close_because(4001, String::new())),
+            (Message::close(), Message::close()),
+        ] {
+            let (mut reader, mut writer, mut peer) = connected();
+            send(&mut peer, &peer_close);
+            let received = reader.recv_message().unwrap();
+            assert_eq!(received, peer_close);
+            echo_close(&mut writer, &received).unwrap();
+            assert_eq!(read_replies(&mut peer), vec![reply]);
+        }
+    }
+
+    #[test]
+    fn echo_close_only_echoes_a_close() {
+        let (_, mut writer, _) = connected();
+        let result = echo_close(&mut writer, &Message::text("bye".to_string()));
+        assert!(matches!(result, Err(WebSocketError::ProtocolError(_))));
+    }
+}
@@ -12,6 +12,7 @@ pub enum WsUrlError {
     NoHostName,
 }
 
+#[allow(clippy::enum_variant_names)]
 #[derive(Error, Debug)]
 pub enum WebSocketOtherError {
     #[error(" WebSocket protocol error: {0}")]
@@ -21,7 +22,7 @@ pub enum WebSocketOtherError {
     #[error(" Invalid WebSocket response error: {0}")]
     ResponseError(&'static str),
     #[error(" Received unexpected status code: {0}")]
-    StatusCodeError(#[from] StatusCode),
+    StatusCodeError(StatusCode),
     #[error(" An HTTP parsing error: {0}")]
     HttpError(#[from] HttpError),
     // #[error(" A URL parsing error: {0}")]
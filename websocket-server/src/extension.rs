@@ -0,0 +1,131 @@
+//! Choosing which offered extensions a server accepts, and configuring
+//! the connection for them.
+use std::sync::Arc;
+use websocket_core::compression::{CompressionCodec, DeflateCodec, PERMESSAGE_DEFLATE};
+use crate::header::{Extension, Parameter};
+use crate::receiver::Receiver;
+use crate::sender::Sender;
+
+/// An extension accepted by an [`ExtensionHandler`].
+#[derive(Clone)]
+pub struct Accepted {
+    /// The extension as it goes in the response.
+    pub response: Extension,
+    /// The codec that compresses messages while the extension is active.
+    pub codec: Arc<dyn CompressionCodec>,
+}
+
+/// Accepts or declines offers of one extension.
+pub trait ExtensionHandler: Send + Sync {
+    /// The extension name this handler answers to.
+    fn name(&self) -> &str;
+
+    /// The response to `offer`, or `None` to decline it.
+    fn accept(&self, offer: &Extension) -> Option<Accepted>;
+}
+
+/// Server side of permessage-deflate (RFC7692).
+///
+/// Offers restricting the server's window are declined, flate2 can't
+/// honour them; offers with unknown parameters are declined as RFC7692
+/// requires.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DeflateHandler {
+    pub codec: DeflateCodec,
+}
+
+impl ExtensionHandler for DeflateHandler {
+    fn name(&self) -> &str {
+        PERMESSAGE_DEFLATE
+    }
+
+    fn accept(&self, offer: &Extension) -> Option<Accepted> {
+        let mut codec = self.codec;
+        let mut params = Vec::new();
+        for param in &offer.params {
+            match (param.name.to_ascii_lowercase().as_str(), param.value.as_deref()) {
+                ("server_no_context_takeover", None) => codec.compress_no_context_takeover = true,
+                ("client_no_context_takeover", None) => codec.decompress_no_context_takeover = true,
+                ("server_max_window_bits", Some("15")) => {}
+                // we may leave the client's window at the default
+                ("client_max_window_bits", None) => continue,
+                ("client_max_window_bits", Some(bits)) if matches!(bits.parse::<u8>(), Ok(8..=15)) => continue,
+                _ => return None,
+            }
+            params.push(param.clone());
+        }
+        if codec.compress_no_context_takeover && !params.iter().any(|x| x.name == "server_no_context_takeover") {
+            params.push(Parameter {
+                name: "server_no_context_takeover".to_string(),
+                value: None,
+            });
+        }
+        Some(Accepted {
+            response: Extension {
+                name: PERMESSAGE_DEFLATE.to_string(),
+                params,
+            },
+            codec: Arc::new(codec),
+        })
+    }
+}
+
+/// Picks from a client's offered extensions those some handler accepts.
+///
+/// Every handler compresses messages with RSV1, so at most one offer is
+/// accepted: the first one, in the client's order of preference, that a
+/// handler accepts.
+#[derive(Clone, Default)]
+pub struct ExtensionNegotiator {
+    handlers: Vec<Arc<dyn ExtensionHandler>>,
+}
+
+/// The result of [`ExtensionNegotiator::negotiate`].
+#[derive(Clone, Default)]
+pub struct Negotiation {
+    /// The extensions for the `Sec-WebSocket-Extensions` response header.
+    pub accepted: Vec<Extension>,
+    pub codec: Option<Arc<dyn CompressionCodec>>,
+}
+
+impl ExtensionNegotiator {
+    pub fn new() -> ExtensionNegotiator {
+        ExtensionNegotiator::default()
+    }
+
+    pub fn with_handler(mut self, handler: Arc<dyn ExtensionHandler>) -> ExtensionNegotiator {
+        self.handlers.push(handler);
+        self
+    }
+
+    pub fn negotiate(&self, offered: &[Extension]) -> Negotiation {
+        for offer in offered {
+            let handler = self.handlers.iter().find(|handler| handler.name().eq_ignore_ascii_case(&offer.name));
+            if let Some(accepted) = handler.and_then(|handler| handler.accept(offer)) {
+                return Negotiation {
+                    accepted: vec![accepted.response],
+                    codec: Some(accepted.codec),
+                };
+            }
+        }
+        Negotiation::default()
+    }
+}
+
+impl Negotiation {
+    /// The value of the `Sec-WebSocket-Extensions` response header, `None`
+    /// if nothing was accepted and the header should be left out.
+    pub fn response_header(&self) -> Option<String> {
+        if self.accepted.is_empty() {
+            return None;
+        }
+        let extensions: Vec<String> = self.accepted.iter().map(ToString::to_string).collect();
+        Some(extensions.join(", "))
+    }
+
+    /// Sets up the compressor and decompressor of a connection.
+    pub fn apply(&self, receiver: &mut Receiver, sender: &mut Sender) {
+        receiver.set_decompressor(self.codec.as_ref().map(|codec| codec.new_decompressor()));
+        sender.set_compressor(self.codec.as_ref().map(|codec| codec.new_compressor()));
+    }
+}
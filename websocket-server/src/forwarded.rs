@@ -0,0 +1,416 @@
+//! The client behind a reverse proxy, from `Forwarded` (RFC7239) or
+//! `X-Forwarded-For`, see [`AcceptConfig::connection_info`](crate::handshake::AcceptConfig::connection_info).
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+use thiserror::Error;
+
+/// A range of addresses such as `10.0.0.0/8` or `fd00::/8`. A bare
+/// address is a range of one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpCidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+#[error("invalid CIDR range {0:?}")]
+pub struct CidrError(pub String);
+
+impl IpCidr {
+    /// The range of the `prefix` leading bits of `addr`, failing if
+    /// `prefix` is longer than the address. A range within the IPv4
+    /// addresses mapped into IPv6 is kept as the IPv4 range.
+    pub fn new(addr: IpAddr, prefix: u8) -> Result<IpCidr, CidrError> {
+        let bits = if addr.is_ipv4() { 32 } else { 128 };
+        if prefix > bits {
+            return Err(CidrError(format!("{}/{}", addr, prefix)));
+        }
+        match addr.to_canonical() {
+            IpAddr::V4(mapped) if addr.is_ipv6() && prefix >= 96 => Ok(IpCidr {
+                addr: IpAddr::V4(mapped),
+                prefix: prefix - 96,
+            }),
+            _ => Ok(IpCidr { addr, prefix }),
+        }
+    }
+
+    /// Whether `addr` is in this range. IPv4 addresses mapped into IPv6
+    /// count as IPv4.
+    pub fn contains(&self, addr: &IpAddr) -> bool {
+        match (self.addr.to_canonical(), addr.to_canonical()) {
+            (IpAddr::V4(range), IpAddr::V4(addr)) => {
+                prefix_matches(u32::from(range) as u128, u32::from(addr) as u128, self.prefix, 32)
+            }
+            (IpAddr::V6(range), IpAddr::V6(addr)) => prefix_matches(u128::from(range), u128::from(addr), self.prefix, 128),
+            // an IPv6 range wider than the mapped IPv4 addresses
+            (IpAddr::V6(range), IpAddr::V4(addr)) => {
+                prefix_matches(u128::from(range), u128::from(addr.to_ipv6_mapped()), self.prefix, 128)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn prefix_matches(range: u128, addr: u128, prefix: u8, bits: u32) -> bool {
+    if prefix == 0 {
+        return true;
+    }
+    let shift = bits - prefix as u32;
+    range >> shift == addr >> shift
+}
+
+impl FromStr for IpCidr {
+    type Err = CidrError;
+
+    fn from_str(value: &str) -> Result<IpCidr, CidrError> {
+        let invalid = || CidrError(value.to_string());
+        let (addr, prefix) = match value.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (value, None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| invalid())?;
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse().map_err(|_| invalid())?,
+            None if addr.is_ipv4() => 32,
+            None => 128,
+        };
+        IpCidr::new(addr, prefix).map_err(|_| invalid())
+    }
+}
+
+/// Who is on the other end of an accepted connection.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectionInfo {
+    peer_addr: SocketAddr,
+    client_addr: IpAddr,
+    forwarded_proto: Option<String>,
+    forwarded_host: Option<String>,
+    forwarded_error: Option<&'static str>,
+}
+
+impl ConnectionInfo {
+    /// The address of the socket, which is the proxy's behind a proxy.
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer_addr
+    }
+
+    /// The client's address: the one proxies forwarded if the peer is a
+    /// trusted proxy and said so, the peer's otherwise.
+    pub fn client_addr(&self) -> IpAddr {
+        self.client_addr
+    }
+
+    /// The scheme the client used to reach the first proxy, e.g. `https`.
+    pub fn forwarded_proto(&self) -> Option<&str> {
+        self.forwarded_proto.as_deref()
+    }
+
+    /// The `Host` the client asked the first proxy for.
+    pub fn forwarded_host(&self) -> Option<&str> {
+        self.forwarded_host.as_deref()
+    }
+
+    /// Why the forwarding headers of a trusted proxy were ignored, if they
+    /// were malformed. The connection is fine all the same.
+    pub fn forwarded_error(&self) -> Option<&'static str> {
+        self.forwarded_error
+    }
+
+    /// The connection of `peer_addr`, trusting the forwarding headers
+    /// among `headers` only if `peer_addr` is within `trusted_proxies`.
+    ///
+    /// `Forwarded` is used if present, `X-Forwarded-For` with
+    /// `X-Forwarded-Proto` and `X-Forwarded-Host` otherwise. Of the listed
+    /// hops, the client is the rightmost one not within `trusted_proxies`,
+    /// or the leftmost if all are. A hop given as `unknown` or an
+    /// obfuscated name leaves the client unknown and the peer's address is
+    /// used.
+    pub fn from_headers(peer_addr: SocketAddr, headers: &[(String, String)], trusted_proxies: &[IpCidr]) -> ConnectionInfo {
+        let mut info = ConnectionInfo {
+            peer_addr,
+            client_addr: peer_addr.ip(),
+            forwarded_proto: None,
+            forwarded_host: None,
+            forwarded_error: None,
+        };
+        let trusted = |addr: &IpAddr| trusted_proxies.iter().any(|range| range.contains(addr));
+        if !trusted(&peer_addr.ip()) {
+            return info;
+        }
+        let values = |name: &str| -> Vec<&str> {
+            headers
+                .iter()
+                .filter(|(key, _)| key.eq_ignore_ascii_case(name))
+                .map(|(_, value)| value.as_str())
+                .collect()
+        };
+
+        let forwarded = values("Forwarded");
+        let hops = if !forwarded.is_empty() {
+            parse_forwarded(&forwarded)
+        } else {
+            let forwarded_for = values("X-Forwarded-For");
+            if forwarded_for.is_empty() {
+                return info;
+            }
+            let last = |name| {
+                values(name)
+                    .iter()
+                    .flat_map(|value| value.split(','))
+                    .map(str::trim)
+                    .rfind(|value| !value.is_empty())
+                    .map(str::to_string)
+            };
+            list_items(&forwarded_for, ',').and_then(|items| {
+                items
+                    .iter()
+                    .map(|item| {
+                        Ok(Hop {
+                            node: parse_node(item)?,
+                            proto: last("X-Forwarded-Proto"),
+                            host: last("X-Forwarded-Host"),
+                        })
+                    })
+                    .collect()
+            })
+        };
+        let hops = match hops {
+            Ok(hops) => hops,
+            Err(reason) => {
+                info.forwarded_error = Some(reason);
+                return info;
+            }
+        };
+
+        let mut client = None;
+        for hop in hops.iter().rev() {
+            match hop.node {
+                Some(addr) if trusted(&addr) => client = Some(hop),
+                Some(_) => {
+                    client = Some(hop);
+                    break;
+                }
+                None => {
+                    client = None;
+                    break;
+                }
+            }
+        }
+        if let Some(hop) = client {
+            if let Some(addr) = hop.node {
+                info.client_addr = addr;
+            }
+            info.forwarded_proto = hop.proto.clone();
+            info.forwarded_host = hop.host.clone();
+        }
+        info
+    }
+}
+
+/// One client or proxy a request passed. `node` is `None` for an
+/// `unknown` or obfuscated one.
+struct Hop {
+    node: Option<IpAddr>,
+    proto: Option<String>,
+    host: Option<String>,
+}
+
+/// Splits header values at `separator`, but not inside quoted strings,
+/// dropping empty items.
+fn list_items(values: &[&str], separator: char) -> Result<Vec<String>, &'static str> {
+    let mut items = Vec::new();
+    for value in values {
+        let mut item = String::new();
+        let mut quoted = false;
+        let mut escaped = false;
+        for c in value.chars() {
+            if escaped {
+                escaped = false;
+            } else if quoted && c == '\\' {
+                escaped = true;
+            } else if c == '"' {
+                quoted = !quoted;
+            } else if c == separator && !quoted {
+                items.push(std::mem::take(&mut item));
+                continue;
+            }
+            item.push(c);
+        }
+        if quoted {
+            return Err("Unterminated quoted string in forwarding header");
+        }
+        items.push(item);
+    }
+    items.retain(|item| !item.trim().is_empty());
+    Ok(items.into_iter().map(|item| item.trim().to_string()).collect())
+}
+
+/// The value of a quoted string, or `value` as it is if it isn't one.
+fn unquote(value: &str) -> String {
+    match value.strip_prefix('"').and_then(|value| value.strip_suffix('"')) {
+        Some(inner) => {
+            let mut unquoted = String::with_capacity(inner.len());
+            let mut chars = inner.chars();
+            while let Some(c) = chars.next() {
+                match c {
+                    '\\' => unquoted.extend(chars.next()),
+                    c => unquoted.push(c),
+                }
+            }
+            unquoted
+        }
+        None => value.to_string(),
+    }
+}
+
+/// Parses a hop as listed in `Forwarded: for=` or `X-Forwarded-For`:
+/// `192.0.2.1`, `192.0.2.1:80`, `[2001:db8::1]`, `[2001:db8::1]:80`, a
+/// bare IPv6 address, `unknown` or an obfuscated `_name`.
+fn parse_node(value: &str) -> Result<Option<IpAddr>, &'static str> {
+    let value = unquote(value.trim());
+    if value.eq_ignore_ascii_case("unknown") || value.starts_with('_') {
+        return Ok(None);
+    }
+    if let Ok(addr) = value.parse::<IpAddr>() {
+        return Ok(Some(addr));
+    }
+    let invalid = "Invalid address in forwarding header";
+    let (addr, port) = match value.strip_prefix('[') {
+        Some(rest) => {
+            let (addr, rest) = rest.split_once(']').ok_or(invalid)?;
+            let addr: Ipv6Addr = addr.parse().map_err(|_| invalid)?;
+            (IpAddr::V6(addr), rest.strip_prefix(':'))
+        }
+        None => {
+            let (addr, port) = value.split_once(':').ok_or(invalid)?;
+            let addr: IpAddr = addr.parse().map_err(|_| invalid)?;
+            (addr, Some(port))
+        }
+    };
+    match port {
+        // a port may be obfuscated as well (RFC7239 6.3)
+        Some(port) if port.parse::<u16>().is_ok() || port.starts_with('_') => Ok(Some(addr)),
+        None if value.ends_with(']') => Ok(Some(addr)),
+        _ => Err(invalid),
+    }
+}
+
+/// Parses the elements of `Forwarded` headers into hops, in order.
+/// Elements without `for` are skipped.
+fn parse_forwarded(values: &[&str]) -> Result<Vec<Hop>, &'static str> {
+    let mut hops = Vec::new();
+    for element in list_items(values, ',')? {
+        let mut hop = Hop {
+            node: None,
+            proto: None,
+            host: None,
+        };
+        let mut has_for = false;
+        for pair in list_items(&[&element], ';')? {
+            let (name, value) = pair.split_once('=').ok_or("Malformed Forwarded pair")?;
+            let name = name.trim();
+            if name.eq_ignore_ascii_case("for") {
+                hop.node = parse_node(value)?;
+                has_for = true;
+            } else if name.eq_ignore_ascii_case("proto") {
+                hop.proto = Some(unquote(value.trim()));
+            } else if name.eq_ignore_ascii_case("host") {
+                hop.host = Some(unquote(value.trim()));
+            }
+        }
+        if has_for {
+            hops.push(hop);
+        }
+    }
+    Ok(hops)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn connection(peer: &str, headers: &[(&str, &str)]) -> ConnectionInfo {
+        let headers: Vec<(String, String)> = headers
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        let trusted = ["10.0.0.0/8".parse().unwrap(), "fd00::/8".parse().unwrap()];
+        ConnectionInfo::from_headers(peer.parse().unwrap(), &headers, &trusted)
+    }
+
+    fn ip(addr: &str) -> IpAddr {
+        addr.parse().unwrap()
+    }
+
+    #[test]
+    fn client_behind_several_proxies() {
+        let info = connection("10.0.0.1:443", &[("X-Forwarded-For", "203.0.113.7, 10.0.0.2"), ("X-Forwarded-For", "10.0.0.3")]);
+        assert_eq!(info.client_addr(), ip("203.0.113.7"));
+        assert_eq!(info.forwarded_error(), None);
+
+        let info = connection(
+            "10.0.0.1:443",
+            &[("Forwarded", "for=198.51.100.17;proto=https;host=example.com, for=10.0.0.2;proto=http")],
+        );
+        assert_eq!(info.client_addr(), ip("198.51.100.17"));
+        assert_eq!(info.forwarded_proto(), Some("https"));
+        assert_eq!(info.forwarded_host(), Some("example.com"));
+
+        // all trusted: the leftmost is as far as can be seen
+        let info = connection("10.0.0.1:443", &[("X-Forwarded-For", "10.1.1.1, 10.0.0.2")]);
+        assert_eq!(info.client_addr(), ip("10.1.1.1"));
+    }
+
+    #[test]
+    fn spoofed_headers_are_ignored() {
+        // an untrusted peer can claim anything
+        let info = connection("203.0.113.9:5000", &[("X-Forwarded-For", "10.0.0.5"), ("Forwarded", "for=1.2.3.4;proto=https")]);
+        assert_eq!(info.client_addr(), ip("203.0.113.9"));
+        assert_eq!(info.forwarded_proto(), None);
+        assert_eq!(info.forwarded_error(), None);
+
+        // a client prepending hops of its own is still told apart
+        let info = connection("10.0.0.1:443", &[("X-Forwarded-For", "6.6.6.6, 10.0.0.99, 203.0.113.7")]);
+        assert_eq!(info.client_addr(), ip("203.0.113.7"));
+    }
+
+    #[test]
+    fn bracketed_ipv6() {
+        let info = connection("[fd00::1]:443", &[("Forwarded", "for=\"[2001:db8:cafe::17]:4711\"")]);
+        assert_eq!(info.client_addr(), ip("2001:db8:cafe::17"));
+        let info = connection("10.0.0.1:443", &[("X-Forwarded-For", "[2001:db8::1]")]);
+        assert_eq!(info.client_addr(), ip("2001:db8::1"));
+        let info = connection("10.0.0.1:443", &[("X-Forwarded-For", "2001:db8::2")]);
+        assert_eq!(info.client_addr(), ip("2001:db8::2"));
+    }
+
+    #[test]
+    fn malformed_values_fall_back_to_the_peer() {
+        for headers in [
+            [("Forwarded", "for=\"[2001:db8::1\"")],
+            [("Forwarded", "for=\"192.0.2.1")],
+            [("Forwarded", "for")],
+            [("Forwarded", "for=192.0.2.1:http")],
+            [("X-Forwarded-For", "not-an-address")],
+        ] {
+            let info = connection("10.0.0.1:443", &headers);
+            assert_eq!(info.client_addr(), ip("10.0.0.1"), "{:?}", headers);
+            assert!(info.forwarded_error().is_some(), "{:?}", headers);
+        }
+    }
+
+    #[test]
+    fn mapped_ipv4_ranges_and_addresses() {
+        let mapped_range: IpCidr = "::ffff:10.0.0.0/104".parse().unwrap();
+        let range: IpCidr = "10.0.0.0/8".parse().unwrap();
+        assert_eq!(mapped_range, range);
+        for range in [mapped_range, range] {
+            assert!(range.contains(&ip("10.1.2.3")));
+            assert!(range.contains(&ip("::ffff:10.1.2.3")));
+            assert!(!range.contains(&ip("11.1.2.3")));
+        }
+        let everything: IpCidr = "::/0".parse().unwrap();
+        assert!(everything.contains(&ip("192.0.2.1")));
+        assert!(IpCidr::new(ip("::ffff:10.0.0.0"), 129).is_err());
+    }
+}
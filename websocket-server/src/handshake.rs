@@ -0,0 +1,576 @@
+//! The HTTP side of the opening handshake.
+use std::io::{self, Read};
+use std::net::SocketAddr;
+use websocket_core::protocol::role::Role;
+use websocket_core::sec_header::{names, WebSocketAccept, WebSocketKey};
+use crate::error::WebSocketOtherError;
+use crate::extension::{ExtensionNegotiator, Negotiation};
+use crate::forwarded::{ConnectionInfo, IpCidr};
+use crate::header::{Extension, Version};
+
+/// The only protocol version defined by RFC6455, as sent; see
+/// [`SUPPORTED_VERSION`](crate::header::SUPPORTED_VERSION).
+pub const WEBSOCKET_VERSION: &str = "13";
+
+/// The upgrade request a client sends to open a connection.
+pub struct ClientHandshake {
+    /// Value of the `Host` header.
+    pub host: String,
+    /// The request target, e.g. `/chat?room=1`.
+    pub resource: String,
+    pub key: WebSocketKey,
+    pub origin: Option<String>,
+    pub protocols: Vec<String>,
+    pub extensions: Vec<Extension>,
+}
+
+impl ClientHandshake {
+    /// Creates a request for `resource` on `host` with a fresh random key.
+    pub fn new(host: String, resource: String) -> ClientHandshake {
+        ClientHandshake {
+            host,
+            resource,
+            key: WebSocketKey::new(),
+            origin: None,
+            protocols: Vec::new(),
+            extensions: Vec::new(),
+        }
+    }
+
+    /// The request headers as name/value pairs, for performing the upgrade
+    /// with an existing HTTP client.
+    pub fn headers(&self) -> Vec<(String, String)> {
+        let mut headers = vec![
+            ("Host".to_string(), self.host.clone()),
+            ("Upgrade".to_string(), "websocket".to_string()),
+            ("Connection".to_string(), "Upgrade".to_string()),
+            (names::KEY.to_string(), self.key.serialize()),
+            (names::VERSION.to_string(), Version::default().to_string()),
+        ];
+        if let Some(ref origin) = self.origin {
+            headers.push(("Origin".to_string(), origin.clone()));
+        }
+        if !self.protocols.is_empty() {
+            headers.push((names::PROTOCOL.to_string(), self.protocols.join(", ")));
+        }
+        if !self.extensions.is_empty() {
+            let extensions: Vec<String> = self.extensions.iter().map(ToString::to_string).collect();
+            headers.push((names::EXTENSIONS.to_string(), extensions.join(", ")));
+        }
+        headers
+    }
+
+    /// The complete request as it goes on the wire.
+    pub fn to_request_bytes(&self) -> Vec<u8> {
+        let mut request = format!("GET {} HTTP/1.1\r\n", self.resource);
+        for (name, value) in self.headers() {
+            request.push_str(&name);
+            request.push_str(": ");
+            request.push_str(&value);
+            request.push_str("\r\n");
+        }
+        request.push_str("\r\n");
+        request.into_bytes()
+    }
+
+    /// The `Sec-WebSocket-Accept` value the server must answer with.
+    pub fn expected_accept(&self) -> WebSocketAccept {
+        WebSocketAccept::new(&self.key)
+    }
+}
+
+/// An HTTP head read off a stream, and whatever arrived after it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HandshakeHead {
+    /// The request or status line and the headers, up to and including the
+    /// blank line that ends them.
+    pub head: Vec<u8>,
+    /// Bytes read past the head, typically the first frames of a peer that
+    /// didn't wait for the handshake to complete. They belong to the
+    /// WebSocket stream, see `Reader::from_upgraded`.
+    pub leftover: Vec<u8>,
+}
+
+/// How long a single line of a handshake head may be by default, not
+/// counting its CRLF.
+pub const DEFAULT_MAX_HEAD_LINE_LEN: usize = 8192;
+
+/// Reads the head of an upgrade request, failing with `RequestError` if it
+/// is longer than `max_len` or has a line longer than
+/// [`DEFAULT_MAX_HEAD_LINE_LEN`].
+pub fn read_request_head<R>(reader: &mut R, max_len: usize) -> Result<HandshakeHead, WebSocketOtherError>
+    where
+        R: Read,
+{
+    read_request_head_with_line_limit(reader, max_len, DEFAULT_MAX_HEAD_LINE_LEN)
+}
+
+/// Like [`read_request_head`], with lines limited to `max_line_len`.
+pub fn read_request_head_with_line_limit<R>(
+    reader: &mut R,
+    max_len: usize,
+    max_line_len: usize,
+) -> Result<HandshakeHead, WebSocketOtherError>
+    where
+        R: Read,
+{
+    read_head(reader, max_len, max_line_len, WebSocketOtherError::RequestError)
+}
+
+/// Reads the head of the server's response, failing with `ResponseError`
+/// if it is longer than `max_len` or has a line longer than
+/// [`DEFAULT_MAX_HEAD_LINE_LEN`].
+pub fn read_response_head<R>(reader: &mut R, max_len: usize) -> Result<HandshakeHead, WebSocketOtherError>
+    where
+        R: Read,
+{
+    read_response_head_with_line_limit(reader, max_len, DEFAULT_MAX_HEAD_LINE_LEN)
+}
+
+/// Like [`read_response_head`], with lines limited to `max_line_len`.
+pub fn read_response_head_with_line_limit<R>(
+    reader: &mut R,
+    max_len: usize,
+    max_line_len: usize,
+) -> Result<HandshakeHead, WebSocketOtherError>
+    where
+        R: Read,
+{
+    read_head(reader, max_len, max_line_len, WebSocketOtherError::ResponseError)
+}
+
+fn read_head<R>(
+    reader: &mut R,
+    max_len: usize,
+    max_line_len: usize,
+    invalid: fn(&'static str) -> WebSocketOtherError,
+) -> Result<HandshakeHead, WebSocketOtherError>
+    where
+        R: Read,
+{
+    let mut buffer = Vec::new();
+    let mut chunk = [0u8; 1024];
+    // where the line being read starts
+    let mut line_start = 0;
+    loop {
+        let read = reader.read(&mut chunk)?;
+        if read == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "stream ended in the handshake head").into());
+        }
+        // a CRLF may straddle two reads
+        let from = buffer.len().saturating_sub(1).max(line_start);
+        buffer.extend_from_slice(&chunk[..read]);
+        let mut at = from;
+        while let Some(found) = buffer[at..].windows(2).position(|window| window == b"\r\n") {
+            let line_end = at + found;
+            if line_end - line_start > max_line_len {
+                return Err(invalid("Handshake head line too long"));
+            }
+            at = line_end + 2;
+            if line_end == line_start && line_start > 0 {
+                // an empty line ends the head
+                if at > max_len {
+                    return Err(invalid("Handshake head too long"));
+                }
+                let leftover = buffer.split_off(at);
+                return Ok(HandshakeHead { head: buffer, leftover });
+            }
+            line_start = at;
+        }
+        if buffer.len() - line_start > max_line_len {
+            return Err(invalid("Handshake head line too long"));
+        }
+        if buffer.len() > max_len {
+            return Err(invalid("Handshake head too long"));
+        }
+    }
+}
+
+/// Whether a request with these headers asks for a WebSocket upgrade:
+/// `Upgrade` lists `websocket`, `Connection` lists `Upgrade`, and a key and
+/// a version are present. Nothing else is validated, so a server sharing
+/// its port with plain HTTP can route on this before committing to the
+/// handshake.
+pub fn is_websocket_upgrade(headers: &[(String, String)]) -> bool {
+    let lists = |name: &str, token: &str| {
+        headers
+            .iter()
+            .filter(|(key, _)| key.eq_ignore_ascii_case(name))
+            .flat_map(|(_, value)| value.split(','))
+            .any(|item| item.trim().eq_ignore_ascii_case(token))
+    };
+    let present = |name: &str| headers.iter().any(|(key, _)| key.eq_ignore_ascii_case(name));
+    lists("Upgrade", "websocket") && lists("Connection", "Upgrade") && present(names::KEY) && present(names::VERSION)
+}
+
+/// How long an upgrade request may get before [`parse_upgrade_request`]
+/// gives up waiting for its end.
+pub const MAX_UPGRADE_REQUEST_LEN: usize = 64 * 1024;
+
+/// Why [`parse_upgrade_request`] returned no request.
+#[derive(Debug)]
+pub enum ParseUpgradeError {
+    /// The head isn't complete yet; call again with more bytes.
+    NeedMoreData,
+    /// The bytes aren't a valid upgrade request.
+    Invalid(WebSocketOtherError),
+}
+
+impl From<WebSocketOtherError> for ParseUpgradeError {
+    fn from(error: WebSocketOtherError) -> Self {
+        ParseUpgradeError::Invalid(error)
+    }
+}
+
+/// An upgrade request received by a server.
+#[derive(Clone)]
+pub struct WsUpgradeRequest {
+    /// The request target, e.g. `/chat?room=1`.
+    pub resource: String,
+    pub key: WebSocketKey,
+    pub version: Version,
+    pub origin: Option<String>,
+    /// The offered subprotocols, in the client's order of preference.
+    pub protocols: Vec<String>,
+    /// The offered extensions, in the client's order of preference.
+    pub extensions: Vec<Extension>,
+    /// All headers as name/value pairs, in the order received.
+    pub headers: Vec<(String, String)>,
+}
+
+impl WsUpgradeRequest {
+    /// The value of the first header called `name`.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Parses an upgrade request from the start of `bytes`, returning it with
+/// the length of its head. Bytes after the head belong to the WebSocket
+/// stream, see [`WebSocket::from_upgraded_parts`](crate::websocket::WebSocket::from_upgraded_parts).
+///
+/// Meant to be called again as bytes arrive, e.g. by a nonblocking accept
+/// loop; `NeedMoreData` means the head isn't complete yet. It fails once
+/// the head gets longer than [`MAX_UPGRADE_REQUEST_LEN`] or a line longer
+/// than [`DEFAULT_MAX_HEAD_LINE_LEN`].
+pub fn parse_upgrade_request(bytes: &[u8]) -> Result<(WsUpgradeRequest, usize), ParseUpgradeError> {
+    let invalid = |reason| ParseUpgradeError::Invalid(WebSocketOtherError::RequestError(reason));
+    let mut lines = Vec::new();
+    let mut line_start = 0;
+    let consumed = loop {
+        let line_end = match bytes[line_start..].windows(2).position(|window| window == b"\r\n") {
+            Some(at) => line_start + at,
+            None if bytes.len() - line_start > DEFAULT_MAX_HEAD_LINE_LEN => return Err(invalid("Handshake head line too long")),
+            None if bytes.len() > MAX_UPGRADE_REQUEST_LEN => return Err(invalid("Handshake head too long")),
+            None => return Err(ParseUpgradeError::NeedMoreData),
+        };
+        if line_end - line_start > DEFAULT_MAX_HEAD_LINE_LEN {
+            return Err(invalid("Handshake head line too long"));
+        }
+        if line_end > MAX_UPGRADE_REQUEST_LEN {
+            return Err(invalid("Handshake head too long"));
+        }
+        let line = std::str::from_utf8(&bytes[line_start..line_end]).map_err(|_| invalid("Handshake head is not UTF-8"))?;
+        line_start = line_end + 2;
+        if line.is_empty() && !lines.is_empty() {
+            break line_start;
+        }
+        lines.push(line);
+    };
+
+    let mut request_line = lines[0].split(' ');
+    let (method, resource, http) = match (request_line.next(), request_line.next(), request_line.next(), request_line.next()) {
+        (Some(method), Some(resource), Some(http), None) => (method, resource, http),
+        _ => return Err(invalid("Malformed request line")),
+    };
+    if method != "GET" {
+        return Err(invalid("Upgrade request method must be GET"));
+    }
+    if http != "HTTP/1.1" {
+        return Err(invalid("Upgrade request must be HTTP/1.1"));
+    }
+    let mut headers = Vec::with_capacity(lines.len() - 1);
+    for line in &lines[1..] {
+        let (name, value) = line.split_once(':').ok_or_else(|| invalid("Malformed header line"))?;
+        headers.push((name.trim().to_string(), value.trim().to_string()));
+    }
+    if !is_websocket_upgrade(&headers) {
+        return Err(invalid("Not a WebSocket upgrade request"));
+    }
+    let values = |name: &'static str| {
+        headers
+            .iter()
+            .filter(move |(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    };
+    let key = values(names::KEY)
+        .next()
+        .and_then(|key| key.parse().ok())
+        .ok_or_else(|| invalid("Invalid Sec-WebSocket-Key"))?;
+    let version = Version::parse(values(names::VERSION).next().unwrap_or_default())?;
+    let origin = values("Origin").next().map(str::to_string);
+    let protocols = values(names::PROTOCOL)
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .filter(|protocol| !protocol.is_empty())
+        .map(str::to_string)
+        .collect();
+    let mut extensions = Vec::new();
+    for value in values(names::EXTENSIONS) {
+        extensions.extend(Extension::parse_list(value)?);
+    }
+    let request = WsUpgradeRequest {
+        resource: resource.to_string(),
+        key,
+        version,
+        origin,
+        protocols,
+        extensions,
+        headers,
+    };
+    Ok((request, consumed))
+}
+
+/// What a server accepts in an upgrade, see [`build_accept_response`].
+#[derive(Clone, Default)]
+pub struct AcceptConfig {
+    /// Supported subprotocols. The client's most preferred one among them
+    /// is selected.
+    pub protocols: Vec<String>,
+    pub extensions: ExtensionNegotiator,
+    /// Further headers for the response.
+    pub headers: Vec<(String, String)>,
+    /// Proxies whose forwarding headers are believed, see
+    /// [`AcceptConfig::connection_info`].
+    pub trusted_proxies: Vec<IpCidr>,
+}
+
+/// What both ends agreed on in a completed handshake.
+#[derive(Clone)]
+pub struct HandshakeFacts {
+    /// Which end we are.
+    pub role: Role,
+    /// The selected subprotocol.
+    pub protocol: Option<String>,
+    /// The accepted extensions and the codec they need.
+    pub extensions: Negotiation,
+}
+
+impl AcceptConfig {
+    /// What accepting `request` agrees on.
+    pub fn facts(&self, request: &WsUpgradeRequest) -> HandshakeFacts {
+        let protocol = request
+            .protocols
+            .iter()
+            .find(|offered| self.protocols.contains(offered))
+            .cloned();
+        HandshakeFacts {
+            role: Role::Server,
+            protocol,
+            extensions: self.extensions.negotiate(&request.extensions),
+        }
+    }
+
+    /// Who sent `request` over a connection from `peer_addr`. Behind one
+    /// of the `trusted_proxies` this is the client they forwarded, see
+    /// [`ConnectionInfo::from_headers`]; the headers of any other peer are
+    /// ignored.
+    pub fn connection_info(&self, request: &WsUpgradeRequest, peer_addr: SocketAddr) -> ConnectionInfo {
+        ConnectionInfo::from_headers(peer_addr, &request.headers, &self.trusted_proxies)
+    }
+}
+
+/// The `101 Switching Protocols` response accepting `request`, as it goes
+/// on the wire. The same agreement is given by [`AcceptConfig::facts`].
+pub fn build_accept_response(request: &WsUpgradeRequest, config: &AcceptConfig) -> Vec<u8> {
+    let facts = config.facts(request);
+    let mut response = String::from("HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n");
+    let mut headers = vec![(names::ACCEPT.to_string(), WebSocketAccept::new(&request.key).serialize())];
+    if let Some(protocol) = facts.protocol {
+        headers.push((names::PROTOCOL.to_string(), protocol));
+    }
+    if let Some(extensions) = facts.extensions.response_header() {
+        headers.push((names::EXTENSIONS.to_string(), extensions));
+    }
+    headers.extend(config.headers.iter().cloned());
+    for (name, value) in headers {
+        response.push_str(&name);
+        response.push_str(": ");
+        response.push_str(&value);
+        response.push_str("\r\n");
+    }
+    response.push_str("\r\n");
+    response.into_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+    use std::io::Write;
+    use std::sync::{Arc, Mutex};
+    use websocket_core::action::receiver::Receiver as ReceiverAble;
+    use websocket_core::dataframe::DataFrame;
+    use websocket_core::message::Message;
+    use websocket_core::protocol::dataframe::DataFrameRefTrait;
+    use websocket_core::protocol::header::Opcode;
+    use crate::receiver::{Reader, Receiver};
+    use crate::websocket::WebSocket;
+    use super::*;
+
+    /// Hands out one chunk per read, as they arrived on the wire.
+    struct Chunks(VecDeque<Vec<u8>>);
+
+    impl Read for Chunks {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let chunk = match self.0.front_mut() {
+                Some(chunk) => chunk,
+                None => return Ok(0),
+            };
+            let len = buf.len().min(chunk.len());
+            buf[..len].copy_from_slice(&chunk[..len]);
+            chunk.drain(..len);
+            if chunk.is_empty() {
+                self.0.pop_front();
+            }
+            Ok(len)
+        }
+    }
+
+    /// A fragmented text message and a binary one, as `role` receives them.
+    fn first_frames(role: Role) -> (Vec<u8>, Vec<Message>) {
+        let masked = role.expects_masked_incoming();
+        let mut bytes = Vec::new();
+        for frame in [
+            DataFrame::new(false, Opcode::Text, b"hello ".to_vec()),
+            DataFrame::new(true, Opcode::Continuation, b"world".to_vec()),
+            DataFrame::new(true, Opcode::Binary, vec![7; 40]),
+        ] {
+            frame.write_to(&mut bytes, masked).unwrap();
+        }
+        (bytes, vec![Message::text("hello world".to_string()), Message::binary(vec![7; 40])])
+    }
+
+    fn request() -> Vec<u8> {
+        ClientHandshake::new("example.com".to_string(), "/chat".to_string()).to_request_bytes()
+    }
+
+    fn response() -> Vec<u8> {
+        let (request, _) = parse_upgrade_request(&request()).unwrap();
+        build_accept_response(&request, &AcceptConfig::default())
+    }
+
+    /// Reads the head and then the messages behind it, with the frames
+    /// arriving in the same read as the head up to `split`.
+    fn read_behind_head(head: &[u8], role: Role, split: usize) -> Vec<Message> {
+        let (frames, messages) = first_frames(role);
+        let mut wire = Chunks(VecDeque::from([[head, &frames[..split]].concat(), frames[split..].to_vec()]));
+        let read = match role {
+            Role::Server => read_request_head(&mut wire, 4096).unwrap(),
+            Role::Client => read_response_head(&mut wire, 4096).unwrap(),
+        };
+        assert_eq!(read.head, head);
+        assert_eq!(read.leftover, &frames[..split]);
+        let mut reader = Reader::from_upgraded(wire, read.leftover, role);
+        let received: Vec<Message> = messages.iter().map(|_| reader.recv_message().unwrap()).collect();
+        assert!(reader.recv_message().is_err(), "nothing more was sent");
+        received
+    }
+
+    #[test]
+    fn frames_in_the_read_of_the_request_head_are_kept() {
+        let (frames, messages) = first_frames(Role::Server);
+        for split in 0..=frames.len() {
+            assert_eq!(read_behind_head(&request(), Role::Server, split), messages, "split at {}", split);
+        }
+    }
+
+    #[test]
+    fn frames_in_the_read_of_the_response_head_are_kept() {
+        let (frames, messages) = first_frames(Role::Client);
+        for split in 0..=frames.len() {
+            assert_eq!(read_behind_head(&response(), Role::Client, split), messages, "split at {}", split);
+        }
+    }
+
+    #[test]
+    fn parse_upgrade_request_fed_a_byte_at_a_time() {
+        let request = request();
+        let (frames, _) = first_frames(Role::Server);
+        let bytes = [&request[..], &frames[..]].concat();
+        for fed in 0..=bytes.len() {
+            match parse_upgrade_request(&bytes[..fed]) {
+                Err(ParseUpgradeError::NeedMoreData) => assert!(fed < request.len(), "fed {}", fed),
+                Ok((parsed, consumed)) => {
+                    assert!(fed >= request.len(), "fed {}", fed);
+                    assert_eq!(consumed, request.len());
+                    assert_eq!(parsed.resource, "/chat");
+                    assert_eq!(parsed.header("Host"), Some("example.com"));
+                }
+                Err(e) => panic!("fed {}: {:?}", fed, e),
+            }
+        }
+    }
+
+    #[test]
+    fn parse_upgrade_request_with_crlf_split_across_feeds() {
+        let request = request();
+        let crs = request.iter().enumerate().filter(|&(_, &byte)| byte == b'\r').map(|(at, _)| at);
+        for cr in crs {
+            // the feed ends between CR and LF, the next one starts with LF
+            assert!(matches!(parse_upgrade_request(&request[..=cr]), Err(ParseUpgradeError::NeedMoreData)));
+            let last = cr + 2 == request.len();
+            assert_eq!(parse_upgrade_request(&request[..cr + 2]).is_ok(), last, "CR at {}", cr);
+        }
+    }
+
+    /// A stream with nothing more to read, collecting what is written.
+    struct Wire(Arc<Mutex<Vec<u8>>>);
+
+    impl Read for Wire {
+        fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+            Ok(0)
+        }
+    }
+
+    impl Write for Wire {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn connection_from_upgraded_parts() {
+        let mut bytes = request();
+        for message in [
+            Message::text("hi".to_string()),
+            Message::ping(b"are you there".to_vec()),
+            Message::binary(vec![1, 2, 3]),
+        ] {
+            message.write_to(&mut bytes, true).unwrap();
+        }
+        let (request, consumed) = parse_upgrade_request(&bytes).unwrap();
+        let facts = AcceptConfig::default().facts(&request);
+        let written = Arc::new(Mutex::new(Vec::new()));
+        let leftover = bytes[consumed..].to_vec();
+        let mut websocket = WebSocket::from_upgraded_parts(Wire(written.clone()), leftover, facts);
+
+        assert_eq!(websocket.recv().unwrap(), Message::text("hi".to_string()));
+        assert_eq!(websocket.recv().unwrap(), Message::binary(vec![1, 2, 3]));
+        websocket.send(Message::text("back".to_string())).unwrap();
+
+        // the client reads the pong answering its ping and our message
+        let written = written.lock().unwrap();
+        let mut stream = &written[..];
+        let mut client = Receiver::new(false);
+        assert_eq!(client.recv_message(&mut stream).unwrap(), Message::pong(b"are you there".to_vec()));
+        assert_eq!(client.recv_message(&mut stream).unwrap(), Message::text("back".to_string()));
+        assert!(stream.is_empty());
+    }
+}
@@ -1,4 +1,6 @@
+use std::fmt;
 use websocket_core::sec_header::{WebSocketAccept, WebSocketKey};
+use crate::error::WebSocketOtherError;
 
 pub enum Header {
     Accept(WebSocketAccept),
@@ -6,15 +8,102 @@ pub enum Header {
     Key(WebSocketKey),
     Origin(String),
     Protocol(Vec<String>),
-    Version(String)
+    Version(Version)
 }
 
+/// The only protocol version defined by RFC6455.
+pub const SUPPORTED_VERSION: u8 = 13;
+
+/// The value of `Sec-WebSocket-Version`, checked to be one we speak.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Version(u8);
+
+impl Version {
+    /// Parses a `Sec-WebSocket-Version` value, failing with a protocol
+    /// error for anything but [`SUPPORTED_VERSION`].
+    pub fn parse(value: &str) -> Result<Version, WebSocketOtherError> {
+        match value.trim().parse::<u8>() {
+            Ok(SUPPORTED_VERSION) => Ok(Version(SUPPORTED_VERSION)),
+            _ => Err(WebSocketOtherError::ProtocolError("Unsupported WebSocket version")),
+        }
+    }
+
+    pub fn number(self) -> u8 {
+        self.0
+    }
+}
+
+impl Default for Version {
+    fn default() -> Self {
+        Version(SUPPORTED_VERSION)
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Extension {
     pub name: String,
     pub params: Vec<Parameter>
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Parameter {
     pub name: String,
     pub value: Option<String>,
 }
+
+impl fmt::Display for Extension {
+    /// Formats the extension as it appears in `Sec-WebSocket-Extensions`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.name)?;
+        for param in &self.params {
+            match param.value {
+                Some(ref value) => write!(f, "; {}={}", param.name, value)?,
+                None => write!(f, "; {}", param.name)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Extension {
+    /// Parses the value of a `Sec-WebSocket-Extensions` header into the
+    /// extensions it lists, in order.
+    pub fn parse_list(value: &str) -> Result<Vec<Extension>, WebSocketOtherError> {
+        let mut extensions = Vec::new();
+        for item in value.split(',') {
+            if item.trim().is_empty() {
+                continue;
+            }
+            let mut parts = item.split(';').map(str::trim);
+            let name = parts.next().unwrap_or_default();
+            if name.is_empty() {
+                return Err(WebSocketOtherError::RequestError("Extension without a name"));
+            }
+            let mut params = Vec::new();
+            for param in parts {
+                let (name, value) = match param.split_once('=') {
+                    Some((name, value)) => (name.trim(), Some(value.trim().trim_matches('"').to_string())),
+                    None => (param, None),
+                };
+                if name.is_empty() {
+                    return Err(WebSocketOtherError::RequestError("Extension parameter without a name"));
+                }
+                params.push(Parameter {
+                    name: name.to_string(),
+                    value,
+                });
+            }
+            extensions.push(Extension {
+                name: name.to_string(),
+                params,
+            });
+        }
+        Ok(extensions)
+    }
+}
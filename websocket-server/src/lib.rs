@@ -1,8 +1,22 @@
 use websocket_core::error::WebSocketError;
 
-mod header;
-mod error;
-mod receiver;
-mod sender;
+pub mod header;
+pub mod error;
+pub mod receiver;
+pub mod sender;
+pub mod close;
+pub mod adapter;
+pub mod handshake;
+pub mod websocket;
+pub mod extension;
+pub mod typed;
+pub mod payload;
+pub mod forwarded;
+#[cfg(feature = "stats")]
+pub mod stats;
+#[cfg(any(test, feature = "test-util"))]
+pub mod test_util;
+#[cfg(feature = "prometheus")]
+pub mod metrics;
 
 pub type WebSocketResult<T> = Result<T, WebSocketError>;
\ No newline at end of file
@@ -0,0 +1,2 @@
+//! Exporting connection statistics to monitoring systems.
+pub mod prometheus;
@@ -0,0 +1,98 @@
+//! Connection statistics in the Prometheus text exposition format.
+use std::fmt::Write as _;
+use std::io::{self, Write};
+use std::net::TcpListener;
+use crate::handshake::read_request_head;
+use crate::stats::{HistogramSnapshot, StatsRecorder, StatsSnapshot};
+
+/// Something statistics can be rendered from: one connection's recorder or
+/// a snapshot merged from many.
+pub trait StatsSource {
+    fn stats_snapshot(&self) -> StatsSnapshot;
+}
+
+impl StatsSource for StatsRecorder {
+    fn stats_snapshot(&self) -> StatsSnapshot {
+        self.snapshot()
+    }
+}
+
+impl StatsSource for StatsSnapshot {
+    fn stats_snapshot(&self) -> StatsSnapshot {
+        self.clone()
+    }
+}
+
+/// Renders the statistics of `source`, every metric name starting with
+/// `prefix` and an underscore.
+///
+/// Timings are recorded in microseconds and exported in seconds, as
+/// Prometheus expects.
+pub fn render_prometheus<S>(source: &S, prefix: &str) -> String
+    where
+        S: StatsSource + ?Sized,
+{
+    let stats = source.stats_snapshot();
+    let mut out = String::new();
+    counter(&mut out, prefix, "messages_received_total", "Messages received.", stats.message_size.count);
+    counter(&mut out, prefix, "bytes_received_total", "Payload bytes of received messages.", stats.message_size.sum);
+    histogram(&mut out, prefix, "message_size_bytes", "Payload size of received messages.", &stats.message_size, 1.0);
+    histogram(&mut out, prefix, "frames_per_message", "Frames a received message arrived in.", &stats.frames_per_message, 1.0);
+    histogram(&mut out, prefix, "arrival_gap_seconds", "Time between two received messages.", &stats.arrival_gap_micros, 1e6);
+    histogram(&mut out, prefix, "write_duration_seconds", "Time spent in one send call.", &stats.write_duration_micros, 1e6);
+    out
+}
+
+fn counter(out: &mut String, prefix: &str, name: &str, help: &str, value: u64) {
+    let _ = writeln!(out, "# HELP {prefix}_{name} {help}");
+    let _ = writeln!(out, "# TYPE {prefix}_{name} counter");
+    let _ = writeln!(out, "{prefix}_{name} {value}");
+}
+
+/// Writes a histogram, dividing edges and sum by `divisor` to convert
+/// units. Prometheus buckets are cumulative, ours are not.
+fn histogram(out: &mut String, prefix: &str, name: &str, help: &str, histogram: &HistogramSnapshot, divisor: f64) {
+    let _ = writeln!(out, "# HELP {prefix}_{name} {help}");
+    let _ = writeln!(out, "# TYPE {prefix}_{name} histogram");
+    let mut cumulative = 0;
+    for (edge, bucket) in histogram.edges.iter().zip(&histogram.buckets) {
+        cumulative += bucket;
+        let _ = writeln!(out, "{prefix}_{name}_bucket{{le=\"{}\"}} {cumulative}", *edge as f64 / divisor);
+    }
+    let _ = writeln!(out, "{prefix}_{name}_bucket{{le=\"+Inf\"}} {}", histogram.count);
+    let _ = writeln!(out, "{prefix}_{name}_sum {}", histogram.sum as f64 / divisor);
+    let _ = writeln!(out, "{prefix}_{name}_count {}", histogram.count);
+}
+
+/// Answers `GET /metrics` on `listener` with the rendered statistics of
+/// `source`, one connection at a time, until accepting fails. Anything
+/// else gets a 404. Meant for a dedicated port, not the WebSocket one.
+pub fn serve_metrics<S>(listener: &TcpListener, source: &S, prefix: &str) -> io::Result<()>
+    where
+        S: StatsSource + ?Sized,
+{
+    loop {
+        let (mut stream, _) = listener.accept()?;
+        // a broken scrape only affects that scrape
+        let _ = answer(&mut stream, source, prefix);
+    }
+}
+
+fn answer<S, T>(stream: &mut T, source: &S, prefix: &str) -> io::Result<()>
+    where
+        S: StatsSource + ?Sized,
+        T: io::Read + Write,
+{
+    let request = read_request_head(stream, 8192).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    let (status, body) = if request.head.starts_with(b"GET /metrics ") {
+        ("200 OK", render_prometheus(source, prefix))
+    } else {
+        ("404 Not Found", String::new())
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len(),
+    )?;
+    stream.flush()
+}
@@ -0,0 +1,80 @@
+//! Sources of payload streamed out by `Writer::send_binary_from` without
+//! being loaded into memory first.
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+
+/// A payload of known length, read in chunks as it is sent.
+pub trait PayloadSource {
+    /// How many bytes the payload has. The frame headers are written with
+    /// it, so the source must deliver exactly this many.
+    fn len(&self) -> u64;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Reads the next bytes of the payload into `buf`, returning how many;
+    /// 0 means the source has nothing left.
+    fn read_chunk(&mut self, buf: &mut [u8]) -> io::Result<usize>;
+}
+
+impl PayloadSource for &[u8] {
+    fn len(&self) -> u64 {
+        <[u8]>::len(self) as u64
+    }
+
+    fn read_chunk(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.read(buf)
+    }
+}
+
+/// The whole file, by its metadata length. It is read from its current
+/// position, which should be the start.
+impl PayloadSource for File {
+    fn len(&self) -> u64 {
+        self.metadata().map(|metadata| metadata.len()).unwrap_or(0)
+    }
+
+    fn read_chunk(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.read(buf)
+    }
+}
+
+/// Anything seekable, from its current position to its end.
+pub struct SeekSource<R> {
+    inner: R,
+    len: u64,
+}
+
+impl<R> SeekSource<R>
+    where
+        R: Read + Seek,
+{
+    /// Measures `inner` by seeking to its end and back.
+    pub fn new(mut inner: R) -> io::Result<SeekSource<R>> {
+        let start = inner.stream_position()?;
+        let end = inner.seek(SeekFrom::End(0))?;
+        inner.seek(SeekFrom::Start(start))?;
+        Ok(SeekSource {
+            inner,
+            len: end.saturating_sub(start),
+        })
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R> PayloadSource for SeekSource<R>
+    where
+        R: Read + Seek,
+{
+    fn len(&self) -> u64 {
+        self.len
+    }
+
+    fn read_chunk(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
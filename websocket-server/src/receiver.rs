@@ -1,26 +1,306 @@
+use std::collections::VecDeque;
 use std::io;
 use std::io::{BufReader, Read};
 use std::net::Shutdown;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use websocket_core::budget::{BudgetWait, MemoryBudget};
+use websocket_core::clock::{Clock, SystemClock};
+use websocket_core::compression::Decompressor;
 use websocket_core::action::receiver::{DataFrameIterator, MessageIterator, Receiver as ReceiverAble};
 use websocket_core::dataframe::DataFrame;
+use websocket_core::dedup::DedupFilter;
 use websocket_core::error::WebSocketError;
 use websocket_core::message::Message;
-use websocket_core::protocol::header::Opcode;
-use websocket_core::stream::{AsTcpStream, Stream};
+use websocket_core::protocol::header::{DataFrameFlags, DataFrameHeader, FrameHeader, MaskingImpl, Opcode};
+use websocket_core::protocol::role::Role;
+use websocket_core::stream::{AsTcpStream, PrefixedStream, Stream};
+#[cfg(feature = "stats")]
+use crate::stats::{StatsConfig, StatsRecorder};
 use crate::WebSocketResult;
 
 const DEFAULT_MAX_DATAFRAME_SIZE : usize = 1024*1024*100;
 const DEFAULT_MAX_MESSAGE_SIZE : usize = 1024*1024*200;
 const MAX_DATAFRAMES_IN_ONE_MESSAGE: usize = 1024*1024;
 const PER_DATAFRAME_OVERHEAD : usize = 64;
+const DEFAULT_MAX_POST_CLOSE_FRAMES: usize = 2;
+const DEFAULT_MAX_POST_CLOSE_BYTES: usize = 512;
 
 
+/// Which incoming messages a [`Receiver`] hands out.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DeliveryMode {
+    /// Data messages and control frames, in exact arrival order. A control
+    /// frame arriving between the fragments of a data message is handed out
+    /// before that message completes.
+    #[default]
+    All,
+    /// Data messages and Close only. Pings and pongs are read and dropped,
+    /// so nothing answers pings unless keepalive is handled elsewhere.
+    DataOnly,
+}
+
+/// What a [`Receiver`] does with reserved bits no negotiated extension
+/// gives a meaning to.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ReservedBitsPolicy {
+    /// Fail the message with a protocol error, as RFC6455 5.2 requires.
+    #[default]
+    Reject,
+    /// Clear the bits, count a violation and deliver the frame. Meant for
+    /// known-broken peers only.
+    ClearAndAccept,
+}
+
+/// What [`Receiver::recv_message_filtered`] does with a message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Keep {
+    Deliver,
+    /// Discard the message without buffering it.
+    Skip,
+}
+
+/// What [`Receiver::set_header_hook`] decides about a frame.
+#[derive(Debug)]
+pub enum HeaderAction {
+    /// Read the payload as usual.
+    Proceed,
+    /// Fail the receive with this error without reading the payload.
+    Reject(WebSocketError),
+}
+
+/// Sees every frame header before its payload is read, see
+/// `Receiver::set_header_hook`.
+pub type HeaderHook = dyn FnMut(&DataFrameHeader) -> HeaderAction + Send;
+
+/// The first frame of a data message, as shown to a filter.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FrameInfo {
+    pub opcode: Opcode,
+    pub finished: bool,
+    pub reserved: [bool; 3],
+    pub masked: bool,
+    /// Payload length of this frame, not of the whole message.
+    pub payload_len: u64,
+}
+
+/// Where a frame returned by [`Receiver::recv_frame_event`] sits in the
+/// sequence of frames.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FramePosition {
+    /// A data message sent as a single frame.
+    Whole,
+    /// The first frame of a fragmented data message.
+    Start,
+    Continuation,
+    /// The last frame of a fragmented data message.
+    Final,
+    /// A control frame, possibly between the frames of a data message.
+    Control,
+}
+
+/// A frame as it arrived, see [`Receiver::recv_frame_event`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct FrameEvent {
+    pub frame: DataFrame,
+    pub position: FramePosition,
+}
+
+/// How much [`Receiver::discard_remaining_message`] may read and drop.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DiscardBudget {
+    /// Payload bytes of the message, counting those already buffered.
+    pub max_bytes: u64,
+    /// How long the discard may take by the receiver's clock. It is checked
+    /// between frames, so a silent peer needs a read timeout as well.
+    pub max_duration: Option<Duration>,
+}
+
+impl Default for DiscardBudget {
+    fn default() -> Self {
+        DiscardBudget {
+            max_bytes: 1024 * 1024,
+            max_duration: Some(Duration::from_secs(5)),
+        }
+    }
+}
+
+/// What [`Receiver::discard_remaining_message`] did.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DiscardReport {
+    /// Data frames dropped, counting those already buffered.
+    pub frames: usize,
+    /// Payload bytes dropped, likewise.
+    pub bytes: u64,
+    /// Control frames that arrived meanwhile, in order, for the caller to
+    /// answer as usual. A Close ends the discard, no data can follow it.
+    pub control: Vec<Message>,
+    /// The budget ran out before the message ended. The stream is left in
+    /// the middle of it, so every later receive fails with
+    /// `WebSocketError::Poisoned`.
+    pub exhausted: bool,
+}
+
+/// Copies what is read through it into a capture buffer, up to `limit`
+/// bytes in all.
+struct Recording<'a, R> {
+    inner: &'a mut R,
+    capture: &'a mut Vec<u8>,
+    limit: usize,
+    /// Bytes read so far, captured or not.
+    read: usize,
+}
+
+impl<R> Read for Recording<'_, R>
+    where
+        R: Read,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.read += read;
+        let room = self.limit.saturating_sub(self.capture.len());
+        self.capture.extend_from_slice(&buf[..read.min(room)]);
+        Ok(read)
+    }
+}
+
+/// How a message arrived, see [`Receiver::set_record_metadata`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MessageMeta {
+    /// When the first frame was read, by the receiver's clock.
+    pub first_frame_at: Instant,
+    /// When the last frame was read.
+    pub last_frame_at: Instant,
+    pub frames: usize,
+    /// Bytes the frames took on the wire, headers included.
+    pub wire_bytes: u64,
+    /// Payload bytes as received, before decompression, if the message was
+    /// compressed.
+    pub compressed_wire_bytes: Option<u64>,
+}
+
+/// The configuration of a [`Receiver`], without any per-session state.
+#[derive(Debug, Clone)]
+pub struct ReceiverConfig {
+    /// Whether incoming frames must be masked.
+    pub mask: bool,
+    pub max_dataframe_size: usize,
+    pub max_message_size: usize,
+    /// Limit for text messages; `max_message_size` if unset.
+    pub max_text_message_size: Option<usize>,
+    /// Limit for binary messages; `max_message_size` if unset.
+    pub max_binary_message_size: Option<usize>,
+    /// The implementation used to unmask incoming payloads.
+    pub masking: MaskingImpl,
+    pub delivery_mode: DeliveryMode,
+    /// A budget shared with other receivers, charged for buffered payload.
+    pub memory_budget: Option<Arc<MemoryBudget>>,
+    /// How long to wait for room in `memory_budget` before failing a message.
+    pub budget_wait: Option<BudgetWait>,
+    /// Applied to reserved bits on Close, Ping and Pong frames.
+    pub control_reserved_bits: ReservedBitsPolicy,
+    /// Applied to reserved bits on data frames.
+    pub data_reserved_bits: ReservedBitsPolicy,
+    /// Read and drop Pong frames instead of handing them out.
+    pub swallow_pongs: bool,
+    /// The time source of timestamps and budget waits.
+    pub clock: Arc<dyn Clock>,
+    /// Check that text messages are valid UTF-8.
+    pub validate_utf8: bool,
+    /// Record a [`MessageMeta`] for every delivered message.
+    pub record_metadata: bool,
+    /// Control frames tolerated and dropped after the peer's Close.
+    pub max_post_close_frames: usize,
+    /// Payload bytes tolerated and dropped after the peer's Close.
+    pub max_post_close_bytes: usize,
+    /// Keep up to this many raw bytes of the last frame read.
+    pub raw_frame_capture: Option<usize>,
+}
+
+impl ReceiverConfig {
+    pub fn new(mask: bool) -> ReceiverConfig {
+        ReceiverConfig {
+            mask,
+            max_dataframe_size: DEFAULT_MAX_DATAFRAME_SIZE,
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            max_text_message_size: None,
+            max_binary_message_size: None,
+            masking: MaskingImpl::default(),
+            delivery_mode: DeliveryMode::default(),
+            memory_budget: None,
+            budget_wait: None,
+            control_reserved_bits: ReservedBitsPolicy::default(),
+            data_reserved_bits: ReservedBitsPolicy::default(),
+            swallow_pongs: false,
+            clock: Arc::new(SystemClock),
+            validate_utf8: true,
+            record_metadata: false,
+            max_post_close_frames: DEFAULT_MAX_POST_CLOSE_FRAMES,
+            max_post_close_bytes: DEFAULT_MAX_POST_CLOSE_BYTES,
+            raw_frame_capture: None,
+        }
+    }
+}
 
 pub struct Receiver {
     buffer: Vec<DataFrame>,
     mask: bool,
     max_dataframe_size: u32,
     max_message_size: u32,
+    max_text_message_size: Option<usize>,
+    max_binary_message_size: Option<usize>,
+    masking: MaskingImpl,
+    delivery_mode: DeliveryMode,
+    memory_budget: Option<Arc<MemoryBudget>>,
+    budget_wait: Option<BudgetWait>,
+    /// Bytes charged to `memory_budget` for the frames in `buffer`.
+    charged: usize,
+    /// The peer's Close frame, once received.
+    received_close: Option<DataFrame>,
+    max_post_close_frames: usize,
+    max_post_close_bytes: usize,
+    /// Control frames, and their payload bytes, dropped after the peer's
+    /// Close.
+    post_close_frames: usize,
+    post_close_bytes: usize,
+    decompressor: Option<Box<dyn Decompressor>>,
+    control_reserved_bits: ReservedBitsPolicy,
+    data_reserved_bits: ReservedBitsPolicy,
+    reserved_bits_violations: u64,
+    swallow_pongs: bool,
+    last_pong_at: Option<Instant>,
+    clock: Arc<dyn Clock>,
+    dedup: Option<DedupFilter>,
+    validate_utf8: bool,
+    /// The opcode of a message `recv_message_filtered` is skipping.
+    skipping: Option<Opcode>,
+    /// Control messages that arrived while `recv_message_filtered` skipped
+    /// a message, to be delivered next.
+    pending_control: VecDeque<Message>,
+    /// The bytes `recv_message_filtered` peeks at, kept so that skipping a
+    /// message doesn't allocate.
+    peek_buffer: Vec<u8>,
+    /// Whether `recv_frame_event` is inside a fragmented data message.
+    in_fragmented: bool,
+    record_metadata: bool,
+    /// How the data message being received arrived so far.
+    message_meta: Option<MessageMeta>,
+    /// How a control frame about to be delivered arrived.
+    control_meta: Option<MessageMeta>,
+    last_message_meta: Option<MessageMeta>,
+    /// Whether the message being received is compressed and inflated
+    /// frame by frame.
+    inflating: bool,
+    header_hook: Option<Box<HeaderHook>>,
+    raw_frame_capture: Option<usize>,
+    /// The first `raw_frame_capture` bytes of the last frame read, as
+    /// received.
+    raw_frame: Vec<u8>,
+    /// Set once a discard gave up in the middle of a message, or a frame
+    /// was rejected by the header hook.
+    poisoned: bool,
+    #[cfg(feature = "stats")]
+    stats: Option<Arc<StatsRecorder>>,
 }
 
 impl Receiver {
@@ -36,29 +316,870 @@ impl Receiver {
             mask,
             max_dataframe_size,
             max_message_size,
+            max_text_message_size: None,
+            max_binary_message_size: None,
+            masking: MaskingImpl::default(),
+            delivery_mode: DeliveryMode::default(),
+            memory_budget: None,
+            budget_wait: None,
+            charged: 0,
+            received_close: None,
+            max_post_close_frames: DEFAULT_MAX_POST_CLOSE_FRAMES,
+            max_post_close_bytes: DEFAULT_MAX_POST_CLOSE_BYTES,
+            post_close_frames: 0,
+            post_close_bytes: 0,
+            decompressor: None,
+            control_reserved_bits: ReservedBitsPolicy::default(),
+            data_reserved_bits: ReservedBitsPolicy::default(),
+            reserved_bits_violations: 0,
+            swallow_pongs: false,
+            last_pong_at: None,
+            clock: Arc::new(SystemClock),
+            dedup: None,
+            validate_utf8: true,
+            skipping: None,
+            pending_control: VecDeque::new(),
+            peek_buffer: Vec::new(),
+            in_fragmented: false,
+            record_metadata: false,
+            message_meta: None,
+            control_meta: None,
+            last_message_meta: None,
+            inflating: false,
+            header_hook: None,
+            raw_frame_capture: None,
+            raw_frame: Vec::new(),
+            poisoned: false,
+            #[cfg(feature = "stats")]
+            stats: None,
         }
     }
-}
 
-impl ReceiverAble for Receiver {
-    type F = DataFrame;
+    /// Creates a receiver from a configuration, e.g. one taken with
+    /// [`Receiver::snapshot_config`] from another receiver.
+    pub fn from_config(config: ReceiverConfig) -> Receiver {
+        let mut receiver = Receiver::new_with_limits(config.mask, config.max_dataframe_size, config.max_message_size);
+        receiver.max_text_message_size = config.max_text_message_size;
+        receiver.max_binary_message_size = config.max_binary_message_size;
+        receiver.masking = config.masking;
+        receiver.delivery_mode = config.delivery_mode;
+        receiver.memory_budget = config.memory_budget;
+        receiver.budget_wait = config.budget_wait;
+        receiver.control_reserved_bits = config.control_reserved_bits;
+        receiver.data_reserved_bits = config.data_reserved_bits;
+        receiver.swallow_pongs = config.swallow_pongs;
+        receiver.clock = config.clock;
+        receiver.validate_utf8 = config.validate_utf8;
+        receiver.record_metadata = config.record_metadata;
+        receiver.max_post_close_frames = config.max_post_close_frames;
+        receiver.max_post_close_bytes = config.max_post_close_bytes;
+        receiver.raw_frame_capture = config.raw_frame_capture;
+        receiver
+    }
 
-    type M = Message;
+    /// Returns the configuration of this receiver.
+    pub fn snapshot_config(&self) -> ReceiverConfig {
+        ReceiverConfig {
+            mask: self.mask,
+            max_dataframe_size: self.max_dataframe_size as usize,
+            max_message_size: self.max_message_size as usize,
+            max_text_message_size: self.max_text_message_size,
+            max_binary_message_size: self.max_binary_message_size,
+            masking: self.masking,
+            delivery_mode: self.delivery_mode,
+            memory_budget: self.memory_budget.clone(),
+            budget_wait: self.budget_wait,
+            control_reserved_bits: self.control_reserved_bits,
+            data_reserved_bits: self.data_reserved_bits,
+            swallow_pongs: self.swallow_pongs,
+            clock: self.clock.clone(),
+            validate_utf8: self.validate_utf8,
+            record_metadata: self.record_metadata,
+            max_post_close_frames: self.max_post_close_frames,
+            max_post_close_bytes: self.max_post_close_bytes,
+            raw_frame_capture: self.raw_frame_capture,
+        }
+    }
 
-    fn recv_dataframe<R>(&mut self, reader: &mut R) -> WebSocketResult<DataFrame>
+    /// Selects the implementation used to unmask incoming payloads.
+    pub fn set_masking(&mut self, masking: MaskingImpl) {
+        self.masking = masking;
+    }
+
+    pub fn masking(&self) -> MaskingImpl {
+        self.masking
+    }
+
+    /// Selects which incoming messages are handed out.
+    pub fn set_delivery_mode(&mut self, delivery_mode: DeliveryMode) {
+        self.delivery_mode = delivery_mode;
+    }
+
+    pub fn delivery_mode(&self) -> DeliveryMode {
+        self.delivery_mode
+    }
+
+    /// Reads and drops Pong frames instead of handing them out. They still
+    /// update [`Receiver::last_pong_at`].
+    pub fn set_swallow_pongs(&mut self, swallow_pongs: bool) {
+        self.swallow_pongs = swallow_pongs;
+    }
+
+    pub fn swallow_pongs(&self) -> bool {
+        self.swallow_pongs
+    }
+
+    /// When the last Pong frame was read, whether it was handed out or not.
+    pub fn last_pong_at(&self) -> Option<Instant> {
+        self.last_pong_at
+    }
+
+    /// Replaces the system clock, e.g. with a `SimClock` in tests.
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+    }
+
+    pub fn clock(&self) -> &Arc<dyn Clock> {
+        &self.clock
+    }
+
+    /// Turns the UTF-8 check of text messages off or on. With it off, text
+    /// payloads are handed out as received, which RFC6455 doesn't allow;
+    /// only do it if text is rejected further up anyway.
+    pub fn set_validate_utf8(&mut self, validate_utf8: bool) {
+        self.validate_utf8 = validate_utf8;
+    }
+
+    pub fn validate_utf8(&self) -> bool {
+        self.validate_utf8
+    }
+
+    /// Drops received messages the filter has already seen. Like the
+    /// decompressor, the filter is per-connection state and not part of
+    /// [`ReceiverConfig`].
+    pub fn set_dedup_filter(&mut self, dedup: Option<DedupFilter>) {
+        self.dedup = dedup;
+    }
+
+    pub fn dedup_filter(&self) -> Option<&DedupFilter> {
+        self.dedup.as_ref()
+    }
+
+    /// Charges buffered payload to a budget shared with other receivers.
+    /// Must be set while no message is partially received.
+    pub fn set_memory_budget(&mut self, budget: Option<Arc<MemoryBudget>>, wait: Option<BudgetWait>) {
+        debug_assert!(self.buffer.is_empty(), "memory budget changed mid-message");
+        self.memory_budget = budget;
+        self.budget_wait = wait;
+    }
+
+    /// Sets separate size limits for text and binary messages. An unset
+    /// limit falls back to the overall maximum message size.
+    pub fn set_message_size_limits(&mut self, text: Option<usize>, binary: Option<usize>) {
+        self.max_text_message_size = text;
+        self.max_binary_message_size = binary;
+    }
+
+    /// The size limit of a message starting with a frame of `opcode`.
+    pub fn message_size_limit(&self, opcode: Opcode) -> usize {
+        let limit = match opcode {
+            Opcode::Text => self.max_text_message_size,
+            Opcode::Binary => self.max_binary_message_size,
+            _ => None,
+        };
+        limit.unwrap_or(self.max_message_size as usize)
+    }
+
+    /// Selects what happens to unexpected reserved bits, separately for
+    /// control and data frames.
+    pub fn set_reserved_bits_policy(&mut self, control: ReservedBitsPolicy, data: ReservedBitsPolicy) {
+        self.control_reserved_bits = control;
+        self.data_reserved_bits = data;
+    }
+
+    /// How many frames had reserved bits cleared under
+    /// [`ReservedBitsPolicy::ClearAndAccept`].
+    pub fn reserved_bits_violations(&self) -> u64 {
+        self.reserved_bits_violations
+    }
+
+    /// Applies the reserved bits policy to a frame about to be delivered.
+    /// `first` tells whether the frame starts a message.
+    fn check_reserved_bits(&mut self, frame: &mut DataFrame, first: bool) -> WebSocketResult<()> {
+        frame.reserved = self.check_reserved(frame.reserved, frame.opcode as u8, first)?;
+        Ok(())
+    }
+
+    /// Applies the reserved bits policy to the bits of a frame, returning
+    /// them as the frame should carry them on.
+    fn check_reserved(&mut self, reserved: [bool; 3], opcode: u8, first: bool) -> WebSocketResult<[bool; 3]> {
+        let is_control = opcode & 0x8 != 0;
+        let mut unexpected = reserved;
+        // RSV1 on the first frame of a data message marks it as compressed
+        if first && !is_control && self.decompressor.is_some() {
+            unexpected[0] = false;
+        }
+        if unexpected == [false; 3] {
+            return Ok(reserved);
+        }
+        let policy = if is_control { self.control_reserved_bits } else { self.data_reserved_bits };
+        match policy {
+            ReservedBitsPolicy::Reject => Err(WebSocketError::ProtocolError(
+                "Unsupported reserved bits received",
+            )),
+            ReservedBitsPolicy::ClearAndAccept => {
+                self.reserved_bits_violations += 1;
+                Ok([0, 1, 2].map(|i| reserved[i] && !unexpected[i]))
+            }
+        }
+    }
+
+    /// Records a [`MessageMeta`] for every delivered message, retrieved
+    /// with [`Receiver::last_message_meta`]. Off by default; when off, the
+    /// clock isn't read for it.
+    pub fn set_record_metadata(&mut self, record_metadata: bool) {
+        self.record_metadata = record_metadata;
+        if !record_metadata {
+            self.message_meta = None;
+            self.control_meta = None;
+            self.last_message_meta = None;
+        }
+    }
+
+    pub fn record_metadata(&self) -> bool {
+        self.record_metadata
+    }
+
+    /// Keeps a copy of up to `limit` bytes of each frame as read, header
+    /// and still masked payload, replaced by the next frame's; see
+    /// [`Receiver::last_raw_frame`]. Off with `None`, the default.
+    pub fn set_raw_frame_capture(&mut self, limit: Option<usize>) {
+        self.raw_frame_capture = limit;
+        if limit.is_none() {
+            self.raw_frame = Vec::new();
+        }
+    }
+
+    pub fn raw_frame_capture(&self) -> Option<usize> {
+        self.raw_frame_capture
+    }
+
+    /// The raw bytes of the last frame read, up to the capture limit, if
+    /// capture is on. After a failed receive these are the bytes of the
+    /// frame that failed, as far as it was read. Payloads of frames skipped
+    /// without reading them, e.g. by `recv_message_filtered`, aren't kept.
+    pub fn last_raw_frame(&self) -> Option<&[u8]> {
+        self.raw_frame_capture.map(|_| self.raw_frame.as_slice())
+    }
+
+    /// Wraps `reader` so what is read from it is captured, if capture is on.
+    fn recording<'a, R>(&'a mut self, reader: &'a mut R) -> Recording<'a, R> {
+        Recording {
+            inner: reader,
+            limit: self.raw_frame_capture.unwrap_or(0),
+            capture: &mut self.raw_frame,
+            read: 0,
+        }
+    }
+
+    /// How the message last returned arrived, if metadata is recorded.
+    pub fn last_message_meta(&self) -> Option<&MessageMeta> {
+        self.last_message_meta.as_ref()
+    }
+
+    /// Accounts a frame read from the wire, before it is decompressed.
+    /// `first` tells whether it starts a message; control frames are
+    /// messages of their own.
+    fn note_frame(&mut self, frame: &DataFrame, first: bool) {
+        if !self.record_metadata {
+            return;
+        }
+        let now = self.clock.now();
+        let len = frame.data.len() as u64;
+        let extended = match len {
+            0..=125 => 0,
+            126..=0xffff => 2,
+            _ => 8,
+        };
+        let wire_bytes = 2 + extended + if frame.mask.is_some() { 4 } else { 0 } + len;
+        let frame_meta = MessageMeta {
+            first_frame_at: now,
+            last_frame_at: now,
+            frames: 1,
+            wire_bytes,
+            compressed_wire_bytes: if frame.reserved[0] { Some(len) } else { None },
+        };
+        if frame.opcode as u8 & 0x8 != 0 {
+            self.control_meta = Some(frame_meta);
+            return;
+        }
+        match self.message_meta {
+            Some(ref mut meta) if !first => {
+                meta.last_frame_at = now;
+                meta.frames += 1;
+                meta.wire_bytes += wire_bytes;
+                if let Some(ref mut compressed) = meta.compressed_wire_bytes {
+                    *compressed += len;
+                }
+            }
+            _ => self.message_meta = Some(frame_meta),
+        }
+    }
+
+    /// Decompresses incoming messages that have RSV1 set. The decompressor
+    /// holds per-connection state, so it isn't part of [`ReceiverConfig`].
+    pub fn set_decompressor(&mut self, decompressor: Option<Box<dyn Decompressor>>) {
+        self.decompressor = decompressor;
+    }
+
+    /// Calls `hook` with the header of every frame read, data or control,
+    /// before its payload is read, e.g. to apply limits that depend on
+    /// memory pressure at the time. A frame it rejects fails the receive
+    /// with the hook's error; its payload is left unread, so the receiver
+    /// is poisoned and the connection can only be closed. The hook may keep
+    /// state of its own, so it isn't part of [`ReceiverConfig`].
+    pub fn set_header_hook(&mut self, hook: Option<Box<HeaderHook>>) {
+        self.header_hook = hook;
+    }
+
+    /// Replaces the payload of a data frame of a compressed message with
+    /// its decompressed form, so a message is inflated as its frames arrive
+    /// instead of all at once. `first` tells whether the frame starts the
+    /// message. The message size limit applies to the total decompressed
+    /// so far, including the frames in `buffer`.
+    fn inflate_frame(&mut self, frame: &mut DataFrame, first: bool) -> WebSocketResult<()> {
+        if first {
+            self.inflating = frame.reserved[0]
+                && matches!(frame.opcode, Opcode::Text | Opcode::Binary)
+                && self.decompressor.is_some();
+        }
+        if !self.inflating {
+            return Ok(());
+        }
+        let opcode = if first { frame.opcode } else { self.buffer[0].opcode };
+        let inflated: usize = if first { 0 } else { self.buffer.iter().map(|x| x.data.len()).sum() };
+        let max_len = self.message_size_limit(opcode).saturating_sub(inflated);
+        let mut data = Vec::new();
+        if let Some(ref mut decompressor) = self.decompressor {
+            decompressor.decompress_chunk(&frame.data, &mut data, max_len)?;
+            if frame.finished {
+                decompressor.finish_message(&mut data, max_len)?;
+            }
+        }
+        frame.data = data;
+        frame.reserved[0] = false;
+        Ok(())
+    }
+
+    fn buffer_frame(&mut self, frame: DataFrame) -> WebSocketResult<()> {
+        if let Some(ref budget) = self.memory_budget {
+            let len = frame.data.len();
+            if !budget.charge_with_clock(len, self.budget_wait, &*self.clock) {
+                self.take_buffer();
+                return Err(WebSocketError::GlobalMemoryPressure);
+            }
+            self.charged += len;
+        }
+        self.buffer.push(frame);
+        Ok(())
+    }
+
+    fn take_buffer(&mut self) -> Vec<DataFrame> {
+        if let Some(ref budget) = self.memory_budget {
+            budget.credit(self.charged);
+        }
+        self.charged = 0;
+        std::mem::take(&mut self.buffer)
+    }
+
+    fn is_dropped(&self, frame: &DataFrame) -> bool {
+        match frame.opcode {
+            Opcode::Ping => self.delivery_mode == DeliveryMode::DataOnly,
+            Opcode::Pong => self.delivery_mode == DeliveryMode::DataOnly || self.swallow_pongs,
+            _ => false,
+        }
+    }
+
+    /// Drops all state of the current logical session while keeping the
+    /// configuration, so the same receiver can decode another session
+    /// arriving on the same byte stream. This includes the decompressor's
+    /// context, the ids remembered by the dedup filter and the counters.
+    pub fn reset_session(&mut self) {
+        self.take_buffer();
+        self.skipping = None;
+        self.pending_control.clear();
+        self.in_fragmented = false;
+        self.inflating = false;
+        if let Some(decompressor) = self.decompressor.as_mut() {
+            decompressor.reset();
+        }
+        if let Some(dedup) = self.dedup.as_mut() {
+            dedup.clear();
+        }
+        self.received_close = None;
+        self.post_close_frames = 0;
+        self.post_close_bytes = 0;
+        self.reserved_bits_violations = 0;
+        self.last_pong_at = None;
+        self.message_meta = None;
+        self.control_meta = None;
+        self.last_message_meta = None;
+        self.raw_frame.clear();
+    }
+
+    /// Whether a message was left unfinished by
+    /// [`Receiver::discard_remaining_message`] running out of budget, or a
+    /// frame rejected by the header hook. Nothing can be received any more
+    /// then.
+    pub fn is_poisoned(&self) -> bool {
+        self.poisoned
+    }
+
+    fn check_poisoned(&self) -> WebSocketResult<()> {
+        if self.poisoned {
+            return Err(WebSocketError::Poisoned);
+        }
+        Ok(())
+    }
+
+    /// Reads and drops the rest of the data message being received, e.g.
+    /// after a receive failed or the caller lost interest in it, leaving the
+    /// stream at the start of the next message. Buffered frames of it are
+    /// dropped too, as is a message `recv_message_filtered` is skipping.
+    /// Does nothing if no message is in progress.
+    ///
+    /// Payloads are discarded without being buffered. A compressed message
+    /// is still inflated, into a scratch buffer, as the peer's compression
+    /// context depends on it. Control frames arriving meanwhile are
+    /// collected in the report. If `budget` runs out first the receiver is
+    /// poisoned and the report says so.
+    pub fn discard_remaining_message<R>(&mut self, reader: &mut R, budget: DiscardBudget) -> WebSocketResult<DiscardReport>
         where
             R: Read,
     {
-        DataFrame::read_dataframe_with_limit(reader, self.mask, self.max_dataframe_size as usize)
+        self.check_poisoned()?;
+        let buffered = self.take_buffer();
+        let mut report = DiscardReport {
+            frames: buffered.len(),
+            bytes: buffered.iter().map(|x| x.data.len() as u64).sum(),
+            ..DiscardReport::default()
+        };
+        let opcode = buffered.first().map(|x| x.opcode);
+        let skipping = self.skipping.take();
+        let in_fragmented = std::mem::take(&mut self.in_fragmented);
+        self.message_meta = None;
+        if opcode.is_none() && skipping.is_none() && !in_fragmented {
+            return Ok(report);
+        }
+        let opcode = opcode.or(skipping).unwrap_or(Opcode::Binary);
+
+        let started = self.clock.now();
+        loop {
+            let out_of_time = budget
+                .max_duration
+                .is_some_and(|max| self.clock.now().saturating_duration_since(started) >= max);
+            if out_of_time {
+                break;
+            }
+            let header = self.read_header(reader)?;
+            let finished = header.flags.contains(DataFrameFlags::FIN);
+            if header.opcode & 0x8 != 0 {
+                let frame = self.read_body(reader, header, Vec::new())?;
+                if self.dropped_after_close(&frame)? {
+                    continue;
+                }
+                let mut frame = self.observe(Ok(frame))?;
+                let closing = frame.opcode == Opcode::Close;
+                if !self.is_dropped(&frame) {
+                    self.check_reserved_bits(&mut frame, true)?;
+                    report.control.push(Message::from_dataframes_validating(vec![frame], true)?);
+                }
+                if closing {
+                    self.inflating = false;
+                    return Ok(report);
+                }
+                continue;
+            }
+            if header.opcode != Opcode::Continuation as u8 {
+                return Err(WebSocketError::ProtocolError("Unexpected data frame opcode"));
+            }
+            self.check_reserved(header.flags.reserved(), header.opcode, false)?;
+            let len = header.len;
+            if report.bytes + len > budget.max_bytes {
+                break;
+            }
+            if self.inflating {
+                let frame = self.read_body(reader, header, Vec::new())?;
+                let max_len = self.message_size_limit(opcode);
+                let mut scratch = Vec::new();
+                if let Some(ref mut decompressor) = self.decompressor {
+                    decompressor.decompress_chunk(&frame.data, &mut scratch, max_len)?;
+                    if finished {
+                        decompressor.finish_message(&mut scratch, max_len)?;
+                    }
+                }
+            } else {
+                Receiver::discard(reader, len)?;
+            }
+            report.frames += 1;
+            report.bytes += len;
+            if finished {
+                self.inflating = false;
+                return Ok(report);
+            }
+        }
+        self.poisoned = true;
+        report.exhausted = true;
+        Ok(report)
     }
 
-    fn recv_message_dataframes<R>(&mut self, reader: &mut R) -> WebSocketResult<Vec<DataFrame>>
+    /// Whether the peer's Close frame has been received.
+    pub fn close_received(&self) -> bool {
+        self.received_close.is_some()
+    }
+
+    /// The peer's Close frame, once received.
+    pub fn received_close(&self) -> Option<&DataFrame> {
+        self.received_close.as_ref()
+    }
+
+    /// How many control frames, with how many payload bytes in all, may
+    /// follow the peer's Close before that fails the connection. Some peers
+    /// send their Close twice or a last Ping after it; within the limits
+    /// these are dropped and counted in
+    /// [`Receiver::post_close_frames`]. A data frame after the Close always
+    /// fails. `(0, 0)` tolerates nothing.
+    pub fn set_post_close_tolerance(&mut self, max_frames: usize, max_bytes: usize) {
+        self.max_post_close_frames = max_frames;
+        self.max_post_close_bytes = max_bytes;
+    }
+
+    /// How many control frames were dropped after the peer's Close.
+    pub fn post_close_frames(&self) -> usize {
+        self.post_close_frames
+    }
+
+    /// Whether `frame`, just read, is to be dropped because it followed the
+    /// peer's Close. Fails if it may not follow it at all.
+    fn dropped_after_close(&mut self, frame: &DataFrame) -> WebSocketResult<bool> {
+        if self.received_close.is_none() {
+            return Ok(false);
+        }
+        if frame.opcode as u8 & 0x8 == 0 {
+            return Err(WebSocketError::ProtocolError("Data frame received after Close"));
+        }
+        self.post_close_frames += 1;
+        self.post_close_bytes += frame.data.len();
+        if self.post_close_frames > self.max_post_close_frames || self.post_close_bytes > self.max_post_close_bytes {
+            return Err(WebSocketError::ProtocolError("Frame received after Close"));
+        }
+        Ok(true)
+    }
+
+    /// Frames of a partially received message that are still buffered.
+    pub fn pending_frames(&self) -> &[DataFrame] {
+        &self.buffer
+    }
+
+    /// Takes the buffered frames of a partially received message, leaving
+    /// the receiver ready to start a new message.
+    pub fn take_pending(&mut self) -> Vec<DataFrame> {
+        self.take_buffer()
+    }
+
+    /// Tracks what a frame read from the peer means for the session.
+    fn observe(&mut self, result: WebSocketResult<DataFrame>) -> WebSocketResult<DataFrame> {
+        match result {
+            Ok(ref frame) if frame.opcode == Opcode::Close => {
+                self.received_close = Some(frame.clone());
+                result
+            }
+            Ok(ref frame) if frame.opcode == Opcode::Pong => {
+                self.last_pong_at = Some(self.clock.now());
+                result
+            }
+            _ => result,
+        }
+    }
+
+    /// Turns the frames of one message into a message, or `None` if the
+    /// dedup filter drops it.
+    fn assemble(&mut self, dataframes: Vec<DataFrame>) -> WebSocketResult<Option<Message>> {
+        #[cfg(feature = "stats")]
+        let frames = dataframes.len();
+        if self.record_metadata {
+            let control = dataframes.first().is_some_and(|x| x.opcode as u8 & 0x8 != 0);
+            self.last_message_meta = if control { self.control_meta.take() } else { self.message_meta.take() };
+        }
+        let message = Message::from_dataframes_validating(dataframes, self.validate_utf8)?;
+        #[cfg(feature = "stats")]
+        if let Some(ref stats) = self.stats {
+            stats.record_message(message.payload.len(), frames, self.clock.now());
+        }
+        if let Some(ref mut dedup) = self.dedup {
+            if !dedup.check(&message) {
+                return Ok(None);
+            }
+        }
+        Ok(Some(message))
+    }
+
+    /// Reads a frame header, checks it and shows it to the header hook.
+    fn read_header<R>(&mut self, reader: &mut R) -> WebSocketResult<DataFrameHeader>
+        where
+            R: Read,
+    {
+        self.check_poisoned()?;
+        self.raw_frame.clear();
+        let mut recording = self.recording(reader);
+        let result = DataFrameHeader::read(&mut recording);
+        let at_boundary = recording.read == 0;
+        let header = match result {
+            // a peer that half-closes after its Close is done, not broken;
+            // one that stops in the middle of a frame is
+            Err(WebSocketError::Io(ref e))
+                if at_boundary && self.received_close.is_some() && e.kind() == io::ErrorKind::UnexpectedEof =>
+            {
+                return Err(WebSocketError::PeerFinishedSending);
+            }
+            result => result?,
+        };
+        if header.mask.is_some() != self.mask {
+            return Err(WebSocketError::DataFrameError(if self.mask {
+                "Expected masked data frame"
+            } else {
+                "Expected unmasked data frame"
+            }));
+        }
+        if header.opcode & 0x8 == 0 && self.received_close.is_some() {
+            return Err(WebSocketError::ProtocolError("Data frame received after Close"));
+        }
+        if header.opcode & 0x8 == 0 && header.len > self.max_dataframe_size as u64 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "exceeded DataFrame length limit").into());
+        }
+        if let Some(ref mut hook) = self.header_hook {
+            if let HeaderAction::Reject(error) = hook(&header) {
+                self.poisoned = true;
+                return Err(error);
+            }
+        }
+        Ok(header)
+    }
+
+    /// Reads the rest of a frame whose first `data.len()` payload bytes
+    /// were already read and unmasked. The frame isn't observed yet.
+    fn read_body<R>(&mut self, reader: &mut R, header: DataFrameHeader, mut data: Vec<u8>) -> WebSocketResult<DataFrame>
+        where
+            R: Read,
+    {
+        let prefix = data.len();
+        DataFrame::read_payload_into(&mut self.recording(reader), header.len - prefix as u64, &mut data)?;
+        if let Some(mask) = header.mask {
+            self.masking.apply(mask, prefix, &mut data[prefix..]);
+        }
+        let frame = DataFrame {
+            finished: header.flags.contains(DataFrameFlags::FIN),
+            reserved: header.flags.reserved(),
+            opcode: Opcode::new(header.opcode).ok_or(WebSocketError::ProtocolError("Unsupported opcode received"))?,
+            data,
+            mask: header.mask,
+        };
+        Ok(frame)
+    }
+
+    /// Reads and drops `len` payload bytes without buffering them.
+    fn discard<R>(reader: &mut R, len: u64) -> WebSocketResult<()>
+        where
+            R: Read,
+    {
+        let discarded = io::copy(&mut reader.take(len), &mut io::sink())?;
+        if discarded < len {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "incomplete payload").into());
+        }
+        Ok(())
+    }
+
+    /// Receives the next frame, data or control, in the order it arrived,
+    /// for relaying traffic as it was sent.
+    ///
+    /// Frames are checked for their place in the sequence and for reserved
+    /// bits but otherwise returned raw: they are not decompressed, nothing
+    /// is dropped by the delivery mode or `swallow_pongs` and no message
+    /// size limit applies. Don't mix this with the message-level receive
+    /// methods in the middle of a message.
+    pub fn recv_frame_event<R>(&mut self, reader: &mut R) -> WebSocketResult<FrameEvent>
+        where
+            R: Read,
+    {
+        let mut frame = self.recv_dataframe(reader)?;
+        // the header reader rejects control frames without FIN
+        let position = if frame.opcode as u8 & 0x8 != 0 {
+            FramePosition::Control
+        } else if frame.opcode == Opcode::Continuation {
+            if !self.in_fragmented {
+                return Err(WebSocketError::ProtocolError("Unexpected continuation data frame opcode"));
+            }
+            if frame.finished {
+                self.in_fragmented = false;
+                FramePosition::Final
+            } else {
+                FramePosition::Continuation
+            }
+        } else {
+            if self.in_fragmented {
+                return Err(WebSocketError::ProtocolError("Unexpected data frame opcode"));
+            }
+            if frame.finished {
+                FramePosition::Whole
+            } else {
+                self.in_fragmented = true;
+                FramePosition::Start
+            }
+        };
+        let first = matches!(position, FramePosition::Whole | FramePosition::Start | FramePosition::Control);
+        self.check_reserved_bits(&mut frame, first)?;
+        Ok(FrameEvent { frame, position })
+    }
+
+    /// Receives the next message `filter` keeps. The filter sees the first
+    /// frame of each data message and up to `peek_len` of its payload bytes,
+    /// unmasked but still compressed if compression is on. Skipped messages
+    /// are dropped with [`Receiver::discard_remaining_message`], with the
+    /// message size limit as its budget: going over it fails with
+    /// `MessageTooLarge` and poisons the receiver. Control frames are handled
+    /// as by `recv_message`, those arriving during a discard are delivered
+    /// by the next calls.
+    pub fn recv_message_filtered<R, F>(&mut self, reader: &mut R, peek_len: usize, mut filter: F) -> WebSocketResult<Message>
+        where
+            R: Read,
+            F: FnMut(&FrameInfo, &[u8]) -> Keep,
+    {
+        loop {
+            if let Some(message) = self.pending_control.pop_front() {
+                return Ok(message);
+            }
+            if !self.buffer.is_empty() {
+                // a kept message still being assembled
+                let dataframes = self.recv_message_dataframes(reader)?;
+                if let Some(message) = self.assemble(dataframes)? {
+                    return Ok(message);
+                }
+                continue;
+            }
+
+            let header = self.read_header(reader)?;
+            let finished = header.flags.contains(DataFrameFlags::FIN);
+
+            if header.opcode & 0x8 != 0 {
+                let frame = self.read_body(reader, header, Vec::new())?;
+                if self.dropped_after_close(&frame)? {
+                    continue;
+                }
+                let mut frame = self.observe(Ok(frame))?;
+                if self.is_dropped(&frame) {
+                    continue;
+                }
+                self.check_reserved_bits(&mut frame, true)?;
+                self.note_frame(&frame, true);
+                if let Some(message) = self.assemble(vec![frame])? {
+                    return Ok(message);
+                }
+                continue;
+            }
+
+            if header.opcode == Opcode::Continuation as u8 {
+                return Err(WebSocketError::ProtocolError("Unexpected continuation data frame opcode"));
+            }
+            // checked before the filter sees the frame, so a skipped message
+            // gets no further than a delivered one
+            let opcode = Opcode::new(header.opcode).ok_or(WebSocketError::ProtocolError("Unsupported opcode received"))?;
+            let limit = self.message_size_limit(opcode);
+            if header.len > limit as u64 {
+                return Err(WebSocketError::MessageTooLarge { limit });
+            }
+            let reserved = self.check_reserved(header.flags.reserved(), header.opcode, true)?;
+
+            let mut peeked = std::mem::take(&mut self.peek_buffer);
+            peeked.clear();
+            peeked.resize(peek_len.min(header.len as usize), 0);
+            self.recording(reader).read_exact(&mut peeked)?;
+            if let Some(mask) = header.mask {
+                self.masking.apply(mask, 0, &mut peeked);
+            }
+            let info = FrameInfo {
+                opcode,
+                finished,
+                reserved,
+                masked: header.mask.is_some(),
+                payload_len: header.len,
+            };
+            let compressed = reserved[0] && matches!(opcode, Opcode::Text | Opcode::Binary) && self.decompressor.is_some();
+            let keep = filter(&info, &peeked);
+            let peeked_len = peeked.len() as u64;
+            // the peeked bytes start the payload unless it is discarded raw
+            let prefix = if keep == Keep::Skip && !compressed { Vec::new() } else { peeked.clone() };
+            self.peek_buffer = peeked;
+
+            match keep {
+                Keep::Skip => {
+                    if compressed {
+                        // the peer's compression context covers skipped
+                        // messages too
+                        let mut frame = self.read_body(reader, header, prefix)?;
+                        frame.reserved = reserved;
+                        self.inflate_frame(&mut frame, true)?;
+                    } else {
+                        Receiver::discard(reader, header.len - peeked_len)?;
+                    }
+                    if !finished {
+                        self.skipping = Some(opcode);
+                        let budget = DiscardBudget {
+                            max_bytes: (limit as u64).saturating_sub(info.payload_len),
+                            max_duration: None,
+                        };
+                        let report = self.discard_remaining_message(reader, budget)?;
+                        self.pending_control.extend(report.control);
+                        if report.exhausted {
+                            return Err(WebSocketError::MessageTooLarge { limit });
+                        }
+                    }
+                }
+                Keep::Deliver => {
+                    let frame = self.read_body(reader, header, prefix)?;
+                    let mut frame = self.observe(Ok(frame))?;
+                    frame.reserved = reserved;
+                    self.note_frame(&frame, true);
+                    self.inflate_frame(&mut frame, true)?;
+                    if finished {
+                        if let Some(message) = self.assemble(vec![frame])? {
+                            return Ok(message);
+                        }
+                    } else {
+                        self.buffer_frame(frame)?;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Reads frames until a message or a control frame is complete.
+    /// Frames of a message interrupted by a control frame stay buffered
+    /// until the next call.
+    fn read_message_dataframes<R>(&mut self, reader: &mut R) -> WebSocketResult<Vec<DataFrame>>
         where
             R: Read,
     {
         let mut current_message_length : usize = self.buffer.iter().map(|x|x.data.len()).sum();
+        let mut payload_length = current_message_length;
         let mut finished = if self.buffer.is_empty() {
-            let first = self.recv_dataframe(reader)?;
+            let mut first = loop {
+                let frame = self.recv_dataframe(reader)?;
+                if !self.is_dropped(&frame) {
+                    break frame;
+                }
+            };
+            self.check_reserved_bits(&mut first, true)?;
+            self.note_frame(&first, true);
 
             if first.opcode == Opcode::Continuation {
                 return Err(WebSocketError::ProtocolError(
@@ -67,25 +1188,42 @@ impl ReceiverAble for Receiver {
             }
 
             let finished = first.finished;
+            if first.opcode as u8 & 0x8 == 0 {
+                let limit = self.message_size_limit(first.opcode);
+                if first.data.len() > limit {
+                    return Err(WebSocketError::MessageTooLarge { limit });
+                }
+                self.inflate_frame(&mut first, true)?;
+            }
             current_message_length += first.data.len() + PER_DATAFRAME_OVERHEAD;
-            self.buffer.push(first);
+            payload_length += first.data.len();
+            self.buffer_frame(first)?;
             finished
         } else {
             false
         };
 
         while !finished {
-            let next = self.recv_dataframe(reader)?;
-            finished = next.finished;
+            let mut next = self.recv_dataframe(reader)?;
 
             match next.opcode as u8 {
                 // Continuation opcode
                 0 => {
+                    self.check_reserved_bits(&mut next, false)?;
+                    self.note_frame(&next, false);
+                    self.inflate_frame(&mut next, false)?;
+                    finished = next.finished;
                     current_message_length += next.data.len() + PER_DATAFRAME_OVERHEAD;
-                    self.buffer.push(next)
+                    payload_length += next.data.len();
+                    self.buffer_frame(next)?
                 }
                 // Control frame
                 8..=15 => {
+                    if self.is_dropped(&next) {
+                        continue;
+                    }
+                    self.check_reserved_bits(&mut next, true)?;
+                    self.note_frame(&next, true);
                     return Ok(vec![next]);
                 }
                 // Others
@@ -96,21 +1234,78 @@ impl ReceiverAble for Receiver {
                 }
             }
 
+            let limit = self.message_size_limit(self.buffer[0].opcode);
+            if payload_length > limit {
+                return Err(WebSocketError::MessageTooLarge { limit });
+            }
             if !finished {
                 if self.buffer.len() >= MAX_DATAFRAMES_IN_ONE_MESSAGE {
                     return Err(WebSocketError::ProtocolError(
                         "Exceeded count of data frames in one WebSocket message",
                     ));
                 }
-                if current_message_length >= self.max_message_size as usize {
-                    return Err(WebSocketError::ProtocolError(
-                        "Exceeded maximum WebSocket message size",
-                    ));
+                if current_message_length >= limit {
+                    return Err(WebSocketError::MessageTooLarge { limit });
                 }
             }
         }
 
-        Ok(std::mem::replace(&mut self.buffer, Vec::new()))
+        Ok(self.take_buffer())
+    }
+}
+
+impl ReceiverAble for Receiver {
+    type F = DataFrame;
+
+    type M = Message;
+
+    fn recv_dataframe<R>(&mut self, reader: &mut R) -> WebSocketResult<DataFrame>
+        where
+            R: Read,
+    {
+        loop {
+            let header = self.read_header(reader)?;
+            let result = self.read_body(reader, header, Vec::new());
+            if let Ok(ref frame) = result {
+                if self.dropped_after_close(frame)? {
+                    continue;
+                }
+            }
+            return self.observe(result);
+        }
+    }
+
+    fn recv_message_dataframes<R>(&mut self, reader: &mut R) -> WebSocketResult<Vec<DataFrame>>
+        where
+            R: Read,
+    {
+        let result = self.read_message_dataframes(reader);
+        // a message that failed can't be completed; IO errors may be
+        // retried, e.g. on a non-blocking stream, so they keep it
+        if let Err(ref e) = result {
+            if !matches!(e, WebSocketError::Io(_)) {
+                self.take_buffer();
+            }
+        }
+        result
+    }
+
+    fn recv_message<R>(&mut self, reader: &mut R) -> WebSocketResult<Message>
+        where
+            R: Read,
+    {
+        loop {
+            let dataframes = self.recv_message_dataframes(reader)?;
+            if let Some(message) = self.assemble(dataframes)? {
+                return Ok(message);
+            }
+        }
+    }
+}
+
+impl Drop for Receiver {
+    fn drop(&mut self) {
+        self.take_buffer();
     }
 }
 
@@ -123,14 +1318,31 @@ pub struct Reader<R>
     pub receiver: Receiver,
 }
 
+impl<R> Reader<PrefixedStream<R>> where R: Read {
+    /// Creates a reader over a stream whose handshake is done, reading the
+    /// `leftover` bytes the handshake parser read past the head first, so
+    /// frames the peer sent right behind the handshake aren't lost.
+    pub fn from_upgraded(stream: R, leftover: Vec<u8>, role: Role) -> Reader<PrefixedStream<R>> {
+        Reader::from_reader(PrefixedStream::new(leftover, stream), role)
+    }
+}
+
 impl<R> Reader<R> where R: Read {
+    /// Creates a reader over any `Read`, e.g. a `Cursor` over captured frames,
+    /// expecting the masking that frames sent to `role` must carry.
+    pub fn from_reader(reader: R, role: Role) -> Reader<R> {
+        Reader {
+            stream: BufReader::new(reader),
+            receiver: Receiver::new(role.expects_masked_incoming()),
+        }
+    }
 
     pub fn recv_dataframe(&mut self) -> WebSocketResult<DataFrame> {
         self.receiver.recv_dataframe(&mut self.stream)
     }
 
 
-    pub fn incoming_dataframes(&mut self) -> DataFrameIterator<Receiver, BufReader<R>> {
+    pub fn incoming_dataframes(&mut self) -> DataFrameIterator<'_, Receiver, BufReader<R>> {
         self.receiver.incoming_dataframes(&mut self.stream)
     }
 
@@ -138,9 +1350,80 @@ impl<R> Reader<R> where R: Read {
         self.receiver.recv_message(&mut self.stream)
     }
 
-    pub fn incoming_messages(&mut self) -> MessageIterator<Receiver, BufReader<R>> {
+    pub fn incoming_messages(&mut self) -> MessageIterator<'_, Receiver, BufReader<R>> {
         self.receiver.incoming_messages(&mut self.stream)
     }
+
+    /// Receives a message with how it arrived, `None` unless metadata is
+    /// recorded, see [`Receiver::set_record_metadata`].
+    pub fn recv_message_with_meta(&mut self) -> WebSocketResult<(Message, Option<MessageMeta>)> {
+        let message = self.recv_message()?;
+        Ok((message, self.receiver.last_message_meta.take()))
+    }
+
+    /// Like `incoming_messages`, with the metadata of each message.
+    pub fn incoming_messages_with_meta(&mut self) -> MessagesWithMeta<'_, R> {
+        MessagesWithMeta { reader: self }
+    }
+
+    /// See [`Receiver::discard_remaining_message`].
+    pub fn discard_remaining_message(&mut self, budget: DiscardBudget) -> WebSocketResult<DiscardReport> {
+        self.receiver.discard_remaining_message(&mut self.stream, budget)
+    }
+
+    /// See [`Receiver::recv_frame_event`].
+    pub fn recv_frame_event(&mut self) -> WebSocketResult<FrameEvent> {
+        self.receiver.recv_frame_event(&mut self.stream)
+    }
+
+    /// See [`Receiver::recv_message_filtered`].
+    pub fn recv_message_filtered<F>(&mut self, peek_len: usize, filter: F) -> WebSocketResult<Message>
+        where
+            F: FnMut(&FrameInfo, &[u8]) -> Keep,
+    {
+        self.receiver.recv_message_filtered(&mut self.stream, peek_len, filter)
+    }
+
+    /// Starts recording statistics about received messages. The returned
+    /// recorder can be shared, e.g. with the matching `Writer`.
+    #[cfg(feature = "stats")]
+    pub fn enable_stats(&mut self, config: StatsConfig) -> Arc<StatsRecorder> {
+        let stats = Arc::new(StatsRecorder::new(config));
+        self.receiver.stats = Some(stats.clone());
+        stats
+    }
+
+    /// Records into an existing recorder, or stops recording with `None`.
+    #[cfg(feature = "stats")]
+    pub fn set_stats(&mut self, stats: Option<Arc<StatsRecorder>>) {
+        self.receiver.stats = stats;
+    }
+
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> Option<&Arc<StatsRecorder>> {
+        self.receiver.stats.as_ref()
+    }
+}
+
+/// Iterator over messages and their metadata, see
+/// [`Reader::incoming_messages_with_meta`].
+pub struct MessagesWithMeta<'a, R>
+    where
+        R: Read,
+{
+    reader: &'a mut Reader<R>,
+}
+
+impl<R> Iterator for MessagesWithMeta<'_, R>
+    where
+        R: Read,
+{
+    type Item = WebSocketResult<(Message, Option<MessageMeta>)>;
+
+    /// Always returns `Some`.
+    fn next(&mut self) -> Option<Self::Item> {
+        Some(self.reader.recv_message_with_meta())
+    }
 }
 
 impl<S> Reader<S> where S: AsTcpStream + Stream + Read{
@@ -151,4 +1434,541 @@ impl<S> Reader<S> where S: AsTcpStream + Stream + Read{
     pub fn shutdown_all(&self) -> io::Result<()> {
         self.stream.get_ref().as_tcp().shutdown(Shutdown::Both)
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::cell::Cell;
+    use websocket_core::compression::{CompressionCodec, DeflateCodec};
+    use websocket_core::protocol::dataframe::DataFrameRefTrait;
+    use websocket_core::protocol::message::Type;
+
+    /// The unmasked bytes of a frame, as sent to a client.
+    fn frame_bytes(finished: bool, reserved: [bool; 3], opcode: Opcode, data: &[u8]) -> Vec<u8> {
+        let mut frame = DataFrame::new(finished, opcode, data.to_vec());
+        frame.reserved = reserved;
+        let mut bytes = Vec::new();
+        frame.write_to(&mut bytes, false).unwrap();
+        bytes
+    }
+
+    fn all_reserved_bits() -> impl Iterator<Item = [bool; 3]> {
+        (0u8..8).map(|bits| [bits & 4 != 0, bits & 2 != 0, bits & 1 != 0])
+    }
+
+    #[test]
+    fn reserved_bits_policy() {
+        for opcode in [Opcode::Binary, Opcode::Ping] {
+            for reserved in all_reserved_bits() {
+                let bytes = frame_bytes(true, reserved, opcode, b"x");
+
+                let mut receiver = Receiver::new(false);
+                let result = receiver.recv_message_dataframes(&mut &bytes[..]);
+                if reserved == [false; 3] {
+                    assert_eq!(result.unwrap()[0].reserved, [false; 3]);
+                } else {
+                    assert!(matches!(result, Err(WebSocketError::ProtocolError(_))), "{:?} {:?}", opcode, reserved);
+                }
+
+                let mut receiver = Receiver::new(false);
+                receiver.set_reserved_bits_policy(ReservedBitsPolicy::ClearAndAccept, ReservedBitsPolicy::ClearAndAccept);
+                let frames = receiver.recv_message_dataframes(&mut &bytes[..]).unwrap();
+                assert_eq!(frames[0].reserved, [false; 3]);
+                assert_eq!(receiver.reserved_bits_violations(), (reserved != [false; 3]) as u64);
+            }
+        }
+    }
+
+    #[test]
+    fn control_frame_between_fragments_keeps_the_buffer() {
+        let mut bytes = frame_bytes(false, [false; 3], Opcode::Text, b"hel");
+        bytes.extend(frame_bytes(true, [false; 3], Opcode::Ping, b"ping"));
+        bytes.extend(frame_bytes(true, [false; 3], Opcode::Continuation, b"lo"));
+        let mut stream = &bytes[..];
+
+        let mut receiver = Receiver::new(false);
+        assert_eq!(receiver.recv_message(&mut stream).unwrap(), Message::ping(b"ping".to_vec()));
+        assert_eq!(receiver.pending_frames().len(), 1);
+        assert_eq!(receiver.recv_message(&mut stream).unwrap(), Message::text("hello".to_string()));
+        assert!(receiver.pending_frames().is_empty());
+    }
+
+    #[test]
+    fn failed_message_clears_the_buffer() {
+        let mut bytes = frame_bytes(false, [false; 3], Opcode::Text, b"hel");
+        bytes.extend(frame_bytes(true, [false; 3], Opcode::Binary, b"lo"));
+
+        let mut receiver = Receiver::new(false);
+        let result = receiver.recv_message(&mut &bytes[..]);
+        assert!(matches!(result, Err(WebSocketError::ProtocolError(_))));
+        assert!(receiver.pending_frames().is_empty());
+    }
+
+    #[test]
+    fn io_error_keeps_the_buffer() {
+        let bytes = frame_bytes(false, [false; 3], Opcode::Text, b"hel");
+
+        let mut receiver = Receiver::new(false);
+        let result = receiver.recv_message(&mut &bytes[..]);
+        assert!(matches!(result, Err(WebSocketError::Io(_))));
+        assert_eq!(receiver.pending_frames().len(), 1);
+    }
+
+    #[test]
+    fn fragmented_control_frame_is_a_protocol_error() {
+        let bytes = frame_bytes(false, [false; 3], Opcode::Ping, b"ping");
+
+        let mut receiver = Receiver::new(false);
+        let result = receiver.recv_message(&mut &bytes[..]);
+        assert!(matches!(
+            result,
+            Err(WebSocketError::ProtocolError("Control frame cannot start a fragmented message"))
+        ));
+    }
+
+    #[test]
+    fn reader_from_cursor() {
+        let mut bytes = frame_bytes(true, [false; 3], Opcode::Text, b"first");
+        bytes.extend(frame_bytes(true, [false; 3], Opcode::Ping, b""));
+        bytes.extend(frame_bytes(true, [false; 3], Opcode::Binary, &[1, 2, 3]));
+
+        let mut reader = Reader::from_reader(std::io::Cursor::new(bytes), Role::Client);
+        assert_eq!(reader.recv_message().unwrap(), Message::text("first".to_string()));
+        assert_eq!(reader.recv_message().unwrap(), Message::ping(Vec::new()));
+        assert_eq!(reader.recv_message().unwrap(), Message::binary(vec![1, 2, 3]));
+        assert!(matches!(reader.recv_message(), Err(WebSocketError::Io(_))));
+    }
+
+    /// The messages read from `stream` by `receiver`, up to and
+    /// including a Close.
+    fn decode_session(receiver: &mut Receiver, stream: &mut &[u8]) -> Vec<Message> {
+        let mut messages = Vec::new();
+        loop {
+            let message = receiver.recv_message(stream).unwrap();
+            let close = message.opcode == Type::Close;
+            messages.push(message);
+            if close {
+                return messages;
+            }
+        }
+    }
+
+    #[test]
+    fn reset_session_decodes_like_a_new_receiver() {
+        let codec = DeflateCodec::default();
+        let compressed = |data: &[u8]| {
+            let mut payload = Vec::new();
+            codec.new_compressor().compress(data, &mut payload).unwrap();
+            payload
+        };
+        let close = frame_bytes(true, [false; 3], Opcode::Close, &[3, 232]);
+
+        // ends in the middle of a compressed message, with a reserved bit
+        // and a pong counted
+        let half = compressed(b"first session");
+        let mut first = frame_bytes(false, [true, false, false], Opcode::Text, &half[..half.len() / 2]);
+        first.extend(frame_bytes(true, [false, true, false], Opcode::Pong, b"pong"));
+        first.extend(&close);
+        let mut second = frame_bytes(true, [true, false, false], Opcode::Text, &compressed(b"second session"));
+        second.extend(frame_bytes(true, [false; 3], Opcode::Binary, &[1, 2, 3]));
+        second.extend(&close);
+
+        let new_receiver = || {
+            let mut receiver = Receiver::new(false);
+            receiver.set_decompressor(Some(codec.new_decompressor()));
+            receiver.set_reserved_bits_policy(ReservedBitsPolicy::ClearAndAccept, ReservedBitsPolicy::Reject);
+            receiver.set_record_metadata(true);
+            receiver
+        };
+
+        let mut separate = new_receiver();
+        let expected_first = decode_session(&mut separate, &mut &first[..]);
+        let mut separate = new_receiver();
+        let expected_second = decode_session(&mut separate, &mut &second[..]);
+
+        let mut bytes = first.clone();
+        bytes.extend(&second);
+        let mut stream = &bytes[..];
+        let mut receiver = new_receiver();
+        assert_eq!(decode_session(&mut receiver, &mut stream), expected_first);
+        assert_eq!(receiver.reserved_bits_violations(), 1);
+        assert!(receiver.last_pong_at().is_some());
+
+        receiver.reset_session();
+        assert!(receiver.pending_frames().is_empty());
+        assert_eq!(receiver.reserved_bits_violations(), 0);
+        assert_eq!(receiver.last_pong_at(), None);
+        assert_eq!(receiver.last_message_meta(), None);
+        assert_eq!(decode_session(&mut receiver, &mut stream), expected_second);
+        assert_eq!(expected_second[0], Message::text("second session".to_string()));
+        assert!(stream.is_empty());
+    }
+
+    /// A receiver with the first frame of a text message buffered, as left
+    /// by a receive that failed after it.
+    fn receiver_in_message() -> Receiver {
+        let mut receiver = Receiver::new(false);
+        let first = frame_bytes(false, [false; 3], Opcode::Text, b"abc");
+        assert!(matches!(receiver.recv_message(&mut &first[..]), Err(WebSocketError::Io(_))));
+        assert_eq!(receiver.pending_frames().len(), 1);
+        receiver
+    }
+
+    fn unlimited() -> DiscardBudget {
+        DiscardBudget {
+            max_bytes: u64::MAX,
+            max_duration: None,
+        }
+    }
+
+    #[test]
+    fn discard_completes_the_message() {
+        let mut bytes = frame_bytes(false, [false; 3], Opcode::Continuation, b"def");
+        bytes.extend(frame_bytes(true, [false; 3], Opcode::Continuation, b"gh"));
+        bytes.extend(frame_bytes(true, [false; 3], Opcode::Text, b"next"));
+        let mut stream = &bytes[..];
+
+        let mut receiver = receiver_in_message();
+        let report = receiver.discard_remaining_message(&mut stream, unlimited()).unwrap();
+        assert_eq!(report, DiscardReport {
+            frames: 3,
+            bytes: 8,
+            control: Vec::new(),
+            exhausted: false,
+        });
+        assert!(receiver.pending_frames().is_empty());
+        assert_eq!(receiver.recv_message(&mut stream).unwrap(), Message::text("next".to_string()));
+    }
+
+    #[test]
+    fn discard_collects_a_ping() {
+        let mut bytes = frame_bytes(false, [false; 3], Opcode::Continuation, b"def");
+        bytes.extend(frame_bytes(true, [false; 3], Opcode::Ping, b"p"));
+        bytes.extend(frame_bytes(true, [false; 3], Opcode::Continuation, b"gh"));
+        bytes.extend(frame_bytes(true, [false; 3], Opcode::Text, b"next"));
+        let mut stream = &bytes[..];
+
+        let mut receiver = receiver_in_message();
+        let report = receiver.discard_remaining_message(&mut stream, unlimited()).unwrap();
+        assert_eq!(report.control, vec![Message::ping(b"p".to_vec())]);
+        assert_eq!(report.frames, 3);
+        assert_eq!(receiver.recv_message(&mut stream).unwrap(), Message::text("next".to_string()));
+    }
+
+    #[test]
+    fn discard_ends_at_a_close() {
+        let mut bytes = frame_bytes(false, [false; 3], Opcode::Continuation, b"def");
+        bytes.extend(frame_bytes(true, [false; 3], Opcode::Close, &[3, 232]));
+
+        let mut receiver = receiver_in_message();
+        let report = receiver.discard_remaining_message(&mut &bytes[..], unlimited()).unwrap();
+        assert_eq!(report.control.len(), 1);
+        assert_eq!(report.control[0].opcode, Type::Close);
+        assert_eq!(report.control[0].cd_status_code, Some(1000));
+        assert!(!report.exhausted);
+        assert!(receiver.close_received());
+        assert!(!receiver.is_poisoned());
+    }
+
+    #[test]
+    fn discard_out_of_budget_poisons() {
+        let mut bytes = frame_bytes(false, [false; 3], Opcode::Continuation, b"def");
+        bytes.extend(frame_bytes(true, [false; 3], Opcode::Continuation, &[0; 100]));
+        bytes.extend(frame_bytes(true, [false; 3], Opcode::Text, b"next"));
+        let mut stream = &bytes[..];
+
+        let mut receiver = receiver_in_message();
+        let budget = DiscardBudget {
+            max_bytes: 10,
+            max_duration: None,
+        };
+        let report = receiver.discard_remaining_message(&mut stream, budget).unwrap();
+        assert!(report.exhausted);
+        assert_eq!(report.bytes, 6);
+        assert!(receiver.is_poisoned());
+        assert!(matches!(receiver.recv_message(&mut stream), Err(WebSocketError::Poisoned)));
+    }
+
+    #[test]
+    fn filtered_skip_delivers_control_frames_of_the_skipped_message() {
+        let mut bytes = frame_bytes(false, [false; 3], Opcode::Binary, b"skip");
+        bytes.extend(frame_bytes(true, [false; 3], Opcode::Ping, b"p"));
+        bytes.extend(frame_bytes(true, [false; 3], Opcode::Continuation, b"me"));
+        bytes.extend(frame_bytes(true, [false; 3], Opcode::Text, b"keep"));
+        let mut stream = &bytes[..];
+
+        let mut receiver = Receiver::new(false);
+        let mut filter = |info: &FrameInfo, _: &[u8]| {
+            if info.opcode == Opcode::Binary { Keep::Skip } else { Keep::Deliver }
+        };
+        assert_eq!(receiver.recv_message_filtered(&mut stream, 0, &mut filter).unwrap(), Message::ping(b"p".to_vec()));
+        assert_eq!(receiver.recv_message_filtered(&mut stream, 0, &mut filter).unwrap(), Message::text("keep".to_string()));
+    }
+
+    #[test]
+    fn filtered_skip_over_the_size_limit_poisons() {
+        let mut bytes = frame_bytes(false, [false; 3], Opcode::Binary, &[0; 10]);
+        bytes.extend(frame_bytes(true, [false; 3], Opcode::Continuation, &[0; 10]));
+
+        let mut receiver = Receiver::new(false);
+        receiver.set_message_size_limits(None, Some(16));
+        let result = receiver.recv_message_filtered(&mut &bytes[..], 0, |_, _| Keep::Skip);
+        assert!(matches!(result, Err(WebSocketError::MessageTooLarge { limit: 16 })));
+        assert!(receiver.is_poisoned());
+    }
+
+    thread_local! {
+        static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+    }
+
+    /// Counts the allocations made by each thread.
+    struct CountingAllocator;
+
+    unsafe impl GlobalAlloc for CountingAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+    fn allocations() -> usize {
+        ALLOCATIONS.with(Cell::get)
+    }
+
+    /// The masked bytes of a frame, as sent to a server.
+    fn masked_frame_bytes(finished: bool, opcode: Opcode, data: &[u8]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        DataFrame::new(finished, opcode, data.to_vec()).write_to(&mut bytes, true).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn filter_keeps_every_20th_of_1000_messages() {
+        let mut bytes = Vec::new();
+        let mut expected = Vec::new();
+        for i in 0u32..1000 {
+            let mut payload = i.to_be_bytes().to_vec();
+            payload.extend((0..100u32).map(|x| (x * 7 + i) as u8));
+            if i.is_multiple_of(20) {
+                expected.push(Message::binary(payload.clone()));
+            }
+            if i.is_multiple_of(3) {
+                // split inside the peeked bytes and after them
+                bytes.extend(masked_frame_bytes(false, Opcode::Binary, &payload[..10]));
+                bytes.extend(masked_frame_bytes(false, Opcode::Continuation, &payload[10..50]));
+                bytes.extend(masked_frame_bytes(true, Opcode::Continuation, &payload[50..]));
+            } else {
+                bytes.extend(masked_frame_bytes(true, Opcode::Binary, &payload));
+            }
+        }
+        let mut stream = &bytes[..];
+
+        // allocations from a skip to the next message's filter call
+        let skip_started = Cell::new(None);
+        let skip_allocations = Cell::new(0);
+        let mut filter = |_: &FrameInfo, peeked: &[u8]| {
+            if let Some(started) = skip_started.take() {
+                skip_allocations.set(skip_allocations.get() + allocations() - started);
+            }
+            let index = u32::from_be_bytes([peeked[0], peeked[1], peeked[2], peeked[3]]);
+            if index.is_multiple_of(20) {
+                Keep::Deliver
+            } else {
+                skip_started.set(Some(allocations()));
+                Keep::Skip
+            }
+        };
+
+        let mut receiver = Receiver::new(true);
+        let mut kept = Vec::new();
+        while kept.len() < expected.len() {
+            kept.push(receiver.recv_message_filtered(&mut stream, 16, &mut filter).unwrap());
+        }
+        assert_eq!(kept, expected);
+        // the last messages are all skipped, leaving the stream at its end
+        let result = receiver.recv_message_filtered(&mut stream, 16, &mut filter);
+        assert!(matches!(result, Err(WebSocketError::Io(_))));
+        assert!(stream.is_empty());
+        assert!(receiver.pending_frames().is_empty());
+        assert_eq!(skip_allocations.get(), 0);
+    }
+
+    #[test]
+    fn filter_checks_reserved_bits_and_size_of_skipped_messages() {
+        let mut receiver = Receiver::new(false);
+        let bytes = frame_bytes(true, [false, true, false], Opcode::Binary, b"x");
+        let result = receiver.recv_message_filtered(&mut &bytes[..], 16, |_, _| Keep::Skip);
+        assert!(matches!(result, Err(WebSocketError::ProtocolError("Unsupported reserved bits received"))));
+
+        let mut receiver = Receiver::new(false);
+        let mut bytes = frame_bytes(false, [false; 3], Opcode::Binary, b"x");
+        bytes.extend(frame_bytes(true, [false, false, true], Opcode::Continuation, b"y"));
+        let result = receiver.recv_message_filtered(&mut &bytes[..], 16, |_, _| Keep::Skip);
+        assert!(matches!(result, Err(WebSocketError::ProtocolError("Unsupported reserved bits received"))));
+
+        let mut receiver = Receiver::new(false);
+        receiver.set_message_size_limits(None, Some(16));
+        let bytes = frame_bytes(true, [false; 3], Opcode::Binary, &[0; 20]);
+        let result = receiver.recv_message_filtered(&mut &bytes[..], 16, |_, _| Keep::Skip);
+        assert!(matches!(result, Err(WebSocketError::MessageTooLarge { limit: 16 })));
+    }
+
+    #[test]
+    fn only_eof_at_a_frame_boundary_after_close_finishes_the_peer() {
+        let close = frame_bytes(true, [false; 3], Opcode::Close, &[0x03, 0xe8]);
+        let ping = frame_bytes(true, [false; 3], Opcode::Ping, b"abc");
+        // whole frames, then cut in the header and in the payload
+        for (cut, finished) in [(0, true), (1, false), (ping.len() - 1, false)] {
+            let mut bytes = close.clone();
+            bytes.extend_from_slice(&ping[..cut]);
+            let mut stream = &bytes[..];
+            let mut receiver = Receiver::new(false);
+            assert_eq!(receiver.recv_dataframe(&mut stream).unwrap().opcode, Opcode::Close);
+            let result = receiver.recv_dataframe(&mut stream);
+            if finished {
+                assert!(matches!(result, Err(WebSocketError::PeerFinishedSending)), "cut: {}", cut);
+            } else {
+                assert!(matches!(result, Err(WebSocketError::Io(_))), "cut: {}", cut);
+            }
+        }
+    }
+
+    /// Inputs no receive may accept: truncated headers, oversized or
+    /// badly encoded lengths, reserved opcodes and invalid UTF-8.
+    fn malformed_inputs() -> Vec<(String, Vec<u8>)> {
+        let mut inputs = Vec::new();
+        for frame in [
+            frame_bytes(true, [false; 3], Opcode::Binary, &[7; 10]),
+            frame_bytes(true, [false; 3], Opcode::Binary, &[7; 300]),
+            frame_bytes(true, [false; 3], Opcode::Binary, &[7; 70_000]),
+        ] {
+            let header_len = match frame[1] & 0x7f {
+                126 => 4,
+                127 => 10,
+                _ => 2,
+            };
+            for cut in 0..header_len {
+                inputs.push((format!("header cut at {} of {}", cut, header_len), frame[..cut].to_vec()));
+            }
+            inputs.push(("payload cut short".to_string(), frame[..frame.len() - 1].to_vec()));
+        }
+        for len in [u64::MAX, 1 << 63, DEFAULT_MAX_DATAFRAME_SIZE as u64 + 1] {
+            let mut bytes = vec![0x82, 127];
+            bytes.extend_from_slice(&len.to_be_bytes());
+            inputs.push((format!("length {}", len), bytes));
+        }
+        inputs.push(("2 byte length under 126".to_string(), vec![0x82, 126, 0, 5, 1, 2, 3, 4, 5]));
+        let mut bytes = vec![0x82, 127];
+        bytes.extend_from_slice(&200u64.to_be_bytes());
+        inputs.push(("8 byte length under 65536".to_string(), bytes));
+        let mut bytes = vec![0x89, 126, 0, 126];
+        bytes.extend_from_slice(&[0; 126]);
+        inputs.push(("long control frame".to_string(), bytes));
+        for opcode in (3..8).chain(11..16) {
+            inputs.push((format!("opcode {}", opcode), vec![0x80 | opcode, 1, b'x']));
+        }
+        inputs.push(("continuation without a message".to_string(), frame_bytes(true, [false; 3], Opcode::Continuation, b"x")));
+        inputs.push(("invalid UTF-8".to_string(), frame_bytes(true, [false; 3], Opcode::Text, &[b'a', 0xff, b'b'])));
+        let mut bytes = frame_bytes(false, [false; 3], Opcode::Text, &[0xe2, 0x82]);
+        bytes.extend(frame_bytes(true, [false; 3], Opcode::Continuation, &[0x28]));
+        inputs.push(("invalid UTF-8 across fragments".to_string(), bytes));
+        inputs.push((
+            "invalid UTF-8 in a Close reason".to_string(),
+            frame_bytes(true, [false; 3], Opcode::Close, &[0x03, 0xe8, 0xc3, 0x28]),
+        ));
+        inputs
+    }
+
+    #[test]
+    fn malformed_input_is_an_error() {
+        for (name, bytes) in malformed_inputs() {
+            let result = Receiver::new(false).recv_message(&mut &bytes[..]);
+            assert!(result.is_err(), "recv_message: {}: {:?}", name, result);
+            let result = Receiver::new(false).recv_message_filtered(&mut &bytes[..], 4, |_, _| Keep::Deliver);
+            assert!(result.is_err(), "recv_message_filtered: {}: {:?}", name, result);
+            // raw frames pass reserved opcodes on, these must only not panic
+            let mut receiver = Receiver::new(false);
+            let mut stream = &bytes[..];
+            while receiver.recv_dataframe(&mut stream).is_ok() {}
+            let mut receiver = Receiver::new(false);
+            let mut stream = &bytes[..];
+            while receiver.recv_frame_event(&mut stream).is_ok() {}
+        }
+    }
+
+    #[test]
+    fn delivery_mode_keeps_the_arrival_order() {
+        let mut bytes = frame_bytes(false, [false; 3], Opcode::Text, b"hello ");
+        bytes.extend(frame_bytes(true, [false; 3], Opcode::Ping, b"ping"));
+        bytes.extend(frame_bytes(true, [false; 3], Opcode::Continuation, b"world"));
+        bytes.extend(frame_bytes(true, [false; 3], Opcode::Pong, b"pong"));
+        bytes.extend(frame_bytes(true, [false; 3], Opcode::Close, &[0x03, 0xe8]));
+        let text = Message::text("hello world".to_string());
+        let close = Message::close_because(1000, String::new());
+        let expected = [
+            (DeliveryMode::All, vec![Message::ping(b"ping".to_vec()), text.clone(), Message::pong(b"pong".to_vec()), close.clone()]),
+            (DeliveryMode::DataOnly, vec![text, close]),
+        ];
+        for (mode, messages) in expected {
+            let mut receiver = Receiver::new(false);
+            receiver.set_delivery_mode(mode);
+            let mut stream = &bytes[..];
+            for message in messages {
+                assert_eq!(receiver.recv_message(&mut stream).unwrap(), message, "{:?}", mode);
+            }
+            assert!(stream.is_empty(), "{:?}", mode);
+        }
+    }
+
+    #[test]
+    fn failed_messages_credit_the_memory_budget() {
+        let text = |finished, data: &[u8]| frame_bytes(finished, [false; 3], Opcode::Text, data);
+        let more = |finished, data: &[u8]| frame_bytes(finished, [false; 3], Opcode::Continuation, data);
+        let failing = [
+            ("too large", [text(false, b"12345"), more(true, b"6789012345")].concat()),
+            ("invalid UTF-8", [text(false, &[0xe2, 0x82]), more(true, &[0x28])].concat()),
+            ("no continuation", [text(false, b"abc"), text(true, b"def")].concat()),
+            ("reserved bits", [text(false, b"abc"), frame_bytes(true, [false, true, false], Opcode::Continuation, b"d")].concat()),
+            (
+                "over the budget",
+                [frame_bytes(false, [false; 3], Opcode::Binary, &[1; 60]), more(true, &[2; 60])].concat(),
+            ),
+        ];
+        let next = text(true, b"next");
+        for (name, bytes) in failing {
+            let budget = Arc::new(MemoryBudget::new(100));
+            let mut receiver = Receiver::new(false);
+            receiver.set_message_size_limits(Some(10), None);
+            receiver.set_memory_budget(Some(budget.clone()), None);
+            let mut stream = &bytes[..];
+            assert!(receiver.recv_message(&mut stream).is_err(), "{}", name);
+            assert_eq!(budget.used(), 0, "{}", name);
+            assert!(budget.peak() > 0, "{}", name);
+
+            receiver.reset_session();
+            assert_eq!(receiver.recv_message(&mut &next[..]).unwrap(), Message::text("next".to_string()));
+            assert_eq!(budget.used(), 0, "{}", name);
+        }
+
+        // a message cut short stays charged until the receiver lets it go
+        let budget = Arc::new(MemoryBudget::new(100));
+        let mut receiver = Receiver::new(false);
+        receiver.set_memory_budget(Some(budget.clone()), None);
+        assert!(receiver.recv_message(&mut &text(false, b"abc")[..]).is_err());
+        assert_eq!(budget.used(), 3);
+        receiver.reset_session();
+        assert_eq!(budget.used(), 0);
+        assert!(receiver.recv_message(&mut &text(false, b"abc")[..]).is_err());
+        assert_eq!(budget.used(), 3);
+        drop(receiver);
+        assert_eq!(budget.used(), 0);
+    }
+}
@@ -1,10 +1,20 @@
 use std::io::Result as IoResult;
-use std::io::Write;
+use std::io::{self, Write};
 use std::net::Shutdown;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use websocket_core::action::sender::Sender as SenderAble;
-use websocket_core::protocol::dataframe::DataFrame;
+use websocket_core::clock::{Clock, SystemClock};
+use websocket_core::compression::{Compressor, DEFAULT_COMPRESSION_THRESHOLD};
+use websocket_core::error::{TransportErrorKind, WebSocketError};
+use websocket_core::protocol::dataframe::DataFrameRefTrait;
+use websocket_core::protocol::header::{gen_mask, DataFrameFlags, DataFrameHeader, FrameHeader, MaskingImpl, Opcode};
 use websocket_core::protocol::message::Message;
 use websocket_core::stream::AsTcpStream;
+#[cfg(feature = "stats")]
+use crate::stats::{StatsConfig, StatsRecorder};
+use crate::payload::PayloadSource;
 use crate::WebSocketResult;
 
 pub struct Writer<W> {
@@ -13,32 +23,208 @@ pub struct Writer<W> {
 	pub sender: Sender,
 }
 
+/// Outgoing data frames waiting to be written together.
+struct Coalescing {
+	max_delay: Duration,
+	max_bytes: usize,
+	pending: Vec<u8>,
+	first_pending_at: Option<Instant>,
+}
+
+/// Decides whether a data message is compressed, see
+/// `Sender::set_should_compress`.
+pub type CompressionFilter = dyn Fn(Opcode, &[u8]) -> bool + Send + Sync;
+
+/// How many payload bytes `send_binary_from` reads and writes at a time.
+const STREAM_CHUNK: usize = 64 * 1024;
+
+/// The longest frame header: two bytes, an eight byte length and a mask.
+const MAX_HEADER_LEN: usize = 14;
+
+/// Counts the bytes that reached the stream, telling a send that failed
+/// before its frame began from one that failed part way through it.
+struct Counting<'a, W> {
+	inner: &'a mut W,
+	written: usize,
+}
+
+impl<W> Write for Counting<'_, W>
+where
+	W: Write,
+{
+	fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+		let written = self.inner.write(buf)?;
+		self.written += written;
+		Ok(written)
+	}
+
+	fn flush(&mut self) -> IoResult<()> {
+		self.inner.flush()
+	}
+}
+
 pub struct Sender {
 	mask: bool,
+	masking: MaskingImpl,
+	streaming: bool,
+	compressor: Option<Box<dyn Compressor>>,
+	should_compress: Option<Box<CompressionFilter>>,
+	coalescing: Option<Coalescing>,
+	clock: Arc<dyn Clock>,
+	/// Set once the owning `Writer` shut the connection down.
+	shut_down: AtomicBool,
+	/// Set once a send stopped in the middle of a frame.
+	poisoned: bool,
+	#[cfg(feature = "stats")]
+	stats: Option<Arc<StatsRecorder>>,
 }
 
 impl Sender {
 	pub fn new(mask: bool) -> Sender {
-		Sender { mask }
+		Sender {
+			mask,
+			masking: MaskingImpl::default(),
+			streaming: false,
+			compressor: None,
+			should_compress: None,
+			coalescing: None,
+			clock: Arc::new(SystemClock),
+			shut_down: AtomicBool::new(false),
+			poisoned: false,
+			#[cfg(feature = "stats")]
+			stats: None,
+		}
+	}
+
+	/// Selects the implementation used to mask outgoing payloads.
+	pub fn set_masking(&mut self, masking: MaskingImpl) {
+		self.masking = masking;
+	}
+
+	/// Writes frames to the stream as their payload is masked, in small
+	/// chunks, instead of building each frame in a buffer first. This saves
+	/// a copy of large payloads, but a frame takes several writes, so the
+	/// stream should buffer. A frame whose payload doesn't match its length
+	/// is only noticed part way through it and poisons the writer.
+	pub fn set_streaming(&mut self, streaming: bool) {
+		self.streaming = streaming;
+	}
+
+	/// Compresses outgoing data messages and marks them with RSV1. Only set
+	/// this once the peer agreed to the extension.
+	pub fn set_compressor(&mut self, compressor: Option<Box<dyn Compressor>>) {
+		self.compressor = compressor;
+	}
+
+	/// Decides per data message whether it is compressed, from its opcode
+	/// and payload. `None` compresses payloads longer than
+	/// `DEFAULT_COMPRESSION_THRESHOLD`.
+	pub fn set_should_compress(&mut self, should_compress: Option<Box<CompressionFilter>>) {
+		self.should_compress = should_compress;
+	}
+
+	/// Replaces the system clock, e.g. with a `SimClock` in tests.
+	pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+		self.clock = clock;
+	}
+
+	/// Turns IO errors of the transport into `WebSocketError::Transport`.
+	fn classify(&self, error: WebSocketError) -> WebSocketError {
+		match error {
+			WebSocketError::Io(source) => {
+				let kind = if self.shut_down.load(Ordering::Relaxed) {
+					TransportErrorKind::LocalShutdown
+				} else {
+					TransportErrorKind::classify(&source)
+				};
+				WebSocketError::Transport { kind, source }
+			}
+			e => e,
+		}
 	}
 }
 
+fn is_control(opcode: u8) -> bool {
+	opcode & 0x8 != 0
+}
+
 impl SenderAble for Sender {
 	fn is_masked(&self) -> bool {
 		self.mask
 	}
+
+	fn masking(&self) -> MaskingImpl {
+		self.masking
+	}
+
+	fn streaming(&self) -> bool {
+		self.streaming
+	}
+
+	fn should_compress(&self, opcode: Opcode, payload: &[u8]) -> bool {
+		match self.should_compress {
+			Some(ref should_compress) => should_compress(opcode, payload),
+			None => payload.len() > DEFAULT_COMPRESSION_THRESHOLD,
+		}
+	}
+
+	fn compressor(&mut self) -> Option<&mut dyn Compressor> {
+		match self.compressor {
+			Some(ref mut compressor) => Some(&mut **compressor),
+			None => None,
+		}
+	}
+}
+
+/// Fills `buf` with the last bytes of `source`, and checks that the source
+/// has none beyond them.
+fn read_last_payload<P>(source: &mut P, buf: &mut [u8]) -> WebSocketResult<()>
+where
+	P: PayloadSource,
+{
+	let mut filled = 0;
+	while filled < buf.len() {
+		let read = source.read_chunk(&mut buf[filled..])?;
+		if read == 0 {
+			return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "payload source ended early").into());
+		}
+		filled += read;
+	}
+	if source.read_chunk(&mut [0u8; 1])? != 0 {
+		return Err(io::Error::new(io::ErrorKind::InvalidData, "payload source longer than its length").into());
+	}
+	Ok(())
 }
 
 impl<W> Writer<W>
 where
 	W: Write,
 {
+	/// Sends a single frame as it is. FIN is taken from the frame's
+	/// `is_last`, which for a `DataFrame` is its `finished` field, so frames
+	/// with `finished` false go out with FIN clear and a message can be
+	/// fragmented by hand: a first frame with the message opcode, then
+	/// `Continuation` frames, the last one finished. `send_message` never
+	/// fragments, a `Message` is always sent as one finished frame.
 	pub fn send_dataframe<D>(&mut self, dataframe: &D) -> WebSocketResult<()>
 	where
-		D: DataFrame,
-		W: Write,
+		D: DataFrameRefTrait + ?Sized,
 	{
-		self.sender.send_dataframe(&mut self.stream, dataframe)
+		self.check_poisoned()?;
+		#[cfg(feature = "stats")]
+		let started = Instant::now();
+		let result = if self.sender.coalescing.is_some() && !is_control(dataframe.opcode()) {
+			self.coalesce(|sender, pending| sender.send_dataframe(pending, dataframe))
+		} else {
+			self.write_pending()
+				.and_then(|_| self.write_committed(|sender, stream| sender.send_dataframe(stream, dataframe)))
+		};
+		let result = result.map_err(|e| self.sender.classify(e));
+		#[cfg(feature = "stats")]
+		if let Some(ref stats) = self.sender.stats {
+			stats.record_write(started.elapsed());
+		}
+		result
 	}
 
 	/// Sends a single message to the remote endpoint.
@@ -46,7 +232,288 @@ where
 	where
 		M: Message,
 	{
-		self.sender.send_message(&mut self.stream, message)
+		self.check_poisoned()?;
+		#[cfg(feature = "stats")]
+		let started = Instant::now();
+		let is_data = message.as_dataframe().is_some_and(|frame| !is_control(frame.opcode()));
+		let result = if self.sender.coalescing.is_some() && is_data {
+			self.coalesce(|sender, pending| sender.send_message(pending, message))
+		} else {
+			self.write_pending()
+				.and_then(|_| self.write_committed(|sender, stream| sender.send_message(stream, message)))
+		};
+		let result = result.map_err(|e| self.sender.classify(e));
+		#[cfg(feature = "stats")]
+		if let Some(ref stats) = self.sender.stats {
+			stats.record_write(started.elapsed());
+		}
+		result
+	}
+
+	/// Sends the payload of `source` as a binary message without loading
+	/// it, in frames of at most `fragment_size` bytes, the last with FIN
+	/// set. The payload is read and masked in chunks of a bounded buffer and
+	/// is never compressed.
+	///
+	/// The last frame is read in full, and the source checked to end with
+	/// it, before its header goes out, so a message is never finished with a
+	/// payload the source didn't deliver. It holds at most the buffer, and
+	/// the frame before it is shortened to leave no more for it.
+	///
+	/// Frame headers announce `source.len()` bytes. If the source delivers
+	/// fewer or more, or reading it or writing fails once a frame has begun,
+	/// the message can't be completed and the stream is left out of sync
+	/// with the peer: the writer is poisoned and every later send fails with
+	/// `WebSocketError::Poisoned`. The connection can only be dropped then.
+	/// Nothing is written, and the writer stays usable, when that happens
+	/// in a message sent as a single frame.
+	pub fn send_binary_from<P>(&mut self, mut source: P, fragment_size: usize) -> WebSocketResult<()>
+	where
+		P: PayloadSource,
+	{
+		self.check_poisoned()?;
+		self.write_pending().map_err(|e| self.sender.classify(e))?;
+		let fragment_size = fragment_size.max(1) as u64;
+		let mut scratch = vec![0u8; fragment_size.min(STREAM_CHUNK as u64) as usize];
+		let mut remaining = source.len();
+		let mut opcode = Opcode::Binary;
+		loop {
+			let mut len = remaining.min(fragment_size);
+			if len == remaining && len > scratch.len() as u64 {
+				len -= scratch.len() as u64;
+			}
+			remaining -= len;
+			let mask = if self.sender.mask { Some(gen_mask()) } else { None };
+			let mut flags = DataFrameFlags::empty();
+			if remaining == 0 {
+				flags.insert(DataFrameFlags::FIN);
+				if let Err(e) = read_last_payload(&mut source, &mut scratch[..len as usize]) {
+					// the frames sent so far began a message that can't be
+					// finished now
+					if opcode == Opcode::Continuation {
+						self.sender.poisoned = true;
+					}
+					return Err(e);
+				}
+				if let Some(mask) = mask {
+					self.sender.masking.apply(mask, 0, &mut scratch[..len as usize]);
+				}
+			}
+			let header = DataFrameHeader {
+				flags,
+				opcode: opcode as u8,
+				mask,
+				len,
+			};
+			let mut header_bytes = [0u8; MAX_HEADER_LEN];
+			let header_len = {
+				let mut free = &mut header_bytes[..];
+				header.write(&mut free)?;
+				MAX_HEADER_LEN - free.len()
+			};
+			let header_bytes = &header_bytes[..header_len];
+			if remaining == 0 {
+				let payload = &scratch[..len as usize];
+				let result = self.write_committed(|_, stream| {
+					stream.write_all(header_bytes)?;
+					Ok(stream.write_all(payload)?)
+				});
+				if result.is_err() && opcode == Opcode::Continuation {
+					self.sender.poisoned = true;
+				}
+				return result.map_err(|e| self.sender.classify(e));
+			}
+			if let Err(e) = self.write_committed(|_, stream| Ok(stream.write_all(header_bytes)?)) {
+				if opcode == Opcode::Continuation {
+					self.sender.poisoned = true;
+				}
+				return Err(self.sender.classify(e));
+			}
+			if let Err(e) = self.stream_frame_payload(&mut source, mask, len, &mut scratch) {
+				self.sender.poisoned = true;
+				return Err(e);
+			}
+			opcode = Opcode::Continuation;
+		}
+	}
+
+	/// Copies `len` bytes of `source` to the stream, masked with `mask`.
+	/// Errors of the source are returned as they are, those of the stream
+	/// are classified.
+	fn stream_frame_payload<P>(&mut self, source: &mut P, mask: Option<[u8; 4]>, len: u64, scratch: &mut [u8]) -> WebSocketResult<()>
+	where
+		P: PayloadSource,
+	{
+		let mut left = len;
+		let mut pos = 0;
+		while left > 0 {
+			let want = left.min(scratch.len() as u64) as usize;
+			let read = source.read_chunk(&mut scratch[..want])?;
+			if read == 0 {
+				return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "payload source ended early").into());
+			}
+			if let Some(mask) = mask {
+				self.sender.masking.apply(mask, pos, &mut scratch[..read]);
+			}
+			if let Err(e) = self.stream.write_all(&scratch[..read]) {
+				return Err(self.sender.classify(e.into()));
+			}
+			pos += read;
+			left -= read as u64;
+		}
+		Ok(())
+	}
+
+	/// Whether a send stopped in the middle of a frame, leaving the stream
+	/// out of sync with the peer. Every send fails with
+	/// `WebSocketError::Poisoned` then, see [`Writer::send_binary_from`].
+	pub fn is_poisoned(&self) -> bool {
+		self.sender.poisoned
+	}
+
+	fn check_poisoned(&self) -> WebSocketResult<()> {
+		if self.sender.poisoned {
+			return Err(WebSocketError::Poisoned);
+		}
+		Ok(())
+	}
+
+	/// Runs `write` against the stream. Unless streaming, frames are built
+	/// in full before the stream is touched; either way a failure after some
+	/// bytes went out is a failed write in the middle of a frame: the writer is poisoned then, as the
+	/// peer would take whatever follows for the rest of that frame.
+	fn write_committed<F>(&mut self, write: F) -> WebSocketResult<()>
+	where
+		F: FnOnce(&mut Sender, &mut Counting<W>) -> WebSocketResult<()>,
+	{
+		let mut stream = Counting {
+			inner: &mut self.stream,
+			written: 0,
+		};
+		let result = write(&mut self.sender, &mut stream);
+		if result.is_err() && stream.written > 0 {
+			self.sender.poisoned = true;
+		}
+		result
+	}
+
+	/// Batches data frames into fewer writes: they are held until
+	/// `max_bytes` of frames are pending or `max_delay` has passed since the
+	/// first one. Control frames and [`Writer::flush`] write everything
+	/// pending right away. The delay is only checked when sending and in
+	/// [`Writer::poll_flush_deadline`], which the caller's loop has to call.
+	pub fn set_coalescing(&mut self, max_delay: Duration, max_bytes: usize) {
+		let pending = self.sender.coalescing.take().map(|c| (c.pending, c.first_pending_at));
+		let (pending, first_pending_at) = pending.unwrap_or_default();
+		self.sender.coalescing = Some(Coalescing {
+			max_delay,
+			max_bytes,
+			pending,
+			first_pending_at,
+		});
+	}
+
+	/// Writes anything pending and turns coalescing off.
+	pub fn disable_coalescing(&mut self) -> WebSocketResult<()> {
+		self.write_pending()?;
+		self.sender.coalescing = None;
+		Ok(())
+	}
+
+	/// Writes the pending frames if their delay is up. Returns when they
+	/// will be due otherwise, or `None` if nothing is pending.
+	pub fn poll_flush_deadline(&mut self) -> WebSocketResult<Option<Instant>> {
+		let deadline = match self.sender.coalescing {
+			Some(Coalescing { first_pending_at: Some(first), max_delay, .. }) => first + max_delay,
+			_ => return Ok(None),
+		};
+		if self.sender.clock.now() >= deadline {
+			self.write_pending()?;
+			return Ok(None);
+		}
+		Ok(Some(deadline))
+	}
+
+	/// Writes anything pending and flushes the stream.
+	pub fn flush(&mut self) -> WebSocketResult<()> {
+		self.write_pending()
+			.and_then(|_| self.stream.flush().map_err(WebSocketError::from))
+			.map_err(|e| self.sender.classify(e))
+	}
+
+	fn coalesce<F>(&mut self, serialize: F) -> WebSocketResult<()>
+	where
+		F: FnOnce(&mut Sender, &mut Vec<u8>) -> WebSocketResult<()>,
+	{
+		let now = self.sender.clock.now();
+		let mut pending = match self.sender.coalescing {
+			Some(ref mut coalescing) => std::mem::take(&mut coalescing.pending),
+			None => Vec::new(),
+		};
+		let len = pending.len();
+		let result = serialize(&mut self.sender, &mut pending);
+		if result.is_err() {
+			pending.truncate(len);
+		}
+		let due = match self.sender.coalescing {
+			Some(ref mut coalescing) => {
+				coalescing.pending = pending;
+				if coalescing.pending.is_empty() {
+					false
+				} else {
+					let first = *coalescing.first_pending_at.get_or_insert(now);
+					coalescing.pending.len() >= coalescing.max_bytes
+						|| now.saturating_duration_since(first) >= coalescing.max_delay
+				}
+			}
+			None => false,
+		};
+		result?;
+		if due {
+			self.write_pending()?;
+		}
+		Ok(())
+	}
+
+	fn write_pending(&mut self) -> WebSocketResult<()> {
+		let pending = match self.sender.coalescing {
+			Some(ref mut coalescing) if !coalescing.pending.is_empty() => std::mem::take(&mut coalescing.pending),
+			Some(ref mut coalescing) => {
+				coalescing.first_pending_at = None;
+				return Ok(());
+			}
+			None => return Ok(()),
+		};
+		let result = self.write_committed(|_, stream| Ok(stream.write_all(&pending)?));
+		if let Some(ref mut coalescing) = self.sender.coalescing {
+			// kept for another try if none of it went out
+			coalescing.pending = pending;
+			if result.is_ok() {
+				coalescing.pending.clear();
+				coalescing.first_pending_at = None;
+			}
+		}
+		result
+	}
+
+	/// Starts recording how long send calls take. The returned recorder can
+	/// be shared, e.g. with the matching `Reader`.
+	#[cfg(feature = "stats")]
+	pub fn enable_stats(&mut self, config: StatsConfig) -> Arc<StatsRecorder> {
+		let stats = Arc::new(StatsRecorder::new(config));
+		self.sender.stats = Some(stats.clone());
+		stats
+	}
+
+	/// Records into an existing recorder, or stops recording with `None`.
+	#[cfg(feature = "stats")]
+	pub fn set_stats(&mut self, stats: Option<Arc<StatsRecorder>>) {
+		self.sender.stats = stats;
+	}
+
+	#[cfg(feature = "stats")]
+	pub fn stats(&self) -> Option<&Arc<StatsRecorder>> {
+		self.sender.stats.as_ref()
 	}
 }
 
@@ -55,12 +522,376 @@ where
 	S: AsTcpStream + Write,
 {
 
+	/// Sends our Close and shuts down our write side only, so a peer that
+	/// already half-closed can still read everything we sent.
+	/// Call `shutdown_all` once the peer is done as well.
+	pub fn send_close_and_shutdown<M>(&mut self, close: &M) -> WebSocketResult<()>
+	where
+		M: Message,
+	{
+		self.send_message(close)?;
+		self.flush()?;
+		self.shutdown()?;
+		Ok(())
+	}
+
+	/// Shuts down our write side. Later send errors are reported as
+	/// `TransportErrorKind::LocalShutdown`.
 	pub fn shutdown(&self) -> IoResult<()> {
+		self.sender.shut_down.store(true, Ordering::Relaxed);
 		self.stream.as_tcp().shutdown(Shutdown::Write)
 	}
 
 	pub fn shutdown_all(&self) -> IoResult<()> {
+		self.sender.shut_down.store(true, Ordering::Relaxed);
 		self.stream.as_tcp().shutdown(Shutdown::Both)
 	}
 }
 
+
+#[cfg(test)]
+mod tests {
+	use std::collections::hash_map::DefaultHasher;
+	use std::fs::{self, File};
+	use std::hash::{Hash, Hasher};
+	use std::io::{self, Write};
+	use std::time::Duration;
+	use websocket_core::action::receiver::Receiver as ReceiverAble;
+	use websocket_core::compression::{CompressionCodec, DeflateCodec};
+	use websocket_core::dataframe::DataFrame;
+	use websocket_core::error::{TransportErrorKind, WebSocketError};
+	use websocket_core::message::Message;
+	use websocket_core::protocol::dataframe::DataFrameRefTrait;
+	use websocket_core::protocol::header::Opcode;
+	use websocket_core::result::WebSocketResult;
+	use crate::payload::PayloadSource;
+	use crate::receiver::Receiver;
+	use std::sync::atomic::Ordering;
+	use super::{Sender, Writer, STREAM_CHUNK};
+
+	fn writer() -> Writer<Vec<u8>> {
+		Writer {
+			stream: Vec::new(),
+			sender: Sender::new(false),
+		}
+	}
+
+	#[test]
+	fn send_dataframe_takes_fin_from_finished() {
+		for (finished, fin) in [(false, 0), (true, 0x80)] {
+			let mut writer = writer();
+			writer.send_dataframe(&DataFrame::new(finished, Opcode::Text, b"abc".to_vec())).unwrap();
+			assert_eq!(writer.stream[0] & 0x80, fin, "finished: {}", finished);
+			assert_eq!(writer.stream[0] & 0x0F, Opcode::Text as u8);
+		}
+	}
+
+	#[test]
+	fn send_dataframe_takes_fin_from_finished_when_coalescing() {
+		for (finished, fin) in [(false, 0), (true, 0x80)] {
+			let mut writer = writer();
+			writer.set_coalescing(Duration::from_secs(60), 1024 * 1024);
+			writer.send_dataframe(&DataFrame::new(finished, Opcode::Binary, b"abc".to_vec())).unwrap();
+			assert!(writer.stream.is_empty(), "the frame should be held back");
+			writer.flush().unwrap();
+			assert_eq!(writer.stream[0] & 0x80, fin, "finished: {}", finished);
+			assert_eq!(writer.stream[0] & 0x0F, Opcode::Binary as u8);
+		}
+	}
+
+	/// A frame that announces `size` payload bytes but writes `payload`.
+	struct Misreported {
+		size: usize,
+		payload: Vec<u8>,
+	}
+
+	impl DataFrameRefTrait for Misreported {
+		fn is_last(&self) -> bool {
+			true
+		}
+
+		fn opcode(&self) -> u8 {
+			Opcode::Binary as u8
+		}
+
+		fn reserved(&self) -> &[bool; 3] {
+			&[false; 3]
+		}
+
+		fn size(&self) -> usize {
+			self.size
+		}
+
+		fn write_payload(&self, socket: &mut dyn Write) -> WebSocketResult<()> {
+			socket.write_all(&self.payload)?;
+			Ok(())
+		}
+	}
+
+	#[test]
+	fn streaming_writes_the_same_frames() {
+		let payload = vec![7u8; 100_000];
+		let mut buffered = writer();
+		let mut streaming = writer();
+		streaming.sender.set_streaming(true);
+		for writer in [&mut buffered, &mut streaming] {
+			writer.send_message(&Message::binary(payload.clone())).unwrap();
+			writer.send_message(&Message::close_because(1000, "bye".to_string())).unwrap();
+			writer.send_dataframe(&DataFrame::new(false, Opcode::Text, b"abc".to_vec())).unwrap();
+		}
+		assert_eq!(streaming.stream, buffered.stream);
+	}
+
+	#[test]
+	fn streaming_checks_the_payload_length() {
+		for (size, payload) in [(10, vec![1; 4]), (4, vec![1; 10])] {
+			let mut writer = writer();
+			writer.sender.set_streaming(true);
+			let result = writer.send_dataframe(&Misreported { size, payload });
+			assert!(matches!(result, Err(WebSocketError::DataFrameError(_))), "size: {}", size);
+			assert!(writer.is_poisoned());
+		}
+	}
+
+	fn hash(data: &[u8]) -> u64 {
+		let mut hasher = DefaultHasher::new();
+		data.hash(&mut hasher);
+		hasher.finish()
+	}
+
+	/// Reads the frames of one message sent by a masking writer.
+	fn read_frames(mut stream: &[u8]) -> Vec<DataFrame> {
+		let mut receiver = Receiver::new(true);
+		let mut frames = Vec::new();
+		loop {
+			let frame = receiver.recv_dataframe(&mut stream).unwrap();
+			let last = frame.finished;
+			frames.push(frame);
+			if last {
+				assert!(stream.is_empty());
+				return frames;
+			}
+		}
+	}
+
+	#[test]
+	fn send_binary_from_sends_a_file() {
+		let data: Vec<u8> = (0..300_000u32).map(|i| (i * 31 % 251) as u8).collect();
+		let path = std::env::temp_dir().join(format!("send_binary_from_{}", std::process::id()));
+		fs::write(&path, &data).unwrap();
+		let mut writer = Writer {
+			stream: Vec::new(),
+			sender: Sender::new(true),
+		};
+		writer.send_binary_from(File::open(&path).unwrap(), 100_000).unwrap();
+		fs::remove_file(&path).unwrap();
+
+		let frames = read_frames(&writer.stream);
+		let payload: Vec<u8> = frames.iter().flat_map(|frame| frame.data.iter().copied()).collect();
+		assert_eq!(hash(&payload), hash(&data));
+		assert!(frames.iter().all(|frame| frame.data.len() <= 100_000));
+		assert_eq!(frames.last().unwrap().data.len(), STREAM_CHUNK);
+		assert_eq!(frames[0].opcode, Opcode::Binary);
+		assert!(!writer.is_poisoned());
+	}
+
+	/// Claims `len` bytes but has `data`.
+	struct Lying<'a> {
+		data: &'a [u8],
+		len: u64,
+	}
+
+	impl PayloadSource for Lying<'_> {
+		fn len(&self) -> u64 {
+			self.len
+		}
+
+		fn read_chunk(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+			self.data.read_chunk(buf)
+		}
+	}
+
+	#[test]
+	fn send_binary_from_short_single_frame_writes_nothing() {
+		let data = [1u8; 100];
+		for len in [200, 50] {
+			let mut writer = writer();
+			let result = writer.send_binary_from(Lying { data: &data, len }, 1000);
+			assert!(matches!(result, Err(WebSocketError::Io(_))), "len: {}", len);
+			assert!(writer.stream.is_empty());
+			assert!(!writer.is_poisoned());
+		}
+	}
+
+	#[test]
+	fn send_binary_from_short_read_poisons() {
+		let data = vec![1u8; 149_000];
+		// runs out in the last frame, which is never written
+		let mut last = writer();
+		let result = last.send_binary_from(Lying { data: &data, len: 150_000 }, 100_000);
+		assert!(matches!(result, Err(WebSocketError::Io(_))));
+		assert!(last.is_poisoned());
+		// only the first frame's header and payload, without FIN
+		assert_eq!(last.stream.len(), 10 + 100_000);
+		assert_eq!(last.stream[0] & 0x80, 0);
+
+		// runs out in the middle of a frame
+		let mut middle = writer();
+		let result = middle.send_binary_from(Lying { data: &data, len: 250_000 }, 100_000);
+		assert!(matches!(result, Err(WebSocketError::Io(_))));
+		assert!(middle.is_poisoned());
+		assert!(matches!(middle.send_message(&Message::text("x".to_string())), Err(WebSocketError::Poisoned)));
+	}
+
+	/// Takes `left` bytes, in short writes, then fails.
+	struct FailAfter {
+		written: Vec<u8>,
+		left: usize,
+	}
+
+	impl Write for FailAfter {
+		fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+			if self.left == 0 {
+				return Err(io::ErrorKind::BrokenPipe.into());
+			}
+			let len = buf.len().min(self.left).min(7);
+			self.written.extend_from_slice(&buf[..len]);
+			self.left -= len;
+			Ok(len)
+		}
+
+		fn flush(&mut self) -> io::Result<()> {
+			Ok(())
+		}
+	}
+
+	type SendSetup = fn(&mut Writer<FailAfter>);
+
+	type SendPath = fn(&mut Writer<FailAfter>) -> WebSocketResult<()>;
+
+	/// Every way of sending, each with its setup.
+	fn send_paths() -> Vec<(&'static str, SendSetup, SendPath)> {
+		fn plain(_: &mut Writer<FailAfter>) {}
+		fn compressing(writer: &mut Writer<FailAfter>) {
+			writer.sender.set_compressor(Some(DeflateCodec::default().new_compressor()));
+			writer.sender.set_should_compress(Some(Box::new(|_, _| true)));
+		}
+		fn streaming(writer: &mut Writer<FailAfter>) {
+			writer.sender.set_streaming(true);
+		}
+		fn coalescing(writer: &mut Writer<FailAfter>) {
+			writer.set_coalescing(Duration::from_secs(60), 1024 * 1024);
+		}
+		vec![
+			("send_message", plain, |writer| writer.send_message(&Message::binary(vec![3; 300]))),
+			("send_message compressed", compressing, |writer| writer.send_message(&Message::text("a".repeat(300)))),
+			("send_message streaming", streaming, |writer| writer.send_message(&Message::binary(vec![3; 300]))),
+			("send_dataframe", plain, |writer| writer.send_dataframe(&DataFrame::new(false, Opcode::Text, b"abc".to_vec()))),
+			("send_dataframe streaming", streaming, |writer| {
+				writer.send_dataframe(&DataFrame::new(true, Opcode::Binary, vec![3; 300]))
+			}),
+			("control frame while coalescing", coalescing, |writer| writer.send_message(&Message::ping(b"p".to_vec()))),
+			("flush while coalescing", coalescing, |writer| {
+				writer.send_message(&Message::binary(vec![3; 100]))?;
+				writer.send_dataframe(&DataFrame::new(true, Opcode::Text, b"abc".to_vec()))?;
+				writer.flush()
+			}),
+			("send_binary_from", plain, |writer| writer.send_binary_from(&[3u8; 300][..], 100)),
+		]
+	}
+
+	fn failing_writer(setup: SendSetup, left: usize) -> Writer<FailAfter> {
+		let mut writer = Writer {
+			stream: FailAfter {
+				written: Vec::new(),
+				left,
+			},
+			sender: Sender::new(true),
+		};
+		setup(&mut writer);
+		writer
+	}
+
+	#[test]
+	fn failed_write_poisons_once_bytes_went_out() {
+		for (name, setup, send) in send_paths() {
+			let mut writer = failing_writer(setup, usize::MAX);
+			send(&mut writer).unwrap();
+			let total = writer.stream.written.len();
+			for k in 0..total {
+				let mut writer = failing_writer(setup, k);
+				let result = send(&mut writer);
+				assert!(matches!(result, Err(WebSocketError::Transport { .. })), "{} failing after {}: {:?}", name, k, result);
+				assert_eq!(writer.stream.written.len(), k, "{}", name);
+				assert_eq!(writer.is_poisoned(), k > 0, "{} failing after {}", name, k);
+				writer.stream.left = usize::MAX;
+				let retried = send(&mut writer);
+				if k > 0 {
+					assert!(matches!(retried, Err(WebSocketError::Poisoned)), "{} failing after {}", name, k);
+					assert_eq!(writer.stream.written.len(), k, "{}", name);
+				} else {
+					assert!(retried.is_ok(), "{}: {:?}", name, retried);
+				}
+			}
+		}
+	}
+
+	#[test]
+	fn sender_is_send_and_sync() {
+		fn shareable<T: Send + Sync>() {}
+		shareable::<Sender>();
+		shareable::<Writer<Vec<u8>>>();
+	}
+
+	/// Fails every write with `kind`.
+	struct FailWith(io::ErrorKind);
+
+	impl Write for FailWith {
+		fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+			Err(self.0.into())
+		}
+
+		fn flush(&mut self) -> io::Result<()> {
+			Err(self.0.into())
+		}
+	}
+
+	#[test]
+	fn write_errors_are_classified() {
+		let kinds = [
+			(io::ErrorKind::ConnectionReset, TransportErrorKind::PeerReset),
+			(io::ErrorKind::ConnectionAborted, TransportErrorKind::PeerReset),
+			(io::ErrorKind::BrokenPipe, TransportErrorKind::PeerGone),
+			(io::ErrorKind::NotConnected, TransportErrorKind::PeerGone),
+			(io::ErrorKind::UnexpectedEof, TransportErrorKind::PeerGone),
+			(io::ErrorKind::TimedOut, TransportErrorKind::Timeout),
+			(io::ErrorKind::WouldBlock, TransportErrorKind::Timeout),
+			(io::ErrorKind::PermissionDenied, TransportErrorKind::Other),
+			(io::ErrorKind::Other, TransportErrorKind::Other),
+		];
+		for (io_kind, expected) in kinds {
+			for shut_down in [false, true] {
+				let mut writer = Writer {
+					stream: FailWith(io_kind),
+					sender: Sender::new(false),
+				};
+				writer.sender.shut_down.store(shut_down, Ordering::Relaxed);
+				let expected = if shut_down { TransportErrorKind::LocalShutdown } else { expected };
+				let results = [
+					writer.send_message(&Message::text("hi".to_string())),
+					writer.send_binary_from(&b"hi"[..], 16),
+					writer.flush(),
+				];
+				for result in results {
+					match result {
+						Err(WebSocketError::Transport { kind, source }) => {
+							assert_eq!(kind, expected, "{:?}", io_kind);
+							assert_eq!(source.kind(), io_kind);
+						}
+						result => panic!("{:?}: {:?}", io_kind, result),
+					}
+				}
+			}
+		}
+	}
+}
@@ -0,0 +1,182 @@
+//! Per-connection protocol statistics kept in fixed-bucket histograms.
+//!
+//! A [`StatsRecorder`] only uses atomics once created, so recording never
+//! allocates and one recorder can be shared between a `Reader`, a `Writer`
+//! and whoever scrapes it.
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// Bucket edges of the histograms of a [`StatsRecorder`]. A value goes to
+/// the first bucket whose edge is greater than or equal to it; values above
+/// the last edge go to an extra overflow bucket.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StatsConfig {
+    /// Message payload size, in bytes.
+    pub message_size: Vec<u64>,
+    /// Number of frames a message arrived in.
+    pub frames_per_message: Vec<u64>,
+    /// Time between two received messages, in microseconds.
+    pub arrival_gap_micros: Vec<u64>,
+    /// Time spent in one send call, in microseconds.
+    pub write_duration_micros: Vec<u64>,
+}
+
+impl Default for StatsConfig {
+    fn default() -> Self {
+        StatsConfig {
+            message_size: vec![64, 256, 1024, 4096, 16384, 65536, 262144, 1048576],
+            frames_per_message: vec![1, 2, 4, 8, 16, 64, 256],
+            arrival_gap_micros: vec![100, 1_000, 10_000, 100_000, 1_000_000, 10_000_000],
+            write_duration_micros: vec![10, 100, 1_000, 10_000, 100_000, 1_000_000],
+        }
+    }
+}
+
+/// A histogram with fixed bucket edges.
+#[derive(Debug)]
+pub struct Histogram {
+    edges: Box<[u64]>,
+    buckets: Box<[AtomicU64]>,
+    count: AtomicU64,
+    sum: AtomicU64,
+}
+
+impl Histogram {
+    /// Creates a histogram with the given edges, which are sorted and
+    /// deduplicated first.
+    pub fn new(mut edges: Vec<u64>) -> Histogram {
+        edges.sort_unstable();
+        edges.dedup();
+        let buckets = (0..=edges.len()).map(|_| AtomicU64::new(0)).collect();
+        Histogram {
+            edges: edges.into_boxed_slice(),
+            buckets,
+            count: AtomicU64::new(0),
+            sum: AtomicU64::new(0),
+        }
+    }
+
+    pub fn record(&self, value: u64) {
+        let bucket = self.edges.partition_point(|&edge| edge < value);
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(value, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> HistogramSnapshot {
+        HistogramSnapshot {
+            edges: self.edges.to_vec(),
+            buckets: self.buckets.iter().map(|x| x.load(Ordering::Relaxed)).collect(),
+            count: self.count.load(Ordering::Relaxed),
+            sum: self.sum.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// The values of a [`Histogram`] at one point in time.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct HistogramSnapshot {
+    pub edges: Vec<u64>,
+    /// One tally per edge, followed by the overflow bucket.
+    pub buckets: Vec<u64>,
+    pub count: u64,
+    pub sum: u64,
+}
+
+impl HistogramSnapshot {
+    /// Adds the tallies of `other` bucket by bucket. Returns false and
+    /// changes nothing if the bucket edges differ.
+    pub fn merge(&mut self, other: &HistogramSnapshot) -> bool {
+        if self.edges != other.edges {
+            return false;
+        }
+        for (bucket, other) in self.buckets.iter_mut().zip(&other.buckets) {
+            *bucket += other;
+        }
+        self.count += other.count;
+        self.sum += other.sum;
+        true
+    }
+}
+
+/// Histograms of one connection.
+#[derive(Debug)]
+pub struct StatsRecorder {
+    message_size: Histogram,
+    frames_per_message: Histogram,
+    arrival_gap_micros: Histogram,
+    write_duration_micros: Histogram,
+    started: Instant,
+    /// Microseconds from `started` to the last arrival, plus one; zero
+    /// before the first message.
+    last_arrival: AtomicU64,
+}
+
+impl StatsRecorder {
+    pub fn new(config: StatsConfig) -> StatsRecorder {
+        StatsRecorder {
+            message_size: Histogram::new(config.message_size),
+            frames_per_message: Histogram::new(config.frames_per_message),
+            arrival_gap_micros: Histogram::new(config.arrival_gap_micros),
+            write_duration_micros: Histogram::new(config.write_duration_micros),
+            started: Instant::now(),
+            last_arrival: AtomicU64::new(0),
+        }
+    }
+
+    /// Records a message received at `now`.
+    pub fn record_message(&self, size: usize, frames: usize, now: Instant) {
+        self.message_size.record(size as u64);
+        self.frames_per_message.record(frames as u64);
+        let at = now.saturating_duration_since(self.started).as_micros() as u64 + 1;
+        let previous = self.last_arrival.swap(at, Ordering::Relaxed);
+        if previous != 0 {
+            self.arrival_gap_micros.record(at.saturating_sub(previous));
+        }
+    }
+
+    /// Records how long one send call took.
+    pub fn record_write(&self, duration: Duration) {
+        self.write_duration_micros.record(duration.as_micros() as u64);
+    }
+
+    pub fn snapshot(&self) -> StatsSnapshot {
+        StatsSnapshot {
+            message_size: self.message_size.snapshot(),
+            frames_per_message: self.frames_per_message.snapshot(),
+            arrival_gap_micros: self.arrival_gap_micros.snapshot(),
+            write_duration_micros: self.write_duration_micros.snapshot(),
+        }
+    }
+}
+
+/// The values of a [`StatsRecorder`] at one point in time.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct StatsSnapshot {
+    pub message_size: HistogramSnapshot,
+    pub frames_per_message: HistogramSnapshot,
+    pub arrival_gap_micros: HistogramSnapshot,
+    pub write_duration_micros: HistogramSnapshot,
+}
+
+impl StatsSnapshot {
+    /// Adds `other` histogram by histogram, e.g. to aggregate many
+    /// connections into one view. Returns false and changes nothing if any
+    /// of the bucket edges differ.
+    pub fn merge(&mut self, other: &StatsSnapshot) -> bool {
+        if self.message_size.edges != other.message_size.edges
+            || self.frames_per_message.edges != other.frames_per_message.edges
+            || self.arrival_gap_micros.edges != other.arrival_gap_micros.edges
+            || self.write_duration_micros.edges != other.write_duration_micros.edges
+        {
+            return false;
+        }
+        self.message_size.merge(&other.message_size);
+        self.frames_per_message.merge(&other.frames_per_message);
+        self.arrival_gap_micros.merge(&other.arrival_gap_micros);
+        self.write_duration_micros.merge(&other.write_duration_micros);
+        true
+    }
+}
@@ -0,0 +1,385 @@
+//! Helpers for deterministic tests of receive paths, and an in-memory
+//! transport to run both ends of a connection in one test.
+use std::collections::VecDeque;
+use std::io::{self, Cursor, Read, Write};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use websocket_core::action::receiver::Receiver as ReceiverAble;
+use websocket_core::clock::{Clock, SimClock};
+use websocket_core::error::WebSocketError;
+use websocket_core::policy::{ConnectionPolicy, PolicyAction, PolicyEvent};
+use websocket_core::protocol::role::Role;
+use crate::receiver::Receiver;
+use crate::websocket::WebSocket;
+
+/// Byte chunks paired with the virtual time they arrive at, measured from
+/// the start of the script.
+#[derive(Debug, Clone, Default)]
+pub struct TimedScript {
+    steps: Vec<(Duration, Vec<u8>)>,
+}
+
+impl TimedScript {
+    pub fn new() -> TimedScript {
+        TimedScript::default()
+    }
+
+    /// Adds a chunk arriving at `at`. Chunks must be added in order.
+    pub fn push(&mut self, at: Duration, bytes: Vec<u8>) -> &mut TimedScript {
+        debug_assert!(self.steps.last().is_none_or(|(last, _)| *last <= at), "script steps out of order");
+        self.steps.push((at, bytes));
+        self
+    }
+
+    pub fn steps(&self) -> &[(Duration, Vec<u8>)] {
+        &self.steps
+    }
+}
+
+/// Serves the chunks of a script, moving the clock to each chunk's arrival
+/// and ticking the policy on the way.
+struct ScriptReader<'a> {
+    steps: std::vec::IntoIter<(Duration, Vec<u8>)>,
+    current: Cursor<Vec<u8>>,
+    clock: &'a SimClock,
+    start: Instant,
+    tick: Option<Duration>,
+    next_tick: Option<Instant>,
+    policy: &'a mut ConnectionPolicy,
+    actions: Vec<(Instant, PolicyAction)>,
+}
+
+impl<'a> ScriptReader<'a> {
+    fn react(&mut self, event: PolicyEvent) {
+        let now = self.clock.now();
+        for action in self.policy.react(event, now) {
+            if action != PolicyAction::Ignore {
+                self.actions.push((now, action));
+            }
+        }
+    }
+
+    fn advance_to(&mut self, at: Instant) {
+        if let Some(tick) = self.tick {
+            let mut next_tick = self.next_tick.unwrap_or(self.start + tick);
+            while next_tick <= at {
+                self.clock.advance_to(next_tick);
+                self.react(PolicyEvent::Tick);
+                next_tick += tick;
+            }
+            self.next_tick = Some(next_tick);
+        }
+        self.clock.advance_to(at);
+    }
+}
+
+impl<'a> Read for ScriptReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let read = self.current.read(buf)?;
+            if read > 0 || buf.is_empty() {
+                return Ok(read);
+            }
+            match self.steps.next() {
+                Some((at, bytes)) => {
+                    self.advance_to(self.start + at);
+                    self.current = Cursor::new(bytes);
+                }
+                None => return Ok(0),
+            }
+        }
+    }
+}
+
+/// Replays `script` through `receiver` and `policy` at virtual speed.
+///
+/// The receiver is switched to `clock`, every received message or error is
+/// reported to the policy, and with `tick` set the policy is ticked at that
+/// interval of virtual time. Returns every action other than `Ignore` with
+/// the virtual time it was decided at. The replay stops at the end of the
+/// script or at the first receive error.
+pub fn replay(
+    script: &TimedScript,
+    receiver: &mut Receiver,
+    policy: &mut ConnectionPolicy,
+    clock: &SimClock,
+    tick: Option<Duration>,
+) -> Vec<(Instant, PolicyAction)> {
+    receiver.set_clock(Arc::new(clock.clone()));
+    let mut reader = ScriptReader {
+        steps: script.steps.clone().into_iter(),
+        current: Cursor::new(Vec::new()),
+        clock,
+        start: clock.now(),
+        tick,
+        next_tick: None,
+        policy,
+        actions: Vec::new(),
+    };
+    loop {
+        match receiver.recv_message(&mut reader) {
+            Ok(message) => reader.react(PolicyEvent::Message(message)),
+            // the script ran out between two messages
+            Err(WebSocketError::Io(ref e)) if e.kind() == io::ErrorKind::UnexpectedEof && receiver.pending_frames().is_empty() => break,
+            Err(e) => {
+                reader.react(PolicyEvent::Error(e));
+                break;
+            }
+        }
+    }
+    reader.actions
+}
+
+/// Bytes travelling one way through a [`DuplexPipe`].
+#[derive(Default)]
+struct Channel {
+    bytes: VecDeque<u8>,
+    /// The writing end closed its write side or was dropped.
+    closed: bool,
+}
+
+/// One end of an in-memory connection, made with [`DuplexPipe::pair`].
+///
+/// What one end writes, the other reads, in the order written. Reads never
+/// block: with nothing to read they fail with `WouldBlock` while the other
+/// end can still write, and return end of file once it can't. So a test
+/// sends on one end and then receives on the other, on a single thread.
+pub struct DuplexPipe {
+    incoming: Arc<Mutex<Channel>>,
+    outgoing: Arc<Mutex<Channel>>,
+}
+
+impl DuplexPipe {
+    /// Two connected ends.
+    pub fn pair() -> (DuplexPipe, DuplexPipe) {
+        let a = Arc::new(Mutex::new(Channel::default()));
+        let b = Arc::new(Mutex::new(Channel::default()));
+        (
+            DuplexPipe { incoming: a.clone(), outgoing: b.clone() },
+            DuplexPipe { incoming: b, outgoing: a },
+        )
+    }
+
+    /// A client and a server `WebSocket` connected to each other, as if the
+    /// handshake were done.
+    pub fn websocket_pair() -> (WebSocket<DuplexPipe>, WebSocket<DuplexPipe>) {
+        let (client, server) = DuplexPipe::pair();
+        (WebSocket::new(client, Role::Client), WebSocket::new(server, Role::Server))
+    }
+
+    /// Closes this end's write side: the other end reads end of file once
+    /// it has read everything written before. Later writes fail with
+    /// `BrokenPipe`.
+    pub fn close_write(&self) {
+        self.outgoing.lock().unwrap().closed = true;
+    }
+
+    /// How many bytes written by the other end are waiting to be read.
+    pub fn available(&self) -> usize {
+        self.incoming.lock().unwrap().bytes.len()
+    }
+}
+
+impl Read for DuplexPipe {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut incoming = self.incoming.lock().unwrap();
+        if incoming.bytes.is_empty() && !buf.is_empty() {
+            if incoming.closed {
+                return Ok(0);
+            }
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        let read = buf.len().min(incoming.bytes.len());
+        for (slot, byte) in buf.iter_mut().zip(incoming.bytes.drain(..read)) {
+            *slot = byte;
+        }
+        Ok(read)
+    }
+}
+
+impl Write for DuplexPipe {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut outgoing = self.outgoing.lock().unwrap();
+        if outgoing.closed {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        outgoing.bytes.extend(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for DuplexPipe {
+    fn drop(&mut self) {
+        // no unwrap, a test may be unwinding already
+        if let Ok(mut outgoing) = self.outgoing.lock() {
+            outgoing.closed = true;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use websocket_core::message::Message;
+    use websocket_core::protocol::message::Message as MessageAble;
+    use super::*;
+    use crate::WebSocketResult;
+
+    fn secs(secs: u64) -> Duration {
+        Duration::from_secs(secs)
+    }
+
+    /// A frame as a client sends it.
+    fn masked(message: &Message) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        message.serialize(&mut bytes, true).unwrap();
+        bytes
+    }
+
+    /// Replays `script` into a server side receiver, ticking every second,
+    /// with the actions timed from the start of the script.
+    fn replay_from_start(script: &TimedScript, policy: &mut ConnectionPolicy) -> Vec<(Duration, PolicyAction)> {
+        let clock = SimClock::new();
+        let start = clock.now();
+        let mut receiver = Receiver::new(true);
+        replay(script, &mut receiver, policy, &clock, Some(secs(1)))
+            .into_iter()
+            .map(|(at, action)| (at - start, action))
+            .collect()
+    }
+
+    #[test]
+    fn keepalive_pings_then_times_out_at_virtual_times() {
+        let mut script = TimedScript::new();
+        script
+            .push(secs(3), masked(&Message::text("hello".to_string())))
+            .push(secs(28), masked(&Message::text("late".to_string())));
+        let policy = || {
+            let mut policy = ConnectionPolicy::default();
+            policy.keepalive_interval = Some(secs(10));
+            policy.keepalive_timeout = Some(secs(25));
+            policy
+        };
+
+        let actions = replay_from_start(&script, &mut policy());
+        assert_eq!(actions, vec![
+            (secs(3), PolicyAction::Deliver(Message::text("hello".to_string()))),
+            (secs(13), PolicyAction::SendPing(Vec::new())),
+            (secs(23), PolicyAction::SendPing(Vec::new())),
+            // ticks come before a chunk arriving at the same time
+            (secs(28), PolicyAction::SendClose(Some(1001), "keepalive timeout".to_string())),
+            (secs(28), PolicyAction::ShutdownAfter(secs(10))),
+            (secs(28), PolicyAction::Deliver(Message::text("late".to_string()))),
+        ]);
+        // nothing depends on the real time the replay took
+        assert_eq!(replay_from_start(&script, &mut policy()), actions);
+    }
+
+    #[test]
+    fn idle_timeout_is_not_reset_by_pongs() {
+        let mut script = TimedScript::new();
+        script.push(secs(2), masked(&Message::text("hello".to_string())));
+        for at in [5, 8, 11, 12] {
+            script.push(secs(at), masked(&Message::pong(Vec::new())));
+        }
+        let mut policy = ConnectionPolicy::default();
+        policy.idle_timeout = Some(secs(10));
+
+        let actions: Vec<_> = replay_from_start(&script, &mut policy)
+            .into_iter()
+            .filter(|(_, action)| !matches!(action, PolicyAction::Deliver(_)))
+            .collect();
+        assert_eq!(actions, vec![
+            (secs(12), PolicyAction::SendClose(Some(1001), "idle timeout".to_string())),
+            (secs(12), PolicyAction::ShutdownAfter(secs(10))),
+        ]);
+    }
+
+    #[test]
+    fn close_handshake_times_out_on_the_sim_clock() {
+        let clock = SimClock::new();
+        let (_client, mut server) = DuplexPipe::websocket_pair();
+        server.reader_mut().receiver.set_clock(Arc::new(clock.clone()));
+        server.policy_mut().close_timeout = secs(5);
+
+        server.send(Message::close()).unwrap();
+        clock.advance(Duration::from_millis(4999));
+        // still waiting for the peer's Close
+        assert!(matches!(server.recv(), Err(WebSocketError::Io(ref e)) if e.kind() == io::ErrorKind::WouldBlock));
+        assert!(server.tick().is_ok());
+
+        clock.advance(Duration::from_millis(1));
+        assert!(matches!(server.recv(), Err(WebSocketError::CloseHandshakeTimedOut)));
+        assert!(matches!(server.tick(), Err(WebSocketError::CloseHandshakeTimedOut)));
+    }
+
+    /// A client end where every read takes `latency` of virtual time, and
+    /// times out after it when nothing has arrived.
+    struct Latent {
+        pipe: DuplexPipe,
+        clock: SimClock,
+        latency: Duration,
+    }
+
+    impl Read for Latent {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.clock.sleep_until(self.clock.now() + self.latency);
+            match self.pipe.read(buf) {
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Err(io::ErrorKind::TimedOut.into()),
+                result => result,
+            }
+        }
+    }
+
+    impl Write for Latent {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.pipe.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.pipe.flush()
+        }
+    }
+
+    /// Pings from a client whose reads take `latency`, with the server's
+    /// pong already on its way.
+    fn ping_pong_with_latency(latency: Duration, timeout: Duration) -> WebSocketResult<Duration> {
+        let clock = SimClock::new();
+        let (client, server) = DuplexPipe::pair();
+        let mut server = WebSocket::new(server, Role::Server);
+        let mut client = WebSocket::new(Latent { pipe: client, clock: clock.clone(), latency }, Role::Client);
+        client.reader_mut().receiver.set_clock(Arc::new(clock));
+
+        // the first ping gets sequence number 1
+        let mut payload = b"rtt".to_vec();
+        payload.extend_from_slice(&1u64.to_be_bytes());
+        server.send(Message::pong(payload)).unwrap();
+        client.ping_pong(b"rtt", timeout)
+    }
+
+    #[test]
+    fn ping_pong_measures_the_virtual_round_trip() {
+        assert_eq!(ping_pong_with_latency(secs(2), secs(3)).unwrap(), secs(2));
+        assert_eq!(ping_pong_with_latency(secs(3), secs(3)).unwrap(), secs(3));
+    }
+
+    #[test]
+    fn ping_pong_times_out_on_a_late_pong() {
+        assert!(matches!(ping_pong_with_latency(secs(4), secs(3)), Err(WebSocketError::PongTimeout)));
+    }
+
+    #[test]
+    fn ping_pong_times_out_on_a_silent_peer() {
+        let clock = SimClock::new();
+        let (client, _server) = DuplexPipe::pair();
+        let mut client = WebSocket::new(Latent { pipe: client, clock: clock.clone(), latency: secs(3) }, Role::Client);
+        client.reader_mut().receiver.set_clock(Arc::new(clock.clone()));
+        let start = clock.now();
+
+        assert!(matches!(client.ping_pong(b"", secs(3)), Err(WebSocketError::PongTimeout)));
+        assert_eq!(clock.now() - start, secs(3));
+    }
+}
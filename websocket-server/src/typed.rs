@@ -0,0 +1,118 @@
+//! Halves of a connection whose types carry the role, so the reading half
+//! of one end can't be paired with the writing half of the other.
+//!
+//! The untyped [`Reader`] and [`Writer`] remain for roles only known at
+//! runtime; the typed halves dereference to them and send the same bytes.
+use std::io::{Read, Write};
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+use websocket_core::protocol::role::RoleMarker;
+use crate::receiver::Reader;
+use crate::sender::{Sender, Writer};
+
+/// A [`Reader`] for the end of a connection marked by `K`.
+pub struct TypedReader<R, K>
+    where
+        R: Read,
+        K: RoleMarker,
+{
+    inner: Reader<R>,
+    role: PhantomData<K>,
+}
+
+/// A [`Writer`] for the end of a connection marked by `K`.
+pub struct TypedWriter<W, K>
+    where
+        K: RoleMarker,
+{
+    inner: Writer<W>,
+    role: PhantomData<K>,
+}
+
+/// Creates both halves of the `K` end of a connection whose handshake is
+/// done, reading from `reader` and writing to `writer`, e.g. the two
+/// handles of a cloned `TcpStream`.
+pub fn from_stream_typed<K, R, W>(reader: R, writer: W) -> (TypedReader<R, K>, TypedWriter<W, K>)
+    where
+        K: RoleMarker,
+        R: Read,
+        W: Write,
+{
+    let reader = TypedReader {
+        inner: Reader::from_reader(reader, K::ROLE),
+        role: PhantomData,
+    };
+    let writer = TypedWriter {
+        inner: Writer {
+            stream: writer,
+            sender: Sender::new(K::ROLE.masks_outgoing()),
+        },
+        role: PhantomData,
+    };
+    (reader, writer)
+}
+
+impl<R, K> TypedReader<R, K>
+    where
+        R: Read,
+        K: RoleMarker,
+{
+    /// Gives up the role in the type, e.g. to store halves of both ends
+    /// together.
+    pub fn into_inner(self) -> Reader<R> {
+        self.inner
+    }
+}
+
+impl<W, K> TypedWriter<W, K>
+    where
+        K: RoleMarker,
+{
+    /// Gives up the role in the type, e.g. to store halves of both ends
+    /// together.
+    pub fn into_inner(self) -> Writer<W> {
+        self.inner
+    }
+}
+
+impl<R, K> Deref for TypedReader<R, K>
+    where
+        R: Read,
+        K: RoleMarker,
+{
+    type Target = Reader<R>;
+
+    fn deref(&self) -> &Reader<R> {
+        &self.inner
+    }
+}
+
+impl<R, K> DerefMut for TypedReader<R, K>
+    where
+        R: Read,
+        K: RoleMarker,
+{
+    fn deref_mut(&mut self) -> &mut Reader<R> {
+        &mut self.inner
+    }
+}
+
+impl<W, K> Deref for TypedWriter<W, K>
+    where
+        K: RoleMarker,
+{
+    type Target = Writer<W>;
+
+    fn deref(&self) -> &Writer<W> {
+        &self.inner
+    }
+}
+
+impl<W, K> DerefMut for TypedWriter<W, K>
+    where
+        K: RoleMarker,
+{
+    fn deref_mut(&mut self) -> &mut Writer<W> {
+        &mut self.inner
+    }
+}
@@ -0,0 +1,402 @@
+//! A connection over a single stream, for request/response style use.
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use websocket_core::error::WebSocketError;
+use websocket_core::message::Message;
+use websocket_core::policy::{ConnectionPolicy, PolicyAction, PolicyEvent};
+use websocket_core::protocol::message::Type;
+use websocket_core::protocol::role::Role;
+use websocket_core::stream::PrefixedStream;
+use websocket_core::protocol::header::Opcode;
+use crate::close::DrainLimit;
+use crate::handshake::HandshakeFacts;
+use crate::receiver::{DiscardBudget, DiscardReport, Reader};
+use crate::sender::{Sender, Writer};
+use crate::WebSocketResult;
+
+/// One stream shared by the two halves of a [`WebSocket`]. They are only
+/// used from `&mut WebSocket`, so the lock is never contended.
+pub struct SharedStream<S>(Arc<Mutex<S>>);
+
+impl<S> SharedStream<S> {
+    fn with<T>(&self, f: impl FnOnce(&mut S) -> io::Result<T>) -> io::Result<T> {
+        let mut stream = self.0.lock().map_err(|_| io::Error::other("stream lock poisoned"))?;
+        f(&mut stream)
+    }
+}
+
+impl<S> Read for SharedStream<S>
+    where
+        S: Read,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.with(|stream| stream.read(buf))
+    }
+}
+
+impl<S> Write for SharedStream<S>
+    where
+        S: Write,
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.with(|stream| stream.write(buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.with(|stream| stream.flush())
+    }
+}
+
+/// A [`Reader`] and a [`Writer`] over one stream, with a
+/// [`ConnectionPolicy`] answering pings and closes as messages arrive.
+///
+/// Reading and writing take turns; to do both at once, use separate halves.
+pub struct WebSocket<S>
+    where
+        S: Read + Write,
+{
+    reader: Reader<SharedStream<S>>,
+    writer: Writer<SharedStream<S>>,
+    policy: ConnectionPolicy,
+    /// Messages that arrived while `ping_pong` waited, for `recv`.
+    pending: VecDeque<Message>,
+    pings_sent: u64,
+    /// The subprotocol agreed on in the handshake.
+    protocol: Option<String>,
+}
+
+impl<S> WebSocket<S>
+    where
+        S: Read + Write,
+{
+    /// Wraps a stream on which the handshake is already done, acting as
+    /// `role`.
+    pub fn new(stream: S, role: Role) -> WebSocket<S> {
+        let stream = Arc::new(Mutex::new(stream));
+        WebSocket {
+            reader: Reader::from_reader(SharedStream(stream.clone()), role),
+            writer: Writer {
+                stream: SharedStream(stream),
+                sender: Sender::new(role.masks_outgoing()),
+            },
+            policy: ConnectionPolicy::default(),
+            pending: VecDeque::new(),
+            pings_sent: 0,
+            protocol: None,
+        }
+    }
+
+    /// The subprotocol agreed on in the handshake, if known.
+    pub fn protocol(&self) -> Option<&str> {
+        self.protocol.as_deref()
+    }
+}
+
+impl<S> WebSocket<PrefixedStream<S>>
+    where
+        S: Read + Write,
+{
+    /// Wraps a stream whose handshake was done elsewhere, trusting `facts`
+    /// about it. `leftover` are the bytes read past the handshake head,
+    /// which are read first. Compression is set up as negotiated.
+    pub fn from_upgraded_parts(stream: S, leftover: Vec<u8>, facts: HandshakeFacts) -> WebSocket<PrefixedStream<S>> {
+        let mut websocket = WebSocket::new(PrefixedStream::new(leftover, stream), facts.role);
+        facts.extensions.apply(&mut websocket.reader.receiver, &mut websocket.writer.sender);
+        websocket.protocol = facts.protocol;
+        websocket
+    }
+}
+
+impl<S> WebSocket<S>
+    where
+        S: Read + Write,
+{
+    fn now(&self) -> Instant {
+        self.reader.receiver.clock().now()
+    }
+
+    /// Fails once the close handshake has taken longer than the policy's
+    /// `close_timeout`.
+    fn check_close_deadline(&self) -> WebSocketResult<()> {
+        match self.policy.close_deadline() {
+            Some(deadline) if self.now() >= deadline => Err(WebSocketError::CloseHandshakeTimedOut),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn send(&mut self, message: Message) -> WebSocketResult<()> {
+        self.check_close_deadline()?;
+        if message.opcode == Type::Close {
+            let now = self.now();
+            self.policy.react(PolicyEvent::CloseSent, now);
+        }
+        self.writer.send_message(&message)?;
+        self.writer.flush()
+    }
+
+    /// Receives the next message for the application. Pings are answered
+    /// and a Close from the peer is echoed, as the policy says, before the
+    /// Close is returned. Errors are returned as they are.
+    ///
+    /// Once a Close was sent or received, this and the other operations
+    /// fail with `WebSocketError::CloseHandshakeTimedOut` after the
+    /// policy's `close_timeout`. The deadline is checked between reads, so
+    /// a silent peer needs a read timeout on the stream as well.
+    pub fn recv(&mut self) -> WebSocketResult<Message> {
+        if let Some(message) = self.pending.pop_front() {
+            return Ok(message);
+        }
+        loop {
+            self.check_close_deadline()?;
+            if let Some(message) = self.recv_delivered()? {
+                return Ok(message);
+            }
+        }
+    }
+
+    /// Sends a ping and waits for its pong, returning the round-trip time.
+    ///
+    /// A sequence number is appended to `payload` so each ping is told
+    /// apart; pongs carrying anything else are ignored. Messages arriving
+    /// meanwhile are kept for `recv`. A Close from the peer is kept as well
+    /// and gives `WebSocketError::ClosedBeforePong`.
+    ///
+    /// The deadline is checked whenever something arrives or reading fails
+    /// with a timeout, so a silent peer is only noticed with a read timeout
+    /// set on the stream. Either way a late pong gives
+    /// `WebSocketError::PongTimeout`.
+    pub fn ping_pong(&mut self, payload: &[u8], timeout: Duration) -> WebSocketResult<Duration> {
+        self.check_close_deadline()?;
+        // control frame payloads are limited to 125 bytes
+        if payload.len() > 125 - 8 {
+            return Err(WebSocketError::ProtocolError("Ping payload too long"));
+        }
+        self.pings_sent += 1;
+        let mut payload = payload.to_vec();
+        payload.extend_from_slice(&self.pings_sent.to_be_bytes());
+
+        let sent_at = self.now();
+        let deadline = sent_at + timeout;
+        self.writer.send_message(&Message::ping(payload.clone()))?;
+        self.writer.flush()?;
+        loop {
+            let delivered = match self.recv_delivered() {
+                Err(WebSocketError::Io(ref e))
+                    if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) && self.now() >= deadline =>
+                {
+                    return Err(WebSocketError::PongTimeout);
+                }
+                result => result?,
+            };
+            let now = self.now();
+            match delivered {
+                Some(message) if message.opcode == Type::Pong && message.payload == payload => {
+                    return if now > deadline {
+                        Err(WebSocketError::PongTimeout)
+                    } else {
+                        Ok(now.saturating_duration_since(sent_at))
+                    };
+                }
+                // unsolicited, or answering an earlier ping
+                Some(message) if message.opcode == Type::Pong => {}
+                Some(message) if message.opcode == Type::Close => {
+                    self.pending.push_back(message);
+                    return Err(WebSocketError::ClosedBeforePong);
+                }
+                Some(message) => self.pending.push_back(message),
+                None => {}
+            }
+            if now >= deadline {
+                return Err(WebSocketError::PongTimeout);
+            }
+            self.check_close_deadline()?;
+        }
+    }
+
+    /// Receives one message and carries out what the policy says about it,
+    /// returning it if it is for the application.
+    fn recv_delivered(&mut self) -> WebSocketResult<Option<Message>> {
+        let message = self.reader.recv_message()?;
+        let now = self.now();
+        let actions = self.policy.react(PolicyEvent::Message(message), now);
+        self.execute(actions)
+    }
+
+    /// Drops the rest of a message a failed `recv` left unfinished, see
+    /// [`Receiver::discard_remaining_message`](crate::receiver::Receiver::discard_remaining_message).
+    /// Control frames arriving meanwhile are handled by the policy as in
+    /// `recv`, and those for the application are kept for it.
+    pub fn discard_remaining_message(&mut self, budget: DiscardBudget) -> WebSocketResult<DiscardReport> {
+        self.check_close_deadline()?;
+        let report = self.reader.discard_remaining_message(budget)?;
+        for message in report.control.iter().cloned() {
+            let now = self.now();
+            let actions = self.policy.react(PolicyEvent::Message(message), now);
+            if let Some(message) = self.execute(actions)? {
+                self.pending.push_back(message);
+            }
+        }
+        Ok(report)
+    }
+
+    /// Lets the policy check its timers, sending the keepalive ping or the
+    /// Close for an idle or timed out connection it asks for. Call it
+    /// periodically, e.g. whenever a read with a timeout set on the stream
+    /// comes back empty.
+    pub fn tick(&mut self) -> WebSocketResult<()> {
+        self.check_close_deadline()?;
+        let now = self.now();
+        let actions = self.policy.react(PolicyEvent::Tick, now);
+        self.execute(actions)?;
+        Ok(())
+    }
+
+    /// Carries out policy actions, returning the message to deliver if
+    /// there is one.
+    fn execute(&mut self, actions: Vec<PolicyAction>) -> WebSocketResult<Option<Message>> {
+        let mut delivered = None;
+        for action in actions {
+            match action {
+                PolicyAction::Deliver(message) => delivered = Some(message),
+                PolicyAction::SendPong(payload) => self.writer.send_message(&Message::pong(payload))?,
+                PolicyAction::SendPing(payload) => self.writer.send_message(&Message::ping(payload))?,
+                PolicyAction::SendClose(Some(code), reason) => {
+                    self.writer.send_message(&Message::close_because(code, reason))?
+                }
+                PolicyAction::SendClose(None, _) => self.writer.send_message(&Message::close())?,
+                // there is no socket to shut down, dropping the stream does it
+                PolicyAction::ShutdownAfter(_) | PolicyAction::Ignore => {}
+            }
+        }
+        self.writer.flush()?;
+        Ok(delivered)
+    }
+
+    /// Closes the connection with a bare Close, draining at most
+    /// `DrainLimit::default()`; see [`WebSocket::close_with`].
+    pub fn close(&mut self) -> WebSocketResult<Message> {
+        self.close_with(&Message::close(), DrainLimit::default())
+    }
+
+    /// Sends `close` and discards incoming frames until the peer's Close,
+    /// which is returned. If the peer's Close was already received, ours
+    /// completes the handshake and that one is returned. A peer that ends
+    /// the stream without a Close, or keeps sending past `limit` instead,
+    /// gives `WebSocketError::PeerDidNotClose`; the connection is done then
+    /// and should be dropped.
+    pub fn close_with(&mut self, close: &Message, limit: DrainLimit) -> WebSocketResult<Message> {
+        let already_received = self.reader.receiver.received_close().cloned();
+        if !self.policy.close_sent() {
+            self.send(close.clone())?;
+        }
+        if let Some(frame) = already_received {
+            return Message::from_dataframes_validating(vec![frame], true);
+        }
+        let mut frames = 0;
+        let mut bytes = 0;
+        loop {
+            let frame = match self.reader.recv_dataframe() {
+                Ok(frame) => frame,
+                Err(WebSocketError::Io(ref e)) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                    return Err(WebSocketError::PeerDidNotClose);
+                }
+                Err(e) => return Err(e),
+            };
+            if frame.opcode == Opcode::Close {
+                return Message::from_dataframes_validating(vec![frame], true);
+            }
+            frames += 1;
+            bytes += frame.data.len();
+            if frames > limit.max_frames || bytes > limit.max_bytes {
+                return Err(WebSocketError::PeerDidNotClose);
+            }
+            self.check_close_deadline()?;
+        }
+    }
+
+    pub fn policy_mut(&mut self) -> &mut ConnectionPolicy {
+        &mut self.policy
+    }
+
+    pub fn reader_mut(&mut self) -> &mut Reader<SharedStream<S>> {
+        &mut self.reader
+    }
+
+    pub fn writer_mut(&mut self) -> &mut Writer<SharedStream<S>> {
+        &mut self.writer
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use websocket_core::protocol::message::Message as MessageAble;
+    use super::*;
+
+    /// Reads what a client sent, collects what is written back.
+    struct Scripted {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for Scripted {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Scripted {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// A server side connection reading `messages`, masked like a client's.
+    fn server_reading(messages: &[Message]) -> WebSocket<Scripted> {
+        let mut input = Vec::new();
+        for message in messages {
+            message.serialize(&mut input, true).unwrap();
+        }
+        let stream = Scripted {
+            input: Cursor::new(input),
+            output: Vec::new(),
+        };
+        WebSocket::new(stream, Role::Server)
+    }
+
+    fn limit(max_frames: usize, max_bytes: usize) -> DrainLimit {
+        DrainLimit { max_frames, max_bytes }
+    }
+
+    #[test]
+    fn close_with_drains_up_to_the_limit() {
+        let peer_close = Message::close_because(1000, "bye".to_string());
+        let mut messages = vec![Message::ping(b"abc".to_vec()); 3];
+        messages.push(peer_close.clone());
+        let mut websocket = server_reading(&messages);
+        assert_eq!(websocket.close_with(&Message::close(), limit(3, 9)).unwrap(), peer_close);
+    }
+
+    #[test]
+    fn close_with_gives_up_past_the_frame_limit() {
+        let mut messages = vec![Message::ping(Vec::new()); 100];
+        messages.push(Message::close());
+        let mut websocket = server_reading(&messages);
+        let result = websocket.close_with(&Message::close(), limit(10, usize::MAX));
+        assert!(matches!(result, Err(WebSocketError::PeerDidNotClose)));
+    }
+
+    #[test]
+    fn close_with_gives_up_past_the_byte_limit() {
+        let messages = [Message::binary(vec![0; 2000]), Message::close()];
+        let mut websocket = server_reading(&messages);
+        let result = websocket.close_with(&Message::close(), limit(usize::MAX, 1000));
+        assert!(matches!(result, Err(WebSocketError::PeerDidNotClose)));
+    }
+}
@@ -0,0 +1,8 @@
+//! The role markers of the typed halves must keep the halves of the two
+//! ends of a connection apart at compile time.
+
+#[test]
+fn typed_halves_of_different_ends_dont_mix() {
+    let cases = trybuild::TestCases::new();
+    cases.compile_fail("tests/ui/typed_*.rs");
+}
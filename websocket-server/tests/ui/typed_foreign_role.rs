@@ -0,0 +1,10 @@
+use websocket_core::protocol::role::{Role, RoleMarker};
+
+/// A role of our own, which would let a server send unmasked as a client.
+struct Impostor;
+
+impl RoleMarker for Impostor {
+    const ROLE: Role = Role::Client;
+}
+
+fn main() {}
@@ -0,0 +1,12 @@
+use std::io::{Cursor, Read, Write};
+use websocket_core::protocol::role::{ClientRole, RoleMarker, ServerRole};
+use websocket_server::typed::{from_stream_typed, TypedReader, TypedWriter};
+
+/// Something that only works on the two halves of one end.
+fn serve<R: Read, W: Write, K: RoleMarker>(_reader: TypedReader<R, K>, _writer: TypedWriter<W, K>) {}
+
+fn main() {
+    let (server_reader, _) = from_stream_typed::<ServerRole, _, _>(Cursor::new(Vec::new()), Vec::new());
+    let (_, client_writer) = from_stream_typed::<ClientRole, _, _>(Cursor::new(Vec::new()), Vec::new());
+    serve(server_reader, client_writer);
+}